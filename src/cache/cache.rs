@@ -24,7 +24,7 @@ use crate::cache::redis::RedisCache;
 #[cfg(feature = "s3")]
 use crate::cache::s3::S3Cache;
 use crate::config::{self, CacheType, Config};
-use crate::util::SpawnExt;
+use crate::util::{self, SpawnExt};
 use futures_03::executor::ThreadPool;
 use std::fmt;
 use std::fs;
@@ -33,8 +33,9 @@ use std::fs::File;
 use std::io::{self, Cursor, Read, Seek, Write};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tempfile::NamedTempFile;
+use walkdir::WalkDir;
 use zip::write::FileOptions;
 use zip::{CompressionMethod, ZipArchive, ZipWriter};
 
@@ -92,6 +93,59 @@ pub trait ReadSeek: Read + Seek + Send {}
 
 impl<T: Read + Seek + Send> ReadSeek for T {}
 
+/// The name of the cache entry member holding its `CacheEntryMetadata`.
+const METADATA_NAME: &str = "metadata";
+
+/// The current version of the `CacheEntryMetadata` format.
+pub const CACHE_ENTRY_METADATA_VERSION: u32 = 1;
+
+/// Information about how a cache entry was produced, stored alongside the
+/// compiler outputs.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CacheEntryMetadata {
+    /// The version of this format, `CACHE_ENTRY_METADATA_VERSION` when written.
+    pub version: u32,
+    /// When the entry was created, in seconds since the Unix epoch.
+    pub created: u64,
+    /// How long the compilation that produced the entry took.
+    pub compile_duration: Duration,
+    /// The kind of compiler that produced the entry, e.g. `gcc` or `rustc`.
+    pub compiler: String,
+    /// The version of that compiler, if known.
+    pub compiler_version: Option<String>,
+    /// The names of the outputs stored in the entry.
+    pub outputs: Vec<String>,
+    /// The machine the entry was produced on, if known.
+    pub hostname: Option<String>,
+    /// The version of sccache that wrote the entry.
+    pub sccache_version: String,
+}
+
+impl CacheEntryMetadata {
+    /// Metadata for an entry produced just now on this machine.
+    pub fn new(
+        compile_duration: Duration,
+        compiler: String,
+        compiler_version: Option<String>,
+        outputs: Vec<String>,
+    ) -> CacheEntryMetadata {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        CacheEntryMetadata {
+            version: CACHE_ENTRY_METADATA_VERSION,
+            created,
+            compile_duration,
+            compiler,
+            compiler_version,
+            outputs,
+            hostname: util::hostname(),
+            sccache_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+}
+
 /// Data stored in the compiler cache.
 pub struct CacheRead {
     zip: ZipArchive<Box<dyn ReadSeek>>,
@@ -145,35 +199,86 @@ impl CacheRead {
         self.get_bytes("stderr")
     }
 
+    /// Get the metadata of this cache entry, if it has any that can be read.
+    /// Entries written by older versions of sccache have none.
+    pub fn get_metadata(&mut self) -> Option<CacheEntryMetadata> {
+        let bytes = self.get_bytes(METADATA_NAME);
+        if bytes.is_empty() {
+            return None;
+        }
+        match serde_json::from_slice(&bytes) {
+            Ok(metadata) => Some(metadata),
+            Err(e) => {
+                warn!("Failed to parse cache entry metadata: {}", e);
+                None
+            }
+        }
+    }
+
     fn get_bytes(&mut self, name: &str) -> Vec<u8> {
         let mut bytes = Vec::new();
         drop(self.get_object(name, &mut bytes));
         bytes
     }
 
+    /// Write the objects at each key to the corresponding path. A key ending
+    /// in `/` names a directory, whose files are all written below the path.
     pub fn extract_objects<T>(mut self, objects: T, pool: &ThreadPool) -> SFuture<()>
     where
         T: IntoIterator<Item = (String, PathBuf)> + Send + Sync + 'static,
     {
         Box::new(pool.spawn_fn(move || {
             for (key, path) in objects {
-                let dir = match path.parent() {
-                    Some(d) => d,
-                    None => bail!("Output file without a parent directory!"),
-                };
-                // Write the cache entry to a tempfile and then atomically
-                // move it to its final location so that other rustc invocations
-                // happening in parallel don't see a partially-written file.
-                let mut tmp = NamedTempFile::new_in(dir)?;
-                let mode = self.get_object(&key, &mut tmp)?;
-                tmp.persist(&path)?;
-                if let Some(mode) = mode {
-                    set_file_mode(&path, mode)?;
+                if key.ends_with('/') {
+                    let names: Vec<String> = self
+                        .zip
+                        .file_names()
+                        .filter(|name| name.starts_with(&key))
+                        .map(str::to_owned)
+                        .collect();
+                    for name in names {
+                        let file_path = path.join(&name[key.len()..]);
+                        if let Some(dir) = file_path.parent() {
+                            fs::create_dir_all(dir)?;
+                        }
+                        self.extract_object(&name, &file_path)?;
+                    }
+                } else {
+                    self.extract_object(&key, &path)?;
                 }
             }
             Ok(())
         }))
     }
+
+    /// Copy the objects of this entry into a new one, to be stored.
+    pub fn into_cache_write(mut self) -> Result<CacheWrite> {
+        let names: Vec<String> = self.zip.file_names().map(str::to_owned).collect();
+        let mut entry = CacheWrite::new();
+        for name in names {
+            let mut bytes = vec![];
+            let mode = self.get_object(&name, &mut bytes)?;
+            entry.put_object(&name, &mut Cursor::new(bytes), mode)?;
+        }
+        Ok(entry)
+    }
+
+    fn extract_object(&mut self, key: &str, path: &Path) -> Result<()> {
+        let dir = match path.parent() {
+            Some(d) => d,
+            None => bail!("Output file without a parent directory!"),
+        };
+        // Write the cache entry to a tempfile and then atomically
+        // move it to its final location so that other rustc invocations
+        // happening in parallel don't see a partially-written file.
+        let mut tmp = NamedTempFile::new_in(dir)?;
+        let mode = self.get_object(key, &mut tmp)?;
+        tmp.persist(path)?;
+        if let Some(mode) = mode {
+            set_file_mode(path, mode)?;
+        }
+        Ok(())
+    }
 }
 
 /// Data to be stored in the compiler cache.
@@ -190,6 +295,8 @@ impl CacheWrite {
     }
 
     /// Create a new cache entry populated with the contents of `objects`.
+    /// A key ending in `/` names a directory, whose files are all stored
+    /// below that key, if it exists.
     pub fn from_objects<T>(objects: T, pool: &ThreadPool) -> SFuture<CacheWrite>
     where
         T: IntoIterator<Item = (String, PathBuf)> + Send + Sync + 'static,
@@ -197,16 +304,39 @@ impl CacheWrite {
         Box::new(pool.spawn_fn(move || -> Result<_> {
             let mut entry = CacheWrite::new();
             for (key, path) in objects {
-                let mut f = fs::File::open(&path)?;
-                let mode = get_file_mode(&f)?;
-                entry
-                    .put_object(&key, &mut f, mode)
-                    .with_context(|| format!("failed to put object `{:?}` in cache entry", path))?;
+                if !key.ends_with('/') {
+                    entry.put_file(&key, &path)?;
+                    continue;
+                }
+                if !path.is_dir() {
+                    continue;
+                }
+                let files = WalkDir::new(&path).sort_by(|a, b| a.file_name().cmp(b.file_name()));
+                for file in files {
+                    let file = file?;
+                    if !file.file_type().is_file() {
+                        continue;
+                    }
+                    let relative = file.path().strip_prefix(&path)?;
+                    let name = relative
+                        .components()
+                        .map(|c| c.as_os_str().to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/");
+                    entry.put_file(&format!("{}{}", key, name), file.path())?;
+                }
             }
             Ok(entry)
         }))
     }
 
+    fn put_file(&mut self, key: &str, path: &Path) -> Result<()> {
+        let mut f = fs::File::open(path)?;
+        let mode = get_file_mode(&f)?;
+        self.put_object(key, &mut f, mode)
+            .with_context(|| format!("failed to put object `{:?}` in cache entry", path))
+    }
+
     /// Add an object containing the contents of `from` to this cache entry at `name`.
     /// If `mode` is `Some`, store the file entry with that mode.
     pub fn put_object<T>(&mut self, name: &str, from: &mut T, mode: Option<u32>) -> Result<()>
@@ -236,6 +366,12 @@ impl CacheWrite {
         self.put_bytes("stderr", bytes)
     }
 
+    /// Record how this entry was produced.
+    pub fn put_metadata(&mut self, metadata: &CacheEntryMetadata) -> Result<()> {
+        let bytes = serde_json::to_vec(metadata)?;
+        self.put_bytes(METADATA_NAME, &bytes)
+    }
+
     fn put_bytes(&mut self, name: &str, bytes: &[u8]) -> Result<()> {
         if !bytes.is_empty() {
             let mut cursor = Cursor::new(bytes);
@@ -285,117 +421,169 @@ pub trait Storage {
     fn max_size(&self) -> SFuture<Option<u64>>;
 }
 
-/// Get a suitable `Storage` implementation from configuration.
-#[allow(clippy::cognitive_complexity)] // TODO simplify!
-pub fn storage_from_config(config: &Config, pool: &ThreadPool) -> Arc<dyn Storage> {
-    for cache_type in config.caches.iter() {
-        match *cache_type {
-            CacheType::Azure(config::AzureCacheConfig) => {
-                debug!("Trying Azure Blob Store account");
-                #[cfg(feature = "azure")]
-                match AzureBlobCache::new() {
-                    Ok(storage) => {
-                        trace!("Using AzureBlobCache");
-                        return Arc::new(storage);
-                    }
-                    Err(e) => warn!("Failed to create Azure cache: {:?}", e),
-                }
-            }
-            CacheType::GCS(config::GCSCacheConfig {
-                ref bucket,
-                ref cred_path,
-                ref url,
-                rw_mode,
-            }) => {
-                debug!(
-                    "Trying GCS bucket({}, {:?}, {:?}, {:?})",
-                    bucket, cred_path, url, rw_mode
-                );
-                #[cfg(feature = "gcs")]
-                {
-                    let service_account_info_opt: Option<gcs::ServiceAccountInfo> =
-                        if let Some(ref cred_path) = *cred_path {
-                            // Attempt to read the service account key from file
-                            let service_account_key_res: Result<gcs::ServiceAccountKey> = (|| {
-                                let mut file = File::open(&cred_path)?;
-                                let mut service_account_json = String::new();
-                                file.read_to_string(&mut service_account_json)?;
-                                Ok(serde_json::from_str(&service_account_json)?)
-                            })(
-                            );
+/// What became of a configured cache backend when choosing the storage to
+/// use.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendOutcome {
+    /// The backend is used.
+    Used,
+    /// The backend couldn't be created, for the given reason.
+    Failed(String),
+    /// A backend tried before this one is used.
+    NotTried,
+}
+
+/// A cache backend considered when choosing the storage to use.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct BackendChoice {
+    /// The name of the backend, as in its `[cache.<name>]` section.
+    pub backend: &'static str,
+    pub outcome: BackendOutcome,
+}
+
+/// Create the storage for the cache backend `cache_type`.
+#[allow(unused_variables)]
+fn storage_from_cache_type(cache_type: &CacheType, pool: &ThreadPool) -> Result<Arc<dyn Storage>> {
+    match *cache_type {
+        CacheType::Azure(config::AzureCacheConfig) => {
+            debug!("Trying Azure Blob Store account");
+            #[cfg(feature = "azure")]
+            return AzureBlobCache::new().map(|storage| {
+                trace!("Using AzureBlobCache");
+                Arc::new(storage) as Arc<dyn Storage>
+            });
+            #[cfg(not(feature = "azure"))]
+            bail!("sccache was built without Azure support");
+        }
+        CacheType::GCS(config::GCSCacheConfig {
+            ref bucket,
+            ref cred_path,
+            ref url,
+            rw_mode,
+        }) => {
+            debug!(
+                "Trying GCS bucket({}, {:?}, {:?}, {:?})",
+                bucket, cred_path, url, rw_mode
+            );
+            #[cfg(feature = "gcs")]
+            {
+                let service_account_info_opt: Option<gcs::ServiceAccountInfo> =
+                    if let Some(ref cred_path) = *cred_path {
+                        // Attempt to read the service account key from file
+                        let service_account_key_res: Result<gcs::ServiceAccountKey> = (|| {
+                            let mut file = File::open(cred_path)?;
+                            let mut service_account_json = String::new();
+                            file.read_to_string(&mut service_account_json)?;
+                            Ok(serde_json::from_str(&service_account_json)?)
+                        })(
+                        );
 
-                            // warn! if an error was encountered reading the key from the file
-                            if let Err(ref e) = service_account_key_res {
-                                warn!(
-                                    "Failed to parse service account credentials from file: {:?}. \
-                                     Continuing without authentication.",
-                                    e
-                                );
-                            }
-
-                            service_account_key_res
-                                .ok()
-                                .map(ServiceAccountInfo::AccountKey)
-                        } else if let Some(ref url) = *url {
-                            Some(ServiceAccountInfo::URL(url.clone()))
-                        } else {
+                        // warn! if an error was encountered reading the key from the file
+                        if let Err(ref e) = service_account_key_res {
                             warn!(
+                                "Failed to parse service account credentials from file: {:?}. \
+                                 Continuing without authentication.",
+                                e
+                            );
+                        }
+
+                        service_account_key_res
+                            .ok()
+                            .map(ServiceAccountInfo::AccountKey)
+                    } else if let Some(ref url) = *url {
+                        Some(ServiceAccountInfo::URL(url.clone()))
+                    } else {
+                        warn!(
                             "No SCCACHE_GCS_KEY_PATH specified-- no authentication will be used."
                         );
-                            None
-                        };
-
-                    let gcs_read_write_mode = match rw_mode {
-                        config::GCSCacheRWMode::ReadOnly => RWMode::ReadOnly,
-                        config::GCSCacheRWMode::ReadWrite => RWMode::ReadWrite,
+                        None
                     };
 
-                    let gcs_cred_provider = service_account_info_opt
-                        .map(|info| GCSCredentialProvider::new(gcs_read_write_mode, info));
+                let gcs_read_write_mode = match rw_mode {
+                    config::GCSCacheRWMode::ReadOnly => RWMode::ReadOnly,
+                    config::GCSCacheRWMode::ReadWrite => RWMode::ReadWrite,
+                };
 
-                    match GCSCache::new(bucket.to_owned(), gcs_cred_provider, gcs_read_write_mode) {
-                        Ok(s) => {
-                            trace!("Using GCSCache");
-                            return Arc::new(s);
-                        }
-                        Err(e) => warn!("Failed to create GCS Cache: {:?}", e),
-                    }
-                }
+                let gcs_cred_provider = service_account_info_opt
+                    .map(|info| GCSCredentialProvider::new(gcs_read_write_mode, info));
+
+                let storage =
+                    GCSCache::new(bucket.to_owned(), gcs_cred_provider, gcs_read_write_mode)?;
+                trace!("Using GCSCache");
+                Ok(Arc::new(storage))
             }
-            CacheType::Memcached(config::MemcachedCacheConfig { ref url }) => {
-                debug!("Trying Memcached({})", url);
-                #[cfg(feature = "memcached")]
-                match MemcachedCache::new(&url, pool) {
-                    Ok(s) => {
-                        trace!("Using Memcached: {}", url);
-                        return Arc::new(s);
-                    }
-                    Err(e) => warn!("Failed to create MemcachedCache: {:?}", e),
+            #[cfg(not(feature = "gcs"))]
+            bail!("sccache was built without GCS support");
+        }
+        CacheType::Memcached(config::MemcachedCacheConfig { ref url }) => {
+            debug!("Trying Memcached({})", url);
+            #[cfg(feature = "memcached")]
+            return MemcachedCache::new(url, pool).map(|storage| {
+                trace!("Using Memcached: {}", url);
+                Arc::new(storage) as Arc<dyn Storage>
+            });
+            #[cfg(not(feature = "memcached"))]
+            bail!("sccache was built without Memcached support");
+        }
+        CacheType::Redis(config::RedisCacheConfig { ref url }) => {
+            debug!("Trying Redis({})", url);
+            #[cfg(feature = "redis")]
+            return RedisCache::new(url).map(|storage| {
+                trace!("Using Redis: {}", url);
+                Arc::new(storage) as Arc<dyn Storage>
+            });
+            #[cfg(not(feature = "redis"))]
+            bail!("sccache was built without Redis support");
+        }
+        CacheType::S3(ref c) => {
+            debug!("Trying S3Cache({}, {})", c.bucket, c.endpoint);
+            #[cfg(feature = "s3")]
+            return S3Cache::new(&c.bucket, &c.endpoint, c.use_ssl, &c.key_prefix).map(|storage| {
+                trace!("Using S3Cache");
+                Arc::new(storage) as Arc<dyn Storage>
+            });
+            #[cfg(not(feature = "s3"))]
+            bail!("sccache was built without S3 support");
+        }
+    }
+}
+
+/// Create the first of the configured remote cache backends that can be
+/// created, if any, recording what became of each of them.
+fn remote_storage_from_config(
+    config: &Config,
+    pool: &ThreadPool,
+) -> (Option<Arc<dyn Storage>>, Vec<BackendChoice>) {
+    let mut storage = None;
+    let mut choices = vec![];
+    for cache_type in config.caches.iter() {
+        let outcome = if storage.is_some() {
+            BackendOutcome::NotTried
+        } else {
+            match storage_from_cache_type(cache_type, pool) {
+                Ok(s) => {
+                    storage = Some(s);
+                    BackendOutcome::Used
                 }
-            }
-            CacheType::Redis(config::RedisCacheConfig { ref url }) => {
-                debug!("Trying Redis({})", url);
-                #[cfg(feature = "redis")]
-                match RedisCache::new(&url) {
-                    Ok(s) => {
-                        trace!("Using Redis: {}", url);
-                        return Arc::new(s);
-                    }
-                    Err(e) => warn!("Failed to create RedisCache: {:?}", e),
+                Err(e) => {
+                    warn!("Failed to create {} cache: {:?}", cache_type.name(), e);
+                    BackendOutcome::Failed(format!("{:#}", e))
                 }
             }
-            CacheType::S3(ref c) => {
-                debug!("Trying S3Cache({}, {})", c.bucket, c.endpoint);
-                #[cfg(feature = "s3")]
-                match S3Cache::new(&c.bucket, &c.endpoint, c.use_ssl, &c.key_prefix) {
-                    Ok(s) => {
-                        trace!("Using S3Cache");
-                        return Arc::new(s);
-                    }
-                    Err(e) => warn!("Failed to create S3Cache: {:?}", e),
-                }
-            }
-        }
+        };
+        choices.push(BackendChoice {
+            backend: cache_type.name(),
+            outcome,
+        });
+    }
+    (storage, choices)
+}
+
+/// Get a suitable `Storage` implementation from configuration.
+pub fn storage_from_config(config: &Config, pool: &ThreadPool) -> Arc<dyn Storage> {
+    if let (Some(storage), _) = remote_storage_from_config(config, pool) {
+        return storage;
     }
 
     info!("No configured caches successful, falling back to default");
@@ -403,3 +591,126 @@ pub fn storage_from_config(config: &Config, pool: &ThreadPool) -> Arc<dyn Storag
     trace!("Using DiskCache({:?}, {})", dir, size);
     Arc::new(DiskCache::new(&dir, size, pool))
 }
+
+/// The cache backends `storage_from_config` tries for `config`, in order and
+/// ending with the local disk cache, and what became of each.
+pub fn storage_choices(config: &Config, pool: &ThreadPool) -> Vec<BackendChoice> {
+    let (storage, mut choices) = remote_storage_from_config(config, pool);
+    choices.push(BackendChoice {
+        backend: "disk",
+        outcome: if storage.is_some() {
+            BackendOutcome::NotTried
+        } else {
+            BackendOutcome::Used
+        },
+    });
+    choices
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::utils::{create_file, ThreadPoolExt};
+    use futures::Future;
+    use std::io::Write;
+
+    #[test]
+    fn test_storage_choices() {
+        let pool = ThreadPool::sized(1);
+        let tempdir = tempfile::Builder::new()
+            .prefix("sccache_test_cache")
+            .tempdir()
+            .unwrap();
+        let config = Config {
+            caches: vec![CacheType::Redis(config::RedisCacheConfig {
+                url: "bogus".to_owned(),
+            })],
+            fallback_cache: config::DiskCacheConfig {
+                dir: tempdir.path().to_owned(),
+                size: 1024,
+            },
+            ..Default::default()
+        };
+        let choices = storage_choices(&config, &pool);
+        assert_eq!(
+            choices.iter().map(|c| c.backend).collect::<Vec<_>>(),
+            vec!["redis", "disk"]
+        );
+        match choices[0].outcome {
+            BackendOutcome::Failed(_) => {}
+            ref outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+        assert_eq!(choices[1].outcome, BackendOutcome::Used);
+    }
+
+    #[test]
+    fn test_directory_objects() {
+        let pool = ThreadPool::sized(1);
+        let tempdir = tempfile::Builder::new()
+            .prefix("sccache_test_cache")
+            .tempdir()
+            .unwrap();
+        let out = tempdir.path().join("out");
+        create_file(&out, "doc/index.html", |mut f| f.write_all(b"index")).unwrap();
+        create_file(&out, "doc/sub/page.html", |mut f| f.write_all(b"page")).unwrap();
+        create_file(&out, "lib.o", |mut f| f.write_all(b"object")).unwrap();
+        let objects = vec![
+            ("doc/".to_owned(), out.join("doc")),
+            ("missing/".to_owned(), out.join("missing")),
+            ("lib.o".to_owned(), out.join("lib.o")),
+        ];
+        let entry = CacheWrite::from_objects(objects.clone(), &pool)
+            .wait()
+            .unwrap();
+        let data = entry.finish().unwrap();
+
+        let restored = tempdir.path().join("restored");
+        fs::create_dir(&restored).unwrap();
+        let objects: Vec<_> = objects
+            .into_iter()
+            .map(|(key, path)| (key, restored.join(path.strip_prefix(&out).unwrap())))
+            .collect();
+        CacheRead::from(Cursor::new(data))
+            .unwrap()
+            .extract_objects(objects, &pool)
+            .wait()
+            .unwrap();
+        assert_eq!(fs::read(restored.join("doc/index.html")).unwrap(), b"index");
+        assert_eq!(
+            fs::read(restored.join("doc/sub/page.html")).unwrap(),
+            b"page"
+        );
+        assert_eq!(fs::read(restored.join("lib.o")).unwrap(), b"object");
+        assert!(!restored.join("missing").exists());
+    }
+
+    #[test]
+    fn test_metadata() {
+        let metadata = CacheEntryMetadata::new(
+            Duration::from_millis(1500),
+            "gcc".to_owned(),
+            Some("9.3.0".to_owned()),
+            vec!["obj".to_owned()],
+        );
+        let mut entry = CacheWrite::new();
+        entry
+            .put_object("obj", &mut Cursor::new(b"object".to_vec()), None)
+            .unwrap();
+        entry.put_metadata(&metadata).unwrap();
+        let mut read = CacheRead::from(Cursor::new(entry.finish().unwrap())).unwrap();
+        assert_eq!(Some(metadata.clone()), read.get_metadata());
+        // The metadata survives copying the entry.
+        let mut read = CacheRead::from(Cursor::new(
+            read.into_cache_write().unwrap().finish().unwrap(),
+        ))
+        .unwrap();
+        assert_eq!(Some(metadata), read.get_metadata());
+
+        // Entries without metadata are still readable.
+        let mut entry = CacheWrite::new();
+        entry.put_stdout(b"stdout").unwrap();
+        let mut read = CacheRead::from(Cursor::new(entry.finish().unwrap())).unwrap();
+        assert_eq!(None, read.get_metadata());
+        assert_eq!(b"stdout", &read.get_stdout()[..]);
+    }
+}
@@ -13,7 +13,7 @@
 // limitations under the License.
 
 use crate::errors::*;
-use crate::protocol::{Request, Response};
+use crate::protocol::{Handshake, Request, Response};
 use crate::util;
 use byteorder::{BigEndian, ByteOrder};
 use retry::{delay::Fixed, retry};
@@ -46,6 +46,48 @@ impl ServerConnection {
         self.read_one_response()
     }
 
+    /// Exchange versions with the server.
+    ///
+    /// Fails with `StaleServerError` if the server is older than the client.
+    /// Servers predating the handshake can't decode the request and close
+    /// the connection, which is reported the same way. Any other failure,
+    /// including a newer server, is a plain error.
+    pub fn handshake(&mut self) -> Result<()> {
+        trace!("ServerConnection::handshake");
+        let ours = Handshake::current();
+        util::write_length_prefixed_bincode(&mut self.writer, Request::Handshake(ours.clone()))
+            .context("Failed to send the handshake to the sccache server")?;
+        let theirs = match self.read_one_response() {
+            Ok(Response::Handshake(theirs)) => theirs,
+            Ok(_) => bail!("Unexpected server response to handshake"),
+            Err(e) if is_undecodable(&e) => {
+                debug!("handshake failed: {:?}", e);
+                return Err(StaleServerError(format!(
+                    "sccache server did not understand the handshake, it is probably \
+                     older than sccache {}",
+                    ours.version
+                ))
+                .into());
+            }
+            Err(e) => {
+                return Err(e.context("Failed to receive the handshake from the sccache server"))
+            }
+        };
+        if theirs == ours {
+            return Ok(());
+        }
+        let mismatch = format!(
+            "sccache server version mismatch: server is {} (protocol {}), \
+             client is {} (protocol {})",
+            theirs.version, theirs.protocol_version, ours.version, ours.protocol_version
+        );
+        if theirs.is_older_than(&ours) {
+            Err(StaleServerError(mismatch).into())
+        } else {
+            bail!(mismatch)
+        }
+    }
+
     /// Read a single `Response` from the server.
     pub fn read_one_response(&mut self) -> Result<Response> {
         trace!("ServerConnection::read_one_response");
@@ -62,6 +104,15 @@ impl ServerConnection {
     }
 }
 
+/// Whether `e` means the server closed the connection or sent something we
+/// can't decode, rather than failing to respond in time.
+fn is_undecodable(e: &Error) -> bool {
+    if let Some(e) = e.downcast_ref::<io::Error>() {
+        return e.kind() == io::ErrorKind::UnexpectedEof;
+    }
+    e.downcast_ref::<bincode::Error>().is_some()
+}
+
 /// Establish a TCP connection to an sccache server listening on `port`.
 pub fn connect_to_server(port: u16) -> io::Result<ServerConnection> {
     trace!("connect_to_server({})", port);
@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use crate::errors::*;
+use chrono::NaiveDate;
 use clap::{App, AppSettings, Arg};
 use std::env;
 use std::ffi::OsString;
@@ -28,10 +29,19 @@ arg_enum! {
     }
 }
 
+/// Statistics recorded across server runs to show instead of those of the
+/// running server.
+pub struct StatsHistoryView {
+    /// Only include statistics recorded on or after this day.
+    pub since: Option<NaiveDate>,
+    /// Show statistics for each day separately.
+    pub per_day: bool,
+}
+
 /// A specific command to run.
 pub enum Command {
     /// Show cache statistics and exit.
-    ShowStats(StatsFormat),
+    ShowStats(StatsFormat, Option<StatsHistoryView>),
     /// Run background server.
     InternalStartServer,
     /// Start background server as a subprocess.
@@ -40,6 +50,10 @@ pub enum Command {
     StopServer,
     /// Zero cache statistics and exit.
     ZeroStats,
+    /// Make the background server reload its configuration.
+    ReloadConfig,
+    /// Show the effective configuration and exit.
+    ShowConfig(StatsFormat),
     /// Show the status of the distributed client.
     DistStatus,
     /// Perform a login to authenticate for distributed compilation.
@@ -77,6 +91,8 @@ pub fn get_app<'a, 'b>() -> App<'a, 'b> {
              --start-server   'start background server'
              --stop-server    'stop background server'
              -z, --zero-stats 'zero statistics counters'
+             --reload-config  'reload the configuration of the background server'
+             --show-config    'show the effective configuration and where each setting came from'
              --dist-auth      'authenticate for distributed compilation'
              --dist-status    'show status of the distributed client'"
                 )
@@ -85,6 +101,14 @@ pub fn get_app<'a, 'b>() -> App<'a, 'b> {
         .arg(Arg::from_usage("--stats-format  'set output format of statistics'")
              .possible_values(&StatsFormat::variants())
              .default_value("text"))
+        .arg(Arg::from_usage("--config-format  'set output format of the configuration'")
+             .possible_values(&StatsFormat::variants())
+             .default_value("text"))
+        .arg(Arg::from_usage("--since [day] 'show statistics recorded since a day (YYYY-MM-DD), across server restarts'")
+             .requires("show-stats")
+             .validator(|day| parse_day(&day).map(|_| ()).map_err(|e| e.to_string())))
+        .arg(Arg::from_usage("--per-day 'show statistics recorded across server restarts for each day'")
+             .requires("show-stats"))
         .arg(
             Arg::with_name("cmd")
                 .multiple(true)
@@ -92,6 +116,12 @@ pub fn get_app<'a, 'b>() -> App<'a, 'b> {
                 )
 }
 
+/// Parse a day given on the commandline.
+fn parse_day(day: &str) -> Result<NaiveDate> {
+    NaiveDate::parse_from_str(day, "%Y-%m-%d")
+        .with_context(|| format!("Invalid day {:?}, expected YYYY-MM-DD", day))
+}
+
 /// Parse the commandline into a `Command` to execute.
 pub fn parse() -> Result<Command> {
     trace!("parse");
@@ -147,6 +177,8 @@ pub fn parse() -> Result<Command> {
     let start_server = matches.is_present("start-server");
     let stop_server = matches.is_present("stop-server");
     let zero_stats = matches.is_present("zero-stats");
+    let reload_config = matches.is_present("reload-config");
+    let show_config = matches.is_present("show-config");
     let dist_auth = matches.is_present("dist-auth");
     let dist_status = matches.is_present("dist-status");
     let package_toolchain = matches.is_present("package-toolchain");
@@ -161,6 +193,8 @@ pub fn parse() -> Result<Command> {
         start_server,
         stop_server,
         zero_stats,
+        reload_config,
+        show_config,
         package_toolchain,
         is_some(&cmd),
     ]
@@ -176,13 +210,26 @@ pub fn parse() -> Result<Command> {
     } else if show_stats {
         let fmt =
             value_t!(matches.value_of("stats-format"), StatsFormat).unwrap_or_else(|e| e.exit());
-        Ok(Command::ShowStats(fmt))
+        let since = matches.value_of("since").map(parse_day).transpose()?;
+        let per_day = matches.is_present("per-day");
+        let history = if since.is_some() || per_day {
+            Some(StatsHistoryView { since, per_day })
+        } else {
+            None
+        };
+        Ok(Command::ShowStats(fmt, history))
     } else if start_server {
         Ok(Command::StartServer)
     } else if stop_server {
         Ok(Command::StopServer)
     } else if zero_stats {
         Ok(Command::ZeroStats)
+    } else if reload_config {
+        Ok(Command::ReloadConfig)
+    } else if show_config {
+        let fmt =
+            value_t!(matches.value_of("config-format"), StatsFormat).unwrap_or_else(|e| e.exit());
+        Ok(Command::ShowConfig(fmt))
     } else if dist_auth {
         Ok(Command::DistAuth)
     } else if dist_status {
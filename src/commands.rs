@@ -12,18 +12,21 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::cache::{storage_choices, BackendChoice, BackendOutcome};
 use crate::client::{connect_to_server, connect_with_retry, ServerConnection};
 use crate::cmdline::{Command, StatsFormat};
 use crate::compiler::ColorMode;
-use crate::config::Config;
+use crate::config::{Config, ConfigSetting};
 use crate::jobserver::Client;
 use crate::mock_command::{CommandChild, CommandCreatorSync, ProcessCommandCreator, RunCommand};
 use crate::protocol::{Compile, CompileFinished, CompileResponse, Request, Response};
 use crate::server::{self, DistInfo, ServerInfo, ServerStartup};
+use crate::stats_history::StatsHistory;
 use crate::util::daemonize;
 use atty::Stream;
 use byteorder::{BigEndian, ByteOrder};
 use futures::Future;
+use futures_03::executor::ThreadPool;
 use log::Level::Trace;
 use std::env;
 use std::ffi::{OsStr, OsString};
@@ -31,7 +34,7 @@ use std::fs::{File, OpenOptions};
 use std::io::{self, Write};
 #[cfg(unix)]
 use std::os::unix::process::ExitStatusExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process;
 use strip_ansi_escapes::Writer;
 use tokio_compat::runtime::current_thread::Runtime;
@@ -266,40 +269,91 @@ fn run_server_process() -> Result<ServerStartup> {
 }
 
 /// Attempt to connect to an sccache server listening on `port`, or start one if no server is running.
+///
+/// A running server older than this binary is stopped and replaced. Any other
+/// server that fails the handshake is left running.
 fn connect_or_start_server(port: u16) -> Result<ServerConnection> {
     trace!("connect_or_start_server({})", port);
     match connect_to_server(port) {
-        Ok(server) => Ok(server),
+        Ok(mut server) => match server.handshake() {
+            Ok(()) => Ok(server),
+            Err(e) => match e.downcast::<StaleServerError>() {
+                Ok(e) => {
+                    eprintln!("sccache: {}, restarting the server", e);
+                    stop_stale_server(port)?;
+                    start_server_and_connect(port)
+                }
+                Err(e) => {
+                    Err(e.context(format!("Could not use the sccache server on port {}", port)))
+                }
+            },
+        },
         Err(ref e)
             if e.kind() == io::ErrorKind::ConnectionRefused
                 || e.kind() == io::ErrorKind::TimedOut =>
         {
             // If the connection was refused we probably need to start
             // the server.
-            match run_server_process()? {
-                ServerStartup::Ok { port: actualport } => {
-                    if port != actualport {
-                        // bail as the next connect_with_retry will fail
-                        bail!(
-                            "sccache: Listening on port {} instead of {}",
-                            actualport,
-                            port
-                        );
-                    }
-                }
-                ServerStartup::AddrInUse => {
-                    debug!("AddrInUse: possible parallel server bootstraps, retrying..")
-                }
-                ServerStartup::TimedOut => bail!("Timed out waiting for server startup"),
-                ServerStartup::Err { reason } => bail!("Server startup failed: {}", reason),
-            }
-            let server = connect_with_retry(port)?;
-            Ok(server)
+            start_server_and_connect(port)
         }
         Err(e) => Err(e.into()),
     }
 }
 
+/// Start a server process listening on `port` and connect to it.
+fn start_server_and_connect(port: u16) -> Result<ServerConnection> {
+    match run_server_process()? {
+        ServerStartup::Ok { port: actualport } => {
+            if port != actualport {
+                // bail as the next connect_with_retry will fail
+                bail!(
+                    "sccache: Listening on port {} instead of {}",
+                    actualport,
+                    port
+                );
+            }
+        }
+        ServerStartup::AddrInUse => {
+            debug!("AddrInUse: possible parallel server bootstraps, retrying..")
+        }
+        ServerStartup::TimedOut => bail!("Timed out waiting for server startup"),
+        ServerStartup::Err { reason } => bail!("Server startup failed: {}", reason),
+    }
+    let mut server = connect_with_retry(port)?;
+    server.handshake().with_context(|| {
+        format!(
+            "Could not replace the sccache server on port {}, stop it with `sccache --stop-server` and try again",
+            port
+        )
+    })?;
+    Ok(server)
+}
+
+/// Shut down a server on `port` that failed the version handshake, and wait for it to exit.
+fn stop_stale_server(port: u16) -> Result<()> {
+    use std::thread;
+    use std::time::Duration;
+
+    trace!("stop_stale_server({})", port);
+    // `Shutdown` is understood by servers predating the handshake, but
+    // there's nothing useful to do with its response or a failure here.
+    if let Ok(conn) = connect_to_server(port) {
+        if let Err(e) = request_shutdown(conn) {
+            debug!("Failed to shut down stale server: {:?}", e);
+        }
+    }
+    for _ in 0..20 {
+        if connect_to_server(port).is_err() {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(250));
+    }
+    bail!(
+        "Timed out waiting for the stale sccache server on port {} to exit, stop it with `sccache --stop-server` and try again",
+        port
+    )
+}
+
 /// Send a `ZeroStats` request to the server, and return the `ServerInfo` request if successful.
 pub fn request_zero_stats(mut conn: ServerConnection) -> Result<ServerInfo> {
     debug!("request_stats");
@@ -313,6 +367,23 @@ pub fn request_zero_stats(mut conn: ServerConnection) -> Result<ServerInfo> {
     }
 }
 
+/// Send a `ReloadConfig` request to the server, and return the `ServerInfo`
+/// with the new configuration if successful.
+pub fn request_reload_config(mut conn: ServerConnection) -> Result<ServerInfo> {
+    debug!("request_reload_config");
+    let response = conn
+        .request(Request::ReloadConfig)
+        .context("Failed to send data to or receive data from server")?;
+    match response {
+        Response::ConfigReloaded(info) => Ok(*info),
+        Response::ConfigReloadFailed(reason) => bail!(
+            "The server couldn't reload its configuration and kept the old one: {}",
+            reason
+        ),
+        _ => bail!("Unexpected server response!"),
+    }
+}
+
 /// Send a `GetStats` request to the server, and return the `ServerInfo` request if successful.
 pub fn request_stats(mut conn: ServerConnection) -> Result<ServerInfo> {
     debug!("request_stats");
@@ -326,6 +397,19 @@ pub fn request_stats(mut conn: ServerConnection) -> Result<ServerInfo> {
     }
 }
 
+/// Send a `GetStatsHistory` request to the server, and return the `StatsHistory` if successful.
+pub fn request_stats_history(mut conn: ServerConnection) -> Result<StatsHistory> {
+    debug!("request_stats_history");
+    let response = conn
+        .request(Request::GetStatsHistory)
+        .context("Failed to send data to or receive data from server")?;
+    if let Response::StatsHistory(history) = response {
+        Ok(*history)
+    } else {
+        bail!("Unexpected server response!")
+    }
+}
+
 /// Send a `DistStatus` request to the server, and return `DistStatus` if successful.
 pub fn request_dist_status(mut conn: ServerConnection) -> Result<DistInfo> {
     debug!("request_dist_status");
@@ -561,6 +645,61 @@ where
     )
 }
 
+/// The effective configuration, as shown by `--show-config`.
+#[derive(Serialize)]
+struct ConfigReport {
+    config_file: PathBuf,
+    config_file_found: bool,
+    settings: Vec<ConfigSetting>,
+    /// The cache backends the server would try, in order.
+    cache_backends: Vec<BackendChoice>,
+}
+
+impl ConfigReport {
+    /// Print the configuration to stdout in a human-readable format.
+    fn print(&self) {
+        println!(
+            "Config file: {} ({})",
+            self.config_file.display(),
+            if self.config_file_found {
+                "found"
+            } else {
+                "not found"
+            }
+        );
+        println!();
+        let values: Vec<_> = self.settings.iter().map(|s| s.value.to_string()).collect();
+        let name_width = self
+            .settings
+            .iter()
+            .map(|s| s.name.len())
+            .max()
+            .unwrap_or(0);
+        let value_width = values.iter().map(|v| v.len()).max().unwrap_or(0);
+        for (setting, value) in self.settings.iter().zip(values) {
+            println!(
+                "{:<name_width$} = {:<value_width$} ({})",
+                setting.name,
+                value,
+                setting.source,
+                name_width = name_width,
+                value_width = value_width
+            );
+        }
+        println!();
+        println!("Cache backends:");
+        for choice in &self.cache_backends {
+            match choice.outcome {
+                BackendOutcome::Used => println!("  {}: used", choice.backend),
+                BackendOutcome::Failed(ref reason) => {
+                    println!("  {}: failed: {}", choice.backend, reason)
+                }
+                BackendOutcome::NotTried => println!("  {}: not tried", choice.backend),
+            }
+        }
+    }
+}
+
 /// Run `cmd` and return the process exit status.
 pub fn run_command(cmd: Command) -> Result<i32> {
     // Config isn't required for all commands, but if it's broken then we should flag
@@ -568,7 +707,7 @@ pub fn run_command(cmd: Command) -> Result<i32> {
     let config = &Config::load()?;
 
     match cmd {
-        Command::ShowStats(fmt) => {
+        Command::ShowStats(fmt, None) => {
             trace!("Command::ShowStats({:?})", fmt);
             let srv = connect_or_start_server(get_port())?;
             let stats = request_stats(srv).context("failed to get stats from server")?;
@@ -577,6 +716,22 @@ pub fn run_command(cmd: Command) -> Result<i32> {
                 StatsFormat::json => serde_json::to_writer(&mut io::stdout(), &stats)?,
             }
         }
+        Command::ShowStats(fmt, Some(view)) => {
+            trace!("Command::ShowStats({:?}, history)", fmt);
+            let srv = connect_or_start_server(get_port())?;
+            let mut history =
+                request_stats_history(srv).context("failed to get stats history from server")?;
+            if let Some(since) = view.since {
+                history = history.since(since);
+            }
+            if !view.per_day {
+                history.days.clear();
+            }
+            match fmt {
+                StatsFormat::text => history.print(view.per_day),
+                StatsFormat::json => serde_json::to_writer(&mut io::stdout(), &history)?,
+            }
+        }
         Command::InternalStartServer => {
             trace!("Command::InternalStartServer");
             // Can't report failure here, we're already daemonized.
@@ -612,6 +767,28 @@ pub fn run_command(cmd: Command) -> Result<i32> {
             let stats = request_zero_stats(conn).context("couldn't zero stats on server")?;
             stats.print();
         }
+        Command::ReloadConfig => {
+            trace!("Command::ReloadConfig");
+            let conn = connect_or_start_server(get_port())?;
+            let info = request_reload_config(conn)?;
+            println!("sccache: Reloaded the server configuration");
+            info.print();
+        }
+        Command::ShowConfig(fmt) => {
+            trace!("Command::ShowConfig({:?})", fmt);
+            let effective = Config::load_effective()?;
+            let pool = ThreadPool::builder().pool_size(1).create()?;
+            let report = ConfigReport {
+                cache_backends: storage_choices(&effective.config, &pool),
+                config_file: effective.config_file,
+                config_file_found: effective.config_file_found,
+                settings: effective.settings,
+            };
+            match fmt {
+                StatsFormat::text => report.print(),
+                StatsFormat::json => serde_json::to_writer(&mut io::stdout(), &report)?,
+            }
+        }
         #[cfg(feature = "dist-client")]
         Command::DistAuth => {
             use crate::config;
@@ -678,7 +855,6 @@ pub fn run_command(cmd: Command) -> Result<i32> {
         #[cfg(feature = "dist-client")]
         Command::PackageToolchain(executable, out) => {
             use crate::compiler;
-            use futures_03::executor::ThreadPool;
 
             trace!("Command::PackageToolchain({})", executable.display());
             let mut runtime = Runtime::new()?;
@@ -689,8 +865,15 @@ pub fn run_command(cmd: Command) -> Result<i32> {
             let out_file = File::create(out)?;
             let cwd = env::current_dir().expect("A current working dir should exist");
 
-            let compiler =
-                compiler::get_compiler_info(creator, &executable, &cwd, &env, &pool, None);
+            let compiler = compiler::get_compiler_info(
+                creator,
+                &executable,
+                &cwd,
+                &env,
+                &pool,
+                None,
+                &config.compiler,
+            );
             let packager = compiler.map(|c| c.0.get_toolchain_packager());
             let res = packager.and_then(|p| p.write_pkg(out_file));
             runtime.block_on(res)?
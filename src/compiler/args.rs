@@ -566,6 +566,31 @@ impl<T: ArgumentValue> SearchableArgInfo<T> for (&'static [ArgInfo<T>], &'static
     }
 }
 
+/// Allow to search over three arrays of ArgInfo, where each complements or
+/// overrides the ones before it.
+impl<T: ArgumentValue> SearchableArgInfo<T>
+    for (
+        &'static [ArgInfo<T>],
+        &'static [ArgInfo<T>],
+        &'static [ArgInfo<T>],
+    )
+{
+    fn search(&self, key: &str) -> Option<&ArgInfo<T>> {
+        [self.0.search(key), self.1.search(key), self.2.search(key)]
+            .iter()
+            .flatten()
+            .fold(None, |found, &b| match found {
+                Some(a) if a.flag_str() > b.flag_str() => Some(a),
+                _ => Some(b),
+            })
+    }
+
+    #[cfg(debug_assertions)]
+    fn check(&self) -> bool {
+        self.0.check() && self.1.check() && self.2.check()
+    }
+}
+
 /// An `Iterator` for parsed arguments
 pub struct ArgsIter<I, T, S>
 where
@@ -12,17 +12,19 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::compiler::args::{Argument, ArgumentValue};
 use crate::compiler::{
-    Cacheable, ColorMode, Compilation, CompileCommand, Compiler, CompilerArguments, CompilerHasher,
-    CompilerKind, HashResult,
+    compiler_check_digests, Cacheable, ColorMode, Compilation, CompileCommand, Compiler,
+    CompilerArguments, CompilerHasher, CompilerKind, DetectedCompiler, HashResult,
 };
 #[cfg(feature = "dist-client")]
 use crate::compiler::{DistPackagers, NoopOutputsRewriter};
+use crate::config::{CCompilerConfig, Sloppiness};
 use crate::dist;
 #[cfg(feature = "dist-client")]
 use crate::dist::pkg;
 use crate::mock_command::CommandCreatorSync;
-use crate::util::{hash_all, Digest, HashToDigest};
+use crate::util::{hash_all, Digest, HashToDigest, SpawnExt};
 use futures::Future;
 use futures_03::executor::ThreadPool;
 use std::borrow::Cow;
@@ -39,14 +41,20 @@ use std::process;
 use crate::errors::*;
 
 /// A generic implementation of the `Compiler` trait for C/C++ compilers.
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct CCompiler<I>
 where
     I: CCompilerImpl,
 {
-    executable: PathBuf,
+    pub(crate) executable: PathBuf,
     executable_digest: String,
+    /// The expansion of `__VERSION__`, if the compiler defines it.
+    #[serde(default)]
+    version: Option<String>,
     compiler: I,
+    /// Restored from the current configuration, not stored.
+    #[serde(skip)]
+    pub(crate) config: CCompilerConfig,
 }
 
 /// A generic implementation of the `CompilerHasher` trait for C/C++ compilers.
@@ -58,16 +66,25 @@ where
     parsed_args: ParsedArguments,
     executable: PathBuf,
     executable_digest: String,
+    version: Option<String>,
     compiler: I,
+    config: CCompilerConfig,
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Language {
     C,
     Cxx,
+    CxxModule,
     ObjectiveC,
     ObjectiveCxx,
     Cuda,
+    Assembler,
+    AssemblerWithCpp,
+    PreprocessedC,
+    PreprocessedCxx,
+    Fortran,
+    FortranWithCpp,
 }
 
 /// The results of parsing a compiler commandline.
@@ -92,6 +109,9 @@ pub struct ParsedArguments {
     pub common_args: Vec<OsString>,
     /// Extra files that need to have their contents hashed.
     pub extra_hash_files: Vec<PathBuf>,
+    /// Fortran module files written by the compilation, which are cached
+    /// under their file names along with `outputs`.
+    pub module_outputs: Vec<PathBuf>,
     /// Whether or not the `-showIncludes` argument is passed on MSVC
     pub msvc_show_includes: bool,
     /// Whether the compilation is generating profiling or coverage data.
@@ -110,6 +130,25 @@ impl ParsedArguments {
             .map(|s| s.to_string_lossy())
             .unwrap_or(Cow::Borrowed("Unknown filename"))
     }
+
+    /// The arguments that go into the hash key besides the preprocessed input.
+    ///
+    /// Inputs that don't need the preprocessor are hashed as they are, but the
+    /// preprocessor arguments are still passed to the compiler with them, so
+    /// they are hashed too.
+    pub fn hashed_args(&self) -> Cow<'_, [OsString]> {
+        if self.language.needs_preprocessor() {
+            Cow::Borrowed(&self.common_args)
+        } else {
+            Cow::Owned(
+                self.common_args
+                    .iter()
+                    .chain(&self.preprocessor_args)
+                    .cloned()
+                    .collect(),
+            )
+        }
+    }
 }
 
 impl Language {
@@ -117,9 +156,18 @@ impl Language {
         match file.extension().and_then(|e| e.to_str()) {
             Some("c") => Some(Language::C),
             Some("C") | Some("cc") | Some("cpp") | Some("cxx") => Some(Language::Cxx),
+            Some("cppm") | Some("ccm") | Some("cxxm") | Some("c++m") => Some(Language::CxxModule),
             Some("m") => Some(Language::ObjectiveC),
             Some("mm") => Some(Language::ObjectiveCxx),
             Some("cu") => Some(Language::Cuda),
+            Some("s") => Some(Language::Assembler),
+            Some("S") | Some("sx") => Some(Language::AssemblerWithCpp),
+            Some("i") => Some(Language::PreprocessedC),
+            Some("ii") => Some(Language::PreprocessedCxx),
+            Some("f") | Some("for") | Some("ftn") | Some("f90") | Some("f95") | Some("f03")
+            | Some("f08") => Some(Language::Fortran),
+            Some("F") | Some("FOR") | Some("FTN") | Some("fpp") | Some("FPP") | Some("F90")
+            | Some("F95") | Some("F03") | Some("F08") => Some(Language::FortranWithCpp),
             e => {
                 trace!("Unknown source extension: {}", e.unwrap_or("(None)"));
                 None
@@ -131,11 +179,67 @@ impl Language {
         match self {
             Language::C => "c",
             Language::Cxx => "c++",
+            Language::CxxModule => "c++-module",
             Language::ObjectiveC => "objc",
             Language::ObjectiveCxx => "objc++",
             Language::Cuda => "cuda",
+            Language::Assembler => "assembler",
+            Language::AssemblerWithCpp => "assembler-with-cpp",
+            Language::PreprocessedC => "cpp-output",
+            Language::PreprocessedCxx => "c++-cpp-output",
+            Language::Fortran => "f95",
+            Language::FortranWithCpp => "f95-cpp-input",
         }
     }
+
+    /// Whether inputs in this language have to go through the preprocessor.
+    /// Others are hashed as they are.
+    pub fn needs_preprocessor(self) -> bool {
+        !matches!(
+            self,
+            Language::Assembler
+                | Language::PreprocessedC
+                | Language::PreprocessedCxx
+                | Language::Fortran
+        )
+    }
+
+    /// Whether this is Fortran, whose modules are found outside the
+    /// preprocessor.
+    pub fn is_fortran(self) -> bool {
+        matches!(self, Language::Fortran | Language::FortranWithCpp)
+    }
+}
+
+/// Parse a commandline compiling several source files at once as one
+/// commandline per source file, using `parse`.
+///
+/// `arguments` is the already parsed original commandline, in which every
+/// `Argument::Raw` is an input.
+pub fn parse_each_input<T, F>(
+    arguments: &[Argument<T>],
+    mut parse: F,
+) -> CompilerArguments<ParsedArguments>
+where
+    T: ArgumentValue,
+    F: FnMut(&[OsString]) -> CompilerArguments<ParsedArguments>,
+{
+    let is_input = |arg: &Argument<T>| matches!(*arg, Argument::Raw(_));
+    let mut parsed = vec![];
+    for (i, _) in arguments.iter().enumerate().filter(|&(_, a)| is_input(a)) {
+        let input_arguments: Vec<OsString> = arguments
+            .iter()
+            .enumerate()
+            .filter(|&(j, a)| j == i || !is_input(a))
+            .flat_map(|(_, a)| a.iter_os_strings())
+            .collect();
+        match parse(&input_arguments) {
+            CompilerArguments::Ok(args) => parsed.push(args),
+            CompilerArguments::Multiple(_) => unreachable!("commandline has a single input"),
+            other => return other,
+        }
+    }
+    CompilerArguments::Multiple(parsed)
 }
 
 /// A generic implementation of the `Compilation` trait for C/C++ compilers.
@@ -147,6 +251,8 @@ struct CCompilation<I: CCompilerImpl> {
     compiler: I,
     cwd: PathBuf,
     env_vars: Vec<(OsString, OsString)>,
+    /// Whether the output may be cached, regardless of the compiler.
+    cacheable: Cacheable,
 }
 
 /// Supported C compilers.
@@ -162,6 +268,10 @@ pub enum CCompilerKind {
     MSVC,
     /// NVIDIA cuda compiler
     NVCC,
+    /// Emscripten's emcc and em++
+    Emscripten,
+    /// gfortran and flang
+    Fortran,
 }
 
 /// An interface to a specific C compiler.
@@ -170,6 +280,25 @@ pub trait CCompilerImpl: Clone + fmt::Debug + Send + 'static {
     fn kind(&self) -> CCompilerKind;
     /// Return true iff this is g++ or clang++.
     fn plusplus(&self) -> bool;
+    /// Return `compiler` in the form stored in the compiler info cache.
+    fn detected(compiler: &CCompiler<Self>) -> DetectedCompiler
+    where
+        Self: Sized;
+    /// Return true iff the environment variable `var` affects the output of
+    /// this compiler, beyond those that affect every C compiler.
+    fn hashes_env_var(&self, _var: &OsStr) -> bool {
+        false
+    }
+    /// Files outside the commandline that affect the output of this compiler,
+    /// like its configuration, as found from its environment.
+    fn config_files(
+        &self,
+        _executable: &Path,
+        _cwd: &Path,
+        _env_vars: &[(OsString, OsString)],
+    ) -> Vec<PathBuf> {
+        vec![]
+    }
     /// Determine whether `arguments` are supported by this compiler.
     fn parse_arguments(
         &self,
@@ -207,27 +336,45 @@ impl<I> CCompiler<I>
 where
     I: CCompilerImpl,
 {
-    pub fn new(
+    pub fn new<T>(
         compiler: I,
         executable: PathBuf,
         version: Option<String>,
+        config: CCompilerConfig,
+        creator: &T,
+        env: &[(OsString, OsString)],
         pool: &ThreadPool,
-    ) -> SFuture<CCompiler<I>> {
+    ) -> SFuture<CCompiler<I>>
+    where
+        T: CommandCreatorSync,
+    {
+        let digests = compiler_check_digests(
+            creator,
+            &executable,
+            vec![executable.clone()],
+            &config.compiler_check,
+            env,
+            pool,
+        );
         Box::new(
-            Digest::file(executable.clone(), &pool).map(move |digest| CCompiler {
-                executable,
-                executable_digest: {
-                    if let Some(version) = version {
-                        let mut m = Digest::new();
-                        m.update(digest.as_bytes());
-                        m.update(version.as_bytes());
-                        m.finish()
-                    } else {
-                        digest
-                    }
-                },
-                compiler,
-            }),
+            digests
+                .map(move |digests| digests.concat())
+                .map(move |digest| CCompiler {
+                    executable,
+                    executable_digest: {
+                        if let Some(ref version) = version {
+                            let mut m = Digest::new();
+                            m.update(digest.as_bytes());
+                            m.update(version.as_bytes());
+                            m.finish()
+                        } else {
+                            digest
+                        }
+                    },
+                    version: version.map(|v| v.trim_matches('"').to_owned()),
+                    compiler,
+                    config,
+                }),
         )
     }
 }
@@ -236,6 +383,9 @@ impl<T: CommandCreatorSync, I: CCompilerImpl> Compiler<T> for CCompiler<I> {
     fn kind(&self) -> CompilerKind {
         CompilerKind::C(self.compiler.kind())
     }
+    fn executable(&self) -> &Path {
+        &self.executable
+    }
     #[cfg(feature = "dist-client")]
     fn get_toolchain_packager(&self) -> Box<dyn pkg::ToolchainPackager> {
         Box::new(CToolchainPackager {
@@ -248,13 +398,21 @@ impl<T: CommandCreatorSync, I: CCompilerImpl> Compiler<T> for CCompiler<I> {
         arguments: &[OsString],
         cwd: &Path,
     ) -> CompilerArguments<Box<dyn CompilerHasher<T> + 'static>> {
-        match self.compiler.parse_arguments(arguments, cwd) {
-            CompilerArguments::Ok(args) => CompilerArguments::Ok(Box::new(CCompilerHasher {
-                parsed_args: args,
+        let hasher = |parsed_args| -> Box<dyn CompilerHasher<T> + 'static> {
+            Box::new(CCompilerHasher {
+                parsed_args,
                 executable: self.executable.clone(),
                 executable_digest: self.executable_digest.clone(),
+                version: self.version.clone(),
                 compiler: self.compiler.clone(),
-            })),
+                config: self.config.clone(),
+            })
+        };
+        match self.compiler.parse_arguments(arguments, cwd) {
+            CompilerArguments::Ok(args) => CompilerArguments::Ok(hasher(args)),
+            CompilerArguments::Multiple(args) => {
+                CompilerArguments::Multiple(args.into_iter().map(hasher).collect())
+            }
             CompilerArguments::CannotCache(why, extra_info) => {
                 CompilerArguments::CannotCache(why, extra_info)
             }
@@ -262,6 +420,10 @@ impl<T: CommandCreatorSync, I: CCompilerImpl> Compiler<T> for CCompiler<I> {
         }
     }
 
+    fn detected(&self) -> Option<DetectedCompiler> {
+        Some(I::detected(self))
+    }
+
     fn box_clone(&self) -> Box<dyn Compiler<T>> {
         Box::new((*self).clone())
     }
@@ -286,24 +448,60 @@ where
             parsed_args,
             executable,
             executable_digest,
+            version: _,
             compiler,
+            config,
         } = me;
-        let result = compiler.preprocess(
-            creator,
-            &executable,
-            &parsed_args,
-            &cwd,
-            &env_vars,
-            may_dist,
-            rewrite_includes_only,
-        );
+        // Line markers are needed to tell what comes from system headers.
+        let line_markers = may_dist || config.is_sloppy(Sloppiness::SystemHeaders);
+        let result: SFuture<Vec<u8>> = if parsed_args.language.needs_preprocessor() {
+            Box::new(
+                compiler
+                    .preprocess(
+                        creator,
+                        &executable,
+                        &parsed_args,
+                        &cwd,
+                        &env_vars,
+                        line_markers,
+                        rewrite_includes_only,
+                    )
+                    .map(|output| output.stdout),
+            )
+        } else {
+            let input = cwd.join(&parsed_args.input);
+            Box::new(pool.spawn_fn(move || -> Result<_> {
+                fs::read(&input).with_context(|| format!("failed to read {}", input.display()))
+            }))
+        };
         let out_pretty = parsed_args.output_pretty().into_owned();
         let result = result.map_err(move |e| {
             debug!("[{}]: preprocessor failed: {:?}", out_pretty, e);
             e
         });
         let out_pretty = parsed_args.output_pretty().into_owned();
-        let extra_hashes = hash_all(&parsed_args.extra_hash_files, &pool.clone());
+        let mut extra_hash_files = parsed_args.extra_hash_files.clone();
+        extra_hash_files.extend(compiler.config_files(&executable, &cwd, &env_vars));
+        let extra_hashes = hash_all(&extra_hash_files, &pool.clone());
+        // The preprocessor expands time macros, so look for them in the source.
+        let time_macros: SFuture<TimeMacros> = if config.is_sloppy(Sloppiness::TimeMacros)
+            || !parsed_args.language.needs_preprocessor()
+        {
+            f_ok(TimeMacros::default())
+        } else {
+            let input = cwd.join(&parsed_args.input);
+            Box::new(pool.spawn_fn(move || -> Result<_> {
+                // Reading the input is best effort: the preprocessor reports
+                // any real problem with it.
+                Ok(match fs::read(&input) {
+                    Ok(source) => TimeMacros::find(&source),
+                    Err(e) => {
+                        debug!("Failed to read {}: {}", input.display(), e);
+                        TimeMacros::default()
+                    }
+                })
+            }))
+        };
         let outputs = parsed_args.outputs.clone();
         let args_cwd = cwd.clone();
 
@@ -346,44 +544,76 @@ where
                         Err(err) => Err(err),
                     }
                 })
-                .and_then(move |preprocessor_result| {
+                .and_then(move |preprocessor_output| {
                     trace!(
                         "[{}]: Preprocessor output is {} bytes",
                         parsed_args.output_pretty(),
-                        preprocessor_result.stdout.len()
+                        preprocessor_output.len()
                     );
 
-                    Box::new(extra_hashes.and_then(move |extra_hashes| {
-                        let key = {
-                            hash_key(
-                                &executable_digest,
-                                parsed_args.language,
-                                &parsed_args.common_args,
-                                &extra_hashes,
-                                &env_vars,
-                                &preprocessor_result.stdout,
-                                compiler.plusplus(),
-                            )
-                        };
-                        // A compiler binary may be a symlink to another and so has the same digest, but that means
-                        // the toolchain will not contain the correct path to invoke the compiler! Add the compiler
-                        // executable path to try and prevent this
-                        let weak_toolchain_key =
-                            format!("{}-{}", executable.to_string_lossy(), executable_digest);
-                        Ok(HashResult {
-                            key,
-                            compilation: Box::new(CCompilation {
-                                parsed_args,
-                                #[cfg(feature = "dist-client")]
-                                preprocessed_input: preprocessor_result.stdout,
-                                executable,
-                                compiler,
-                                cwd,
-                                env_vars,
-                            }),
-                            weak_toolchain_key,
-                        })
-                    }))
+                    Box::new(extra_hashes.join(time_macros).and_then(
+                        move |(mut extra_hashes, time_macros)| {
+                            let mut cacheable = Cacheable::Yes;
+                            if !config.is_sloppy(Sloppiness::TimeMacros) {
+                                // With `rewrite_includes_only`, headers aren't expanded yet.
+                                let time_macros =
+                                    time_macros.or(TimeMacros::find(&preprocessor_output));
+                                if let Some((hash, c)) = time_macros.hash(&env_vars) {
+                                    extra_hashes.push(hash);
+                                    cacheable = c;
+                                }
+                            }
+                            // Some outputs, like a module file written with a bare
+                            // `-fmodule-output`, only show up in the outputs.
+                            let mut output_kinds: Vec<_> = parsed_args.outputs.keys().collect();
+                            output_kinds.sort();
+                            extra_hashes.extend(output_kinds.into_iter().map(|k| k.to_string()));
+                            let mut hashed_output = Cow::Borrowed(&preprocessor_output[..]);
+                            if config.is_sloppy(Sloppiness::SystemHeaders) {
+                                hashed_output = Cow::Owned(strip_system_headers(&hashed_output));
+                            }
+                            if config.is_sloppy(Sloppiness::FileMacro) {
+                                hashed_output = Cow::Owned(strip_dir(&hashed_output, &cwd));
+                            }
+                            let hashed_env_vars: Vec<_> = env_vars
+                                .iter()
+                                .filter(|&(k, _)| {
+                                    is_env_var_hashed(&config, k, compiler.hashes_env_var(k))
+                                })
+                                .cloned()
+                                .collect();
+                            let key = {
+                                hash_key(
+                                    &executable_digest,
+                                    parsed_args.language,
+                                    &parsed_args.hashed_args(),
+                                    &extra_hashes,
+                                    &hashed_env_vars,
+                                    &hashed_output,
+                                    compiler.plusplus(),
+                                )
+                            };
+                            // A compiler binary may be a symlink to another and so has the same digest, but that means
+                            // the toolchain will not contain the correct path to invoke the compiler! Add the compiler
+                            // executable path to try and prevent this
+                            let weak_toolchain_key =
+                                format!("{}-{}", executable.to_string_lossy(), executable_digest);
+                            Ok(HashResult {
+                                key,
+                                compilation: Box::new(CCompilation {
+                                    parsed_args,
+                                    #[cfg(feature = "dist-client")]
+                                    preprocessed_input: preprocessor_output,
+                                    executable,
+                                    compiler,
+                                    cwd,
+                                    env_vars,
+                                    cacheable,
+                                }),
+                                weak_toolchain_key,
+                            })
+                        },
+                    ))
                 }),
         )
     }
@@ -392,6 +622,14 @@ where
         self.parsed_args.color_mode
     }
 
+    fn kind(&self) -> CompilerKind {
+        CompilerKind::C(self.compiler.kind())
+    }
+
+    fn version(&self) -> Option<String> {
+        self.version.clone()
+    }
+
     fn output_pretty(&self) -> Cow<'_, str> {
         self.parsed_args.output_pretty()
     }
@@ -413,16 +651,26 @@ impl<I: CCompilerImpl> Compilation for CCompilation<I> {
             ref compiler,
             ref cwd,
             ref env_vars,
+            cacheable,
             ..
         } = *self;
-        compiler.generate_compile_commands(
-            path_transformer,
-            executable,
-            parsed_args,
-            cwd,
-            env_vars,
-            rewrite_includes_only,
-        )
+        compiler
+            .generate_compile_commands(
+                path_transformer,
+                executable,
+                parsed_args,
+                cwd,
+                env_vars,
+                rewrite_includes_only,
+            )
+            .map(|(command, dist_command, compiler_cacheable)| {
+                let cacheable = if cacheable == Cacheable::Yes {
+                    compiler_cacheable
+                } else {
+                    cacheable
+                };
+                (command, dist_command, cacheable)
+            })
     }
 
     #[cfg(feature = "dist-client")]
@@ -456,7 +704,20 @@ impl<I: CCompilerImpl> Compilation for CCompilation<I> {
     }
 
     fn outputs<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a str, &'a Path)> + 'a> {
-        Box::new(self.parsed_args.outputs.iter().map(|(k, v)| (*k, &**v)))
+        let modules = self.parsed_args.module_outputs.iter().map(|path| {
+            let name = path.file_name().and_then(|f| f.to_str());
+            (
+                name.expect("Module paths are checked when parsing arguments"),
+                &**path,
+            )
+        });
+        Box::new(
+            self.parsed_args
+                .outputs
+                .iter()
+                .map(|(k, v)| (*k, &**v))
+                .chain(modules),
+        )
     }
 }
 
@@ -640,17 +901,163 @@ impl pkg::ToolchainPackager for CToolchainPackager {
 }
 
 /// The cache is versioned by the inputs to `hash_key`.
-pub const CACHE_VERSION: &[u8] = b"10";
+pub const CACHE_VERSION: &[u8] = b"13";
 
 lazy_static! {
-    /// Environment variables that are factored into the cache key.
+    /// Environment variables that are factored into the cache key by default.
     static ref CACHED_ENV_VARS: HashSet<&'static OsStr> = [
         "MACOSX_DEPLOYMENT_TARGET",
         "IPHONEOS_DEPLOYMENT_TARGET",
     ].iter().map(OsStr::new).collect();
+
+    /// Locale environment variables, which may change compiler diagnostics.
+    /// These are left out of the cache key with the `locale` sloppiness.
+    static ref LOCALE_ENV_VARS: HashSet<&'static OsStr> = [
+        "LANG",
+        "LC_ALL",
+        "LC_CTYPE",
+        "LC_MESSAGES",
+    ].iter().map(OsStr::new).collect();
+}
+
+/// Whether `var` goes into the cache key under `config`, where
+/// `compiler_hashes` says whether the compiler itself wants it hashed.
+fn is_env_var_hashed(config: &CCompilerConfig, var: &OsStr, compiler_hashes: bool) -> bool {
+    if LOCALE_ENV_VARS.contains(var) && config.is_sloppy(Sloppiness::Locale) {
+        return false;
+    }
+    let default = CACHED_ENV_VARS.contains(var) || LOCALE_ENV_VARS.contains(var) || compiler_hashes;
+    config.env.is_hashed(var, default)
+}
+
+/// Time macros used by a translation unit.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct TimeMacros {
+    date: bool,
+    time: bool,
+    timestamp: bool,
+}
+
+impl TimeMacros {
+    /// Look for uses of `__DATE__`, `__TIME__` and `__TIMESTAMP__` in `data`.
+    fn find(data: &[u8]) -> TimeMacros {
+        let contains = |needle: &[u8]| data.windows(needle.len()).any(|w| w == needle);
+        TimeMacros {
+            date: contains(b"__DATE__"),
+            time: contains(b"__TIME__"),
+            timestamp: contains(b"__TIMESTAMP__"),
+        }
+    }
+
+    fn or(self, other: TimeMacros) -> TimeMacros {
+        TimeMacros {
+            date: self.date || other.date,
+            time: self.time || other.time,
+            timestamp: self.timestamp || other.timestamp,
+        }
+    }
+
+    /// Return the extra data to hash when time macros are used, along with
+    /// whether the result may be cached.
+    ///
+    /// `SOURCE_DATE_EPOCH` pins `__DATE__` and `__TIME__`, but `__TIMESTAMP__`
+    /// is the modification time of the source file and can't be reproduced.
+    fn hash(self, env_vars: &[(OsString, OsString)]) -> Option<(String, Cacheable)> {
+        if !self.date && !self.time && !self.timestamp {
+            return None;
+        }
+        let epoch = env_vars
+            .iter()
+            .find(|&(k, _)| k == "SOURCE_DATE_EPOCH")
+            .map(|(_, v)| v.to_string_lossy());
+        if let (Some(epoch), false) = (epoch, self.timestamp) {
+            return Some((format!("SOURCE_DATE_EPOCH={}", epoch), Cacheable::Yes));
+        }
+        let now = chrono::Local::now();
+        if self.time || self.timestamp {
+            debug!("Time macros used, not caching");
+            Some((format!("__TIME__={}", now.to_rfc3339()), Cacheable::No))
+        } else {
+            Some((
+                format!("__DATE__={}", now.format("%b %e %Y")),
+                Cacheable::Yes,
+            ))
+        }
+    }
+}
+
+/// Drop the preprocessed contents of system headers from `output`, keeping
+/// the line markers, so that upgrading system headers doesn't change the hash.
+fn strip_system_headers(output: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(output.len());
+    let mut in_system_header = false;
+    let mut rest = output;
+    while !rest.is_empty() {
+        let end = rest
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(rest.len(), |i| i + 1);
+        let (line, next) = rest.split_at(end);
+        rest = next;
+        if let Some(flags) = line_marker_flags(line) {
+            in_system_header = flags
+                .split(u8::is_ascii_whitespace)
+                .any(|flag| flag == b"3");
+            result.extend_from_slice(line);
+        } else if !in_system_header {
+            result.extend_from_slice(line);
+        }
+    }
+    result
+}
+
+/// If `line` is a line marker (`# 12 "file.h" 2 3`), return the flags after
+/// the file name.
+fn line_marker_flags(line: &[u8]) -> Option<&[u8]> {
+    fn trim_start(s: &[u8]) -> &[u8] {
+        let start = s
+            .iter()
+            .position(|b| !b.is_ascii_whitespace())
+            .unwrap_or(s.len());
+        &s[start..]
+    }
+    let rest = trim_start(line);
+    if !rest.starts_with(b"#") {
+        return None;
+    }
+    let mut rest = trim_start(&rest[1..]);
+    if rest.starts_with(b"line") {
+        rest = trim_start(&rest[4..]);
+    }
+    match rest.first() {
+        Some(b) if b.is_ascii_digit() => {}
+        _ => return None,
+    }
+    let end = rest.iter().rposition(|&b| b == b'"')?;
+    Some(trim_start(&rest[end + 1..]))
+}
+
+/// Remove every occurrence of `dir` followed by a path separator from
+/// `output`, so that `__FILE__` expands to the same bytes in any directory.
+fn strip_dir(output: &[u8], dir: &Path) -> Vec<u8> {
+    let mut needle = dir.to_string_lossy().into_owned().into_bytes();
+    if needle.is_empty() {
+        return output.to_vec();
+    }
+    needle.push(std::path::MAIN_SEPARATOR as u8);
+    let mut result = Vec::with_capacity(output.len());
+    let mut rest = output;
+    while let Some(pos) = rest.windows(needle.len()).position(|w| w == &needle[..]) {
+        result.extend_from_slice(&rest[..pos]);
+        rest = &rest[pos + needle.len()..];
+    }
+    result.extend_from_slice(rest);
+    result
 }
 
 /// Compute the hash key of `compiler` compiling `preprocessor_output` with `args`.
+///
+/// `env_vars` should only contain the variables that affect the compilation.
 pub fn hash_key(
     compiler_digest: &str,
     language: Language,
@@ -675,12 +1082,13 @@ pub fn hash_key(
         m.update(hash.as_bytes());
     }
 
-    for &(ref var, ref val) in env_vars.iter() {
-        if CACHED_ENV_VARS.contains(var.as_os_str()) {
-            var.hash(&mut HashToDigest { digest: &mut m });
-            m.update(&b"="[..]);
-            val.hash(&mut HashToDigest { digest: &mut m });
-        }
+    // The same variables come in a different order from different shells.
+    let mut env_vars: Vec<_> = env_vars.iter().collect();
+    env_vars.sort();
+    for &(ref var, ref val) in env_vars {
+        var.hash(&mut HashToDigest { digest: &mut m });
+        m.update(&b"="[..]);
+        val.hash(&mut HashToDigest { digest: &mut m });
     }
     m.update(preprocessor_output);
     m.finish()
@@ -777,6 +1185,51 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_locale_env_vars() {
+        let mut config = CCompilerConfig::default();
+        assert!(is_env_var_hashed(&config, OsStr::new("LC_ALL"), false));
+        assert!(is_env_var_hashed(&config, OsStr::new("LANG"), false));
+        assert!(!is_env_var_hashed(&config, OsStr::new("HOME"), false));
+        config.sloppiness = vec![Sloppiness::Locale];
+        assert!(!is_env_var_hashed(&config, OsStr::new("LC_ALL"), false));
+        assert!(!is_env_var_hashed(&config, OsStr::new("LANG"), false));
+        assert!(is_env_var_hashed(
+            &config,
+            OsStr::new("MACOSX_DEPLOYMENT_TARGET"),
+            false
+        ));
+        // The sloppiness wins over including the variables explicitly.
+        config.env.include = vec!["LC_*".to_owned()];
+        assert!(!is_env_var_hashed(
+            &config,
+            OsStr::new("LC_MESSAGES"),
+            false
+        ));
+    }
+
+    #[test]
+    fn test_hash_key_env_var_order() {
+        let args = ovec!["a", "b", "c"];
+        const PREPROCESSED: &[u8] = b"hello world";
+        let a = (OsString::from("LC_ALL"), OsString::from("C"));
+        let b = (OsString::from("SOURCE_DATE_EPOCH"), OsString::from("0"));
+        let vars = vec![a.clone(), b.clone()];
+        let reversed = vec![b, a];
+        assert_eq!(
+            hash_key("abcd", Language::C, &args, &[], &vars, PREPROCESSED, false),
+            hash_key(
+                "abcd",
+                Language::C,
+                &args,
+                &[],
+                &reversed,
+                PREPROCESSED,
+                false
+            )
+        );
+    }
+
     #[test]
     fn test_extra_hash_data() {
         let args = ovec!["a", "b", "c"];
@@ -797,4 +1250,90 @@ mod test {
             hash_key(digest, Language::C, &args, &[], &[], &PREPROCESSED, false)
         );
     }
+
+    #[test]
+    fn test_hash_key_locale_env_var_differs() {
+        let args = ovec!["a", "b", "c"];
+        const PREPROCESSED: &[u8] = b"hello world";
+        let vars = vec![(OsString::from("LC_ALL"), OsString::from("C"))];
+        assert_neq!(
+            hash_key("abcd", Language::C, &args, &[], &[], PREPROCESSED, false),
+            hash_key("abcd", Language::C, &args, &[], &vars, PREPROCESSED, false)
+        );
+    }
+
+    #[test]
+    fn test_time_macros_find() {
+        assert_eq!(TimeMacros::find(b"int x = 1;"), TimeMacros::default());
+        assert_eq!(
+            TimeMacros::find(b"const char *d = __DATE__;"),
+            TimeMacros {
+                date: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            TimeMacros::find(b"__TIME__ __TIMESTAMP__"),
+            TimeMacros {
+                time: true,
+                timestamp: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_time_macros_hash() {
+        let epoch = vec![(OsString::from("SOURCE_DATE_EPOCH"), OsString::from("0"))];
+        let time = TimeMacros {
+            time: true,
+            ..Default::default()
+        };
+        assert_eq!(TimeMacros::default().hash(&epoch), None);
+        assert_eq!(
+            time.hash(&epoch),
+            Some(("SOURCE_DATE_EPOCH=0".to_owned(), Cacheable::Yes))
+        );
+        assert_eq!(time.hash(&[]).map(|(_, c)| c), Some(Cacheable::No));
+        let date = TimeMacros {
+            date: true,
+            ..Default::default()
+        };
+        assert_eq!(date.hash(&[]).map(|(_, c)| c), Some(Cacheable::Yes));
+        let timestamp = TimeMacros {
+            timestamp: true,
+            ..Default::default()
+        };
+        assert_eq!(timestamp.hash(&epoch).map(|(_, c)| c), Some(Cacheable::No));
+    }
+
+    #[test]
+    fn test_strip_system_headers() {
+        let output = b"# 1 \"foo.c\"\n\
+                       # 1 \"/usr/include/stdio.h\" 1 3 4\n\
+                       int printf(const char *, ...);\n\
+                       # 2 \"foo.c\" 2\n\
+                       int main() {}\n";
+        assert_eq!(
+            &strip_system_headers(output)[..],
+            &b"# 1 \"foo.c\"\n\
+               # 1 \"/usr/include/stdio.h\" 1 3 4\n\
+               # 2 \"foo.c\" 2\n\
+               int main() {}\n"[..]
+        );
+    }
+
+    #[test]
+    fn test_strip_dir() {
+        let dir = Path::new("/home/user/src");
+        let output = format!(
+            "const char *f = \"{}{}foo.c\";",
+            dir.display(),
+            std::path::MAIN_SEPARATOR
+        );
+        assert_eq!(
+            &strip_dir(output.as_bytes(), dir)[..],
+            &b"const char *f = \"foo.c\";"[..]
+        );
+    }
 }
@@ -15,9 +15,11 @@
 #![allow(unused_imports, dead_code, unused_variables)]
 
 use crate::compiler::args::*;
-use crate::compiler::c::{CCompilerImpl, CCompilerKind, Language, ParsedArguments};
+use crate::compiler::c::{CCompiler, CCompilerImpl, CCompilerKind, Language, ParsedArguments};
 use crate::compiler::gcc::ArgData::*;
-use crate::compiler::{gcc, write_temp_file, Cacheable, CompileCommand, CompilerArguments};
+use crate::compiler::{
+    gcc, write_temp_file, Cacheable, CompileCommand, CompilerArguments, DetectedCompiler,
+};
 use crate::dist;
 use crate::mock_command::{CommandCreator, CommandCreatorSync, RunCommand};
 use crate::util::{run_input_output, OsStrExt};
@@ -31,7 +33,7 @@ use std::process;
 use crate::errors::*;
 
 /// A struct on which to implement `CCompilerImpl`.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Clang {
     /// true iff this is clang++.
     pub clangplusplus: bool,
@@ -44,6 +46,9 @@ impl CCompilerImpl for Clang {
     fn plusplus(&self) -> bool {
         self.clangplusplus
     }
+    fn detected(compiler: &CCompiler<Self>) -> DetectedCompiler {
+        DetectedCompiler::Clang(compiler.clone())
+    }
     fn parse_arguments(
         &self,
         arguments: &[OsString],
@@ -112,11 +117,17 @@ counted_array!(pub static ARGS: [ArgInfo<gcc::ArgData>; _] = [
     take_arg!("-debug-info-kind", OsString, Concatenated('='), PassThrough),
     take_arg!("-dependency-file", PathBuf, Separated, DepArgumentPath),
     flag!("-fcolor-diagnostics", DiagnosticsColorFlag),
-    flag!("-fcxx-modules", TooHardFlag),
+    flag!("-fcxx-modules", Modules),
     take_arg!("-fdebug-compilation-dir", OsString, Separated, PassThrough),
-    flag!("-fmodules", TooHardFlag),
+    take_arg!("-fmodule-file", OsString, Concatenated('='), ModuleFile),
+    take_arg!("-fmodule-map-file", PathBuf, Concatenated('='), ExtraHashFile),
+    take_arg!("-fmodule-output", PathBuf, Concatenated('='), ModuleOutput),
+    flag!("-fmodules", Modules),
     flag!("-fno-color-diagnostics", NoDiagnosticsColorFlag),
+    flag!("-fno-implicit-modules", NoImplicitModules),
     take_arg!("-fplugin", PathBuf, CanBeConcatenated('='), ExtraHashFile),
+    // Would need hashing every BMI in the directory.
+    take_arg!("-fprebuilt-module-path", OsString, Concatenated('='), TooHard),
     flag!("-fprofile-instr-generate", ProfileGenerate),
     // Can be either -fprofile-instr-use or -fprofile-instr-use=path
     take_arg!("-fprofile-instr-use", OsString, Concatenated, TooHard),
@@ -205,6 +216,116 @@ mod test {
             CompilerArguments::CannotCache("-fmodules", None),
             parse_arguments_(stringvec!["-c", "foo.c", "-fmodules", "-o", "foo.o"])
         );
+        assert_eq!(
+            CompilerArguments::CannotCache("-fprebuilt-module-path", None),
+            parse_arguments_(stringvec![
+                "-c",
+                "foo.cpp",
+                "-fprebuilt-module-path=mods",
+                "-o",
+                "foo.o"
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_explicit_clangmodules() {
+        let a = parses!(
+            "-c",
+            "foo.c",
+            "-fmodules",
+            "-fno-implicit-modules",
+            "-fmodule-map-file=module.modulemap",
+            "-fmodule-file=bar.pcm",
+            "-o",
+            "foo.o"
+        );
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(
+            ovec![
+                "-fmodules",
+                "-fno-implicit-modules",
+                "-fmodule-map-file=module.modulemap",
+                "-fmodule-file=bar.pcm"
+            ],
+            a.common_args
+        );
+        assert_eq!(
+            ovec![cwd.join("module.modulemap"), cwd.join("bar.pcm")],
+            a.extra_hash_files
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_cxx20_modules() {
+        let a = parses!(
+            "-std=c++20",
+            "-c",
+            "foo.cppm",
+            "-fmodule-file=bar=prebuilt/bar.pcm",
+            "-fmodule-output",
+            "-o",
+            "out/foo.o"
+        );
+        assert_eq!(Language::CxxModule, a.language);
+        assert_map_contains!(
+            a.outputs,
+            ("obj", PathBuf::from("out/foo.o")),
+            ("pcm", PathBuf::from("out/foo.pcm"))
+        );
+        assert_eq!(
+            ovec!["-std=c++20", "-fmodule-file=bar=prebuilt/bar.pcm"],
+            a.common_args
+        );
+        assert_eq!(
+            ovec![std::env::current_dir().unwrap().join("prebuilt/bar.pcm")],
+            a.extra_hash_files
+        );
+
+        let a = parses!(
+            "-c",
+            "-x",
+            "c++-module",
+            "foo.cpp",
+            "-fmodule-output=bmi/foo.pcm",
+            "-o",
+            "foo.o"
+        );
+        assert_eq!(Language::CxxModule, a.language);
+        assert_map_contains!(
+            a.outputs,
+            ("obj", PathBuf::from("foo.o")),
+            ("pcm", PathBuf::from("bmi/foo.pcm"))
+        );
+        assert!(a.common_args.is_empty());
+    }
+
+    #[test]
+    fn test_compile_module_output() {
+        let a = parses!("-c", "foo.cppm", "-fmodule-output=foo.pcm", "-o", "foo.o");
+        let mut path_transformer = dist::PathTransformer::default();
+        let (command, _, _) = gcc::generate_compile_commands(
+            &mut path_transformer,
+            "clang++".as_ref(),
+            &a,
+            ".".as_ref(),
+            &[],
+            CCompilerKind::Clang,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            ovec![
+                "-x",
+                "c++-module",
+                "-c",
+                "foo.cppm",
+                "-o",
+                "foo.o",
+                "-fmodule-output=foo.pcm"
+            ],
+            command.arguments
+        );
     }
 
     #[test]
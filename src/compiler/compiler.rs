@@ -12,34 +12,47 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::cache::{Cache, CacheWrite, DecompressionFailure, Storage};
+use crate::cache::{
+    Cache, CacheEntryMetadata, CacheRead, CacheWrite, DecompressionFailure, Storage,
+};
 use crate::compiler::c::{CCompiler, CCompilerKind};
 use crate::compiler::clang::Clang;
 use crate::compiler::diab::Diab;
+use crate::compiler::emscripten::Emscripten;
+use crate::compiler::fortran::{self, Fortran};
 use crate::compiler::gcc::GCC;
+use crate::compiler::generic::Generic;
+use crate::compiler::info_cache::{DetectedCompiler, DetectedProxy};
 use crate::compiler::msvc;
 use crate::compiler::msvc::MSVC;
 use crate::compiler::nvcc::NVCC;
 use crate::compiler::rust::{Rust, RustupProxy};
+use crate::compiler::rustdoc::{Rustdoc, RustdocProxy};
+use crate::config::{CCompilerConfig, CompilerCheck, CompilerConfigs};
 use crate::dist;
 #[cfg(feature = "dist-client")]
 use crate::dist::pkg;
 use crate::lru_disk_cache;
 use crate::mock_command::{exit_status, CommandChild, CommandCreatorSync, RunCommand};
-use crate::util::{fmt_duration_as_secs, ref_env, run_input_output, SpawnExt};
+use crate::util::{fmt_duration_as_secs, hash_all, ref_env, run_input_output, Digest, SpawnExt};
 use filetime::FileTime;
+use futures::unsync::oneshot;
 use futures::Future;
 use futures_03::executor::ThreadPool;
 use std::borrow::Cow;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::env::consts::EXE_SUFFIX;
 use std::ffi::OsString;
 use std::fmt;
 #[cfg(feature = "dist-client")]
 use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 use std::process::{self, Stdio};
+use std::rc::Rc;
 use std::str;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
@@ -86,6 +99,10 @@ pub enum CompilerKind {
     C(CCompilerKind),
     /// A Rust compiler.
     Rust,
+    /// The Rust documentation generator.
+    Rustdoc,
+    /// A tool described in the configuration, by name.
+    Generic(String),
 }
 
 impl CompilerKind {
@@ -94,6 +111,25 @@ impl CompilerKind {
             CompilerKind::C(CCompilerKind::NVCC) => "CUDA",
             CompilerKind::C(_) => "C/C++",
             CompilerKind::Rust => "Rust",
+            CompilerKind::Rustdoc => "Rustdoc",
+            CompilerKind::Generic(name) => name,
+        }
+        .to_string()
+    }
+
+    /// A short name for the kind of compiler, as recorded in cache entries.
+    pub fn name(&self) -> String {
+        match self {
+            CompilerKind::C(CCompilerKind::GCC) => "gcc",
+            CompilerKind::C(CCompilerKind::Clang) => "clang",
+            CompilerKind::C(CCompilerKind::Diab) => "diab",
+            CompilerKind::C(CCompilerKind::MSVC) => "msvc",
+            CompilerKind::C(CCompilerKind::NVCC) => "nvcc",
+            CompilerKind::C(CCompilerKind::Emscripten) => "emscripten",
+            CompilerKind::C(CCompilerKind::Fortran) => "fortran",
+            CompilerKind::Rust => "rustc",
+            CompilerKind::Rustdoc => "rustdoc",
+            CompilerKind::Generic(name) => name,
         }
         .to_string()
     }
@@ -118,6 +154,8 @@ where
 {
     /// Return the kind of compiler.
     fn kind(&self) -> CompilerKind;
+    /// Return the path to the compiler's executable.
+    fn executable(&self) -> &Path;
     /// Retrieve a packager
     #[cfg(feature = "dist-client")]
     fn get_toolchain_packager(&self) -> Box<dyn pkg::ToolchainPackager>;
@@ -127,6 +165,11 @@ where
         arguments: &[OsString],
         cwd: &Path,
     ) -> CompilerArguments<Box<dyn CompilerHasher<T> + 'static>>;
+    /// Return this compiler in the form stored in the compiler info cache,
+    /// if it can be stored.
+    fn detected(&self) -> Option<DetectedCompiler> {
+        None
+    }
     fn box_clone(&self) -> Box<dyn Compiler<T>>;
 }
 
@@ -152,6 +195,12 @@ where
         env_vars: &[(OsString, OsString)],
     ) -> SFuture<(PathBuf, FileTime)>;
 
+    /// Return this proxy in the form stored in the compiler info cache, if
+    /// it can be stored.
+    fn detected(&self) -> Option<DetectedProxy> {
+        None
+    }
+
     /// Create a clone of `Self` and puts it in a `Box`
     fn box_clone(&self) -> Box<dyn CompilerProxy<T>>;
 }
@@ -178,6 +227,14 @@ where
     /// Return the state of any `--color` option passed to the compiler.
     fn color_mode(&self) -> ColorMode;
 
+    /// Return the kind of compiler.
+    fn kind(&self) -> CompilerKind;
+
+    /// Return the version of the compiler, if known.
+    fn version(&self) -> Option<String> {
+        None
+    }
+
     /// Look up a cached compile result in `storage`. If not found, run the
     /// compile and store the result.
     #[allow(clippy::too_many_arguments)]
@@ -186,6 +243,7 @@ where
         dist_client: Result<Option<Arc<dyn dist::Client>>>,
         creator: T,
         storage: Arc<dyn Storage>,
+        in_flight: InFlight,
         arguments: Vec<OsString>,
         cwd: PathBuf,
         env_vars: Vec<(OsString, OsString)>,
@@ -194,6 +252,8 @@ where
     ) -> SFuture<(CompileResult, process::Output)> {
         let out_pretty = self.output_pretty().into_owned();
         debug!("[{}]: get_cached_or_compile: {:?}", out_pretty, arguments);
+        let compiler_name = self.kind().name();
+        let compiler_version = self.version();
         let start = Instant::now();
         let may_dist = matches!(dist_client, Ok(Some(_)));
         let rewrite_includes_only = match dist_client {
@@ -259,13 +319,14 @@ where
                         );
                         let stdout = entry.get_stdout();
                         let stderr = entry.get_stderr();
+                        let saved = entry.get_metadata().map(|m| m.compile_duration);
                         let write = entry.extract_objects(outputs.clone(), &pool);
                         let output = process::Output {
                             status: exit_status(0),
                             stdout,
                             stderr,
                         };
-                        let hit = CompileResult::CacheHit(duration);
+                        let hit = CompileResult::CacheHit(duration, saved);
                         Box::new(write.then(move |result| match result {
                             Ok(()) => f_ok(CacheLookupResult::Success(hit, output)),
                             Err(e) => {
@@ -315,7 +376,59 @@ where
                     }
                 });
 
-                Box::new(miss_type.and_then(move |result| {
+                // On a miss, wait for an identical compilation already in
+                // progress, or become the one that others wait for.
+                let outputs2 = outputs.clone();
+                let pool2 = pool.clone();
+                let out_pretty3 = out_pretty2.clone();
+                let key2 = key.clone();
+                let lookup = miss_type.and_then(move |result| -> SFuture<_> {
+                    let miss_type = match result {
+                        CacheLookupResult::Miss(miss_type) => miss_type,
+                        result => return f_ok((result, None)),
+                    };
+                    let rx = match in_flight.start(&key2) {
+                        InFlightStatus::Started(guard) => {
+                            return f_ok((CacheLookupResult::Miss(miss_type), Some(guard)))
+                        }
+                        // A forced recache doesn't take the result of another compilation.
+                        InFlightStatus::Waiting(_) if miss_type == MissType::ForcedRecache => {
+                            return f_ok((CacheLookupResult::Miss(miss_type), None))
+                        }
+                        InFlightStatus::Waiting(rx) => rx,
+                    };
+                    debug!("[{}]: Waiting for an identical compilation", out_pretty3);
+                    let start = Instant::now();
+                    Box::new(rx.then(move |res| -> SFuture<_> {
+                        let bytes = match res {
+                            Ok(bytes) => bytes,
+                            Err(_) => {
+                                debug!(
+                                    "[{}]: Identical compilation wasn't cached, compiling",
+                                    out_pretty3
+                                );
+                                return f_ok((CacheLookupResult::Miss(miss_type), None));
+                            }
+                        };
+                        let mut entry = match CacheRead::from(io::Cursor::new((*bytes).clone())) {
+                            Ok(entry) => entry,
+                            Err(e) => return f_err(e),
+                        };
+                        let output = process::Output {
+                            status: exit_status(0),
+                            stdout: entry.get_stdout(),
+                            stderr: entry.get_stderr(),
+                        };
+                        let saved = entry.get_metadata().map(|m| m.compile_duration);
+                        let write = entry.extract_objects(outputs2, &pool2);
+                        Box::new(write.map(move |()| {
+                            let coalesced = CompileResult::Coalesced(start.elapsed(), saved);
+                            (CacheLookupResult::Success(coalesced, output), None)
+                        }))
+                    }))
+                });
+
+                Box::new(lookup.and_then(move |(result, in_flight_guard)| {
                     match result {
                         CacheLookupResult::Success(compile_result, output) => {
                             f_ok((compile_result, output))
@@ -356,6 +469,15 @@ where
                                         out_pretty2,
                                         fmt_duration_as_secs(&duration)
                                     );
+                                    let mut output_names: Vec<_> =
+                                        outputs.keys().cloned().collect();
+                                    output_names.sort();
+                                    let metadata = CacheEntryMetadata::new(
+                                        duration,
+                                        compiler_name,
+                                        compiler_version,
+                                        output_names,
+                                    );
                                     let write = CacheWrite::from_objects(outputs, &pool);
                                     let write = write.fcontext("failed to zip up compiler outputs");
                                     let o = out_pretty2.clone();
@@ -364,6 +486,11 @@ where
                                             .and_then(move |mut entry| {
                                                 entry.put_stdout(&compiler_result.stdout)?;
                                                 entry.put_stderr(&compiler_result.stderr)?;
+                                                entry.put_metadata(&metadata)?;
+                                                let entry = match in_flight_guard {
+                                                    Some(guard) => guard.finish(entry)?,
+                                                    None => entry,
+                                                };
 
                                                 // Try to finish storing the newly-written cache
                                                 // entry. We'll get the result back elsewhere.
@@ -691,6 +818,9 @@ pub struct HashResult {
 pub enum CompilerArguments<T> {
     /// Commandline can be handled.
     Ok(T),
+    /// Commandline compiles several inputs at once, each of which can be
+    /// handled on its own.
+    Multiple(Vec<T>),
     /// Cannot cache this compilation.
     CannotCache(&'static str, Option<String>),
     /// This commandline is not a compile.
@@ -749,8 +879,9 @@ pub struct CacheWriteInfo {
 pub enum CompileResult {
     /// An error made the compilation not possible.
     Error,
-    /// Result was found in cache.
-    CacheHit(Duration),
+    /// Result was found in cache, taking the first duration to read. The
+    /// second is how long the cached compilation took, if it was recorded.
+    CacheHit(Duration, Option<Duration>),
     /// Result was not found in cache.
     ///
     /// The `CacheWriteFuture` will resolve when the result is finished
@@ -760,6 +891,10 @@ pub enum CompileResult {
     NotCacheable,
     /// Not in cache, but compilation failed.
     CompileFailed,
+    /// Result was taken from an identical compilation running at the same
+    /// time, after waiting for the first duration. The second is how long
+    /// that compilation took.
+    Coalesced(Duration, Option<Duration>),
 }
 
 /// The state of `--color` options passed to a compiler.
@@ -781,12 +916,17 @@ impl fmt::Debug for CompileResult {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match *self {
             CompileResult::Error => write!(f, "CompileResult::Error"),
-            CompileResult::CacheHit(ref d) => write!(f, "CompileResult::CacheHit({:?})", d),
+            CompileResult::CacheHit(ref d, ref saved) => {
+                write!(f, "CompileResult::CacheHit({:?}, {:?})", d, saved)
+            }
             CompileResult::CacheMiss(ref m, ref dt, ref d, _) => {
                 write!(f, "CompileResult::CacheMiss({:?}, {:?}, {:?}, _)", d, m, dt)
             }
             CompileResult::NotCacheable => write!(f, "CompileResult::NotCacheable"),
             CompileResult::CompileFailed => write!(f, "CompileResult::CompileFailed"),
+            CompileResult::Coalesced(ref d, ref saved) => {
+                write!(f, "CompileResult::Coalesced({:?}, {:?})", d, saved)
+            }
         }
     }
 }
@@ -796,18 +936,92 @@ impl PartialEq<CompileResult> for CompileResult {
     fn eq(&self, other: &CompileResult) -> bool {
         match (self, other) {
             (&CompileResult::Error, &CompileResult::Error) => true,
-            (&CompileResult::CacheHit(_), &CompileResult::CacheHit(_)) => true,
+            (&CompileResult::CacheHit(..), &CompileResult::CacheHit(..)) => true,
             (
                 &CompileResult::CacheMiss(ref m, ref dt, _, _),
                 &CompileResult::CacheMiss(ref n, ref dt2, _, _),
             ) => m == n && dt == dt2,
             (&CompileResult::NotCacheable, &CompileResult::NotCacheable) => true,
             (&CompileResult::CompileFailed, &CompileResult::CompileFailed) => true,
+            (&CompileResult::Coalesced(..), &CompileResult::Coalesced(..)) => true,
             _ => false,
         }
     }
 }
 
+/// Compilations in progress, by hash key. Requests that miss the cache while
+/// an identical compilation is running wait for its cache entry instead of
+/// running the compiler again.
+#[derive(Clone, Default)]
+pub struct InFlight {
+    waiting: Rc<RefCell<HashMap<String, Vec<EntrySender>>>>,
+}
+
+/// Sends a finished cache entry to a waiting request.
+type EntrySender = oneshot::Sender<Rc<Vec<u8>>>;
+
+enum InFlightStatus {
+    /// No identical compilation is running; the guard marks this one.
+    Started(InFlightGuard),
+    /// Receives the cache entry of the identical compilation, or is canceled
+    /// if that compilation produces none.
+    Waiting(oneshot::Receiver<Rc<Vec<u8>>>),
+}
+
+impl InFlight {
+    pub fn new() -> InFlight {
+        Default::default()
+    }
+
+    fn start(&self, key: &str) -> InFlightStatus {
+        let mut waiting = self.waiting.borrow_mut();
+        if let Some(senders) = waiting.get_mut(key) {
+            let (tx, rx) = oneshot::channel();
+            senders.push(tx);
+            return InFlightStatus::Waiting(rx);
+        }
+        waiting.insert(key.to_owned(), vec![]);
+        InFlightStatus::Started(InFlightGuard {
+            in_flight: self.clone(),
+            key: key.to_owned(),
+        })
+    }
+}
+
+/// Marks a compilation as in progress until it is finished or dropped.
+struct InFlightGuard {
+    in_flight: InFlight,
+    key: String,
+}
+
+impl InFlightGuard {
+    /// Hand `entry` to the requests waiting for this compilation, returning
+    /// it to be stored.
+    fn finish(self, entry: CacheWrite) -> Result<CacheWrite> {
+        let senders = self
+            .in_flight
+            .waiting
+            .borrow_mut()
+            .remove(&self.key)
+            .unwrap_or_default();
+        if senders.is_empty() {
+            return Ok(entry);
+        }
+        let bytes = Rc::new(entry.finish()?);
+        for tx in senders {
+            drop(tx.send(bytes.clone()));
+        }
+        CacheRead::from(io::Cursor::new((*bytes).clone()))?.into_cache_write()
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        // Waiting requests see their receiver canceled, and compile themselves.
+        self.in_flight.waiting.borrow_mut().remove(&self.key);
+    }
+}
+
 /// Can this result be stored in cache?
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Cacheable {
@@ -816,7 +1030,7 @@ pub enum Cacheable {
 }
 
 /// Control of caching behavior.
-#[derive(Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum CacheControl {
     /// Default caching behavior.
     Default,
@@ -857,6 +1071,7 @@ fn detect_compiler<T>(
     env: &[(OsString, OsString)],
     pool: &ThreadPool,
     dist_archive: Option<PathBuf>,
+    config: &CompilerConfigs,
 ) -> SFuture<(Box<dyn Compiler<T>>, Option<Box<dyn CompilerProxy<T>>>)>
 where
     T: CommandCreatorSync,
@@ -870,9 +1085,30 @@ where
     };
     let filename = filename.to_string_lossy().to_lowercase();
 
-    let rustc_vv = if filename == "rustc" || filename == "clippy-driver" {
+    // Tools described in the configuration are taken at their word.
+    if let Some(generic) = config
+        .generic
+        .iter()
+        .find(|g| g.name.to_lowercase() == filename)
+    {
+        debug!("Found generic compiler {}", generic.name);
+        return Box::new(
+            Generic::new(executable.to_owned(), generic.clone(), &creator, env, pool)
+                .map(|generic| (Box::new(generic) as Box<dyn Compiler<T>>, None)),
+        );
+    }
+
+    // rustdoc is checked, and resolved through rustup, via the rustc next to it.
+    let is_rustdoc = filename == "rustdoc";
+    let executable = if is_rustdoc {
+        executable.with_file_name(format!("rustc{}", EXE_SUFFIX))
+    } else {
+        executable.to_owned()
+    };
+
+    let rustc_vv = if filename == "rustc" || filename == "clippy-driver" || is_rustdoc {
         // Sanity check that it's really rustc.
-        let executable = executable.to_path_buf();
+        let executable = executable.clone();
         let mut child = creator.clone().new_command_sync(executable);
         child.env_clear().envs(ref_env(env)).args(&["-vV"]);
 
@@ -890,13 +1126,15 @@ where
 
     let creator1 = creator.clone();
     let creator2 = creator.clone();
-    let executable = executable.to_owned();
+    let creator3 = creator.clone();
     let executable2 = executable.clone();
     let env1 = env.to_owned();
     let env2 = env.to_owned();
     let env3 = env.to_owned();
     let pool = pool.clone();
     let cwd = cwd.to_owned();
+    let c_config = config.c.clone();
+    let rust_config = config.rust.clone();
     Box::new(
         rustc_vv
             .and_then(move |rustc_vv| match rustc_vv {
@@ -942,8 +1180,12 @@ where
                                 = res
                                     .map(|(proxy,resolved_compiler_executable)| {
                                         (
-                                            proxy.map(Box::new).map(|x : Box<RustupProxy>| {
-                                                x as Box<dyn CompilerProxy<T>>
+                                            proxy.map(|proxy| -> Box<dyn CompilerProxy<T>> {
+                                                if is_rustdoc {
+                                                    Box::new(RustdocProxy::new(proxy))
+                                                } else {
+                                                    Box::new(proxy)
+                                                }
                                             }),
                                             resolved_compiler_executable
                                         )
@@ -952,6 +1194,7 @@ where
                                         trace!("Compiling rust without proxy");
                                         (None, executable2)
                                     });
+                            let rustdoc = Rustdoc::sibling_of(&resolved_rustc);
 
                             Rust::new(
                                 creator2,
@@ -959,21 +1202,26 @@ where
                                 &env3,
                                 &rustc_verbose_version,
                                 dist_archive,
-                                pool,
+                                rust_config,
+                                pool.clone(),
                             )
-                            .map(|c| {
-                                (
-                                    Box::new(c) as Box<dyn Compiler<T> >,
-                                    proxy as Option<Box<dyn CompilerProxy<T>>>
-                                )
+                            .and_then(move |c| -> SFuture<Box<dyn Compiler<T>>> {
+                                if is_rustdoc {
+                                    Box::new(Rustdoc::new(rustdoc, c, &creator3, &env3, &pool).map(|c| {
+                                        Box::new(c) as Box<dyn Compiler<T>>
+                                    }))
+                                } else {
+                                    f_ok(Box::new(c))
+                                }
                             })
+                            .map(|c| (c, proxy as Option<Box<dyn CompilerProxy<T>>>))
                     }
                 )
             )
             }
             Some(Err(e)) => f_err(e),
             None => {
-                let cc = detect_c_compiler(creator, executable, env1.to_vec(), pool);
+                let cc = detect_c_compiler(creator, executable, env1.to_vec(), pool, c_config);
                 Box::new(cc.map(|c : Box<dyn Compiler<T>>| { (c, None ) }))
             },
         })
@@ -985,6 +1233,7 @@ fn detect_c_compiler<T>(
     executable: PathBuf,
     env: Vec<(OsString, OsString)>,
     pool: ThreadPool,
+    config: CCompilerConfig,
 ) -> SFuture<Box<dyn Compiler<T>>>
 where
     T: CommandCreatorSync,
@@ -996,12 +1245,17 @@ where
     // Both clang and clang-cl define _MSC_VER on Windows, so we first
     // check for MSVC, then check whether _MT is defined, which is the
     // difference between clang and clang-cl.
-    let test = b"#if defined(__NVCC__)
+    // Emscripten's emcc drives clang, so it needs to come before clang.
+    let c_test = b"#if defined(__NVCC__)
 nvcc
 #elif defined(_MSC_VER) && !defined(__clang__)
 msvc
 #elif defined(_MSC_VER) && defined(_MT)
 msvc-clang
+#elif defined(__EMSCRIPTEN__) && defined(__cplusplus)
+em++
+#elif defined(__EMSCRIPTEN__)
+emcc
 #elif defined(__clang__) && defined(__cplusplus)
 clang++
 #elif defined(__clang__)
@@ -1016,9 +1270,23 @@ diab
 unknown
 #endif
 __VERSION__
-"
-    .to_vec();
-    let write = write_temp_file(&pool, "testfile.c".as_ref(), test);
+";
+    // Fortran compilers don't necessarily preprocess C, so they get a source
+    // of their own.
+    let fortran_test = b"#if defined(__flang__)
+flang
+#elif defined(__GFORTRAN__)
+gfortran
+#else
+unknown
+#endif
+__VERSION__
+";
+    let write = if fortran::is_fortran_compiler(&executable) {
+        write_temp_file(&pool, "testfile.F90".as_ref(), fortran_test.to_vec())
+    } else {
+        write_temp_file(&pool, "testfile.c".as_ref(), c_test.to_vec())
+    };
 
     let mut cmd = creator.clone().new_command_sync(&executable);
     cmd.stdout(Stdio::piped())
@@ -1068,6 +1336,43 @@ __VERSION__
                             },
                             executable,
                             version,
+                            config,
+                            &creator,
+                            &env,
+                            &pool,
+                        )
+                        .map(|c| Box::new(c) as Box<dyn Compiler<T>>),
+                    );
+                }
+                "emcc" | "em++" => {
+                    debug!("Found {}", kind);
+                    return Box::new(
+                        CCompiler::new(
+                            Emscripten {
+                                emplusplus: kind == "em++",
+                            },
+                            executable,
+                            version,
+                            config,
+                            &creator,
+                            &env,
+                            &pool,
+                        )
+                        .map(|c| Box::new(c) as Box<dyn Compiler<T>>),
+                    );
+                }
+                "gfortran" | "flang" => {
+                    debug!("Found {}", kind);
+                    return Box::new(
+                        CCompiler::new(
+                            Fortran {
+                                flang: kind == "flang",
+                            },
+                            executable,
+                            version,
+                            config,
+                            &creator,
+                            &env,
                             &pool,
                         )
                         .map(|c| Box::new(c) as Box<dyn Compiler<T>>),
@@ -1076,7 +1381,7 @@ __VERSION__
                 "diab" => {
                     debug!("Found diab");
                     return Box::new(
-                        CCompiler::new(Diab, executable, version, &pool)
+                        CCompiler::new(Diab, executable, version, config, &creator, &env, &pool)
                             .map(|c| Box::new(c) as Box<dyn Compiler<T>>),
                     );
                 }
@@ -1089,6 +1394,9 @@ __VERSION__
                             },
                             executable,
                             version,
+                            config,
+                            &creator,
+                            &env,
                             &pool,
                         )
                         .map(|c| Box::new(c) as Box<dyn Compiler<T>>),
@@ -1101,7 +1409,7 @@ __VERSION__
                         &creator,
                         executable.as_ref(),
                         is_clang,
-                        env,
+                        env.clone(),
                         &pool,
                     );
                     return Box::new(prefix.and_then(move |prefix| {
@@ -1113,6 +1421,9 @@ __VERSION__
                             },
                             executable,
                             version,
+                            config,
+                            &creator,
+                            &env,
                             &pool,
                         )
                         .map(|c| Box::new(c) as Box<dyn Compiler<T>>)
@@ -1121,7 +1432,7 @@ __VERSION__
                 "nvcc" => {
                     debug!("Found NVCC");
                     return Box::new(
-                        CCompiler::new(NVCC, executable, version, &pool)
+                        CCompiler::new(NVCC, executable, version, config, &creator, &env, &pool)
                             .map(|c| Box::new(c) as Box<dyn Compiler<T>>),
                     );
                 }
@@ -1146,12 +1457,75 @@ pub fn get_compiler_info<T>(
     env: &[(OsString, OsString)],
     pool: &ThreadPool,
     dist_archive: Option<PathBuf>,
+    config: &CompilerConfigs,
 ) -> SFuture<(Box<dyn Compiler<T>>, Option<Box<dyn CompilerProxy<T>>>)>
 where
     T: CommandCreatorSync,
 {
     let pool = pool.clone();
-    detect_compiler(creator, executable, cwd, env, &pool, dist_archive)
+    detect_compiler(creator, executable, cwd, env, &pool, dist_archive, config)
+}
+
+/// Compute the digests identifying the compiler at `executable`, made of
+/// `files`, according to `check`.
+///
+/// Checking the contents or modification times gives one digest per file,
+/// while the other checks give a single digest for the whole compiler.
+pub fn compiler_check_digests<T>(
+    creator: &T,
+    executable: &Path,
+    files: Vec<PathBuf>,
+    check: &CompilerCheck,
+    env: &[(OsString, OsString)],
+    pool: &ThreadPool,
+) -> SFuture<Vec<String>>
+where
+    T: CommandCreatorSync,
+{
+    match check {
+        CompilerCheck::Content => hash_all(&files, pool),
+        CompilerCheck::Mtime => Box::new(pool.spawn_fn(move || -> Result<_> {
+            files
+                .iter()
+                .map(|file| {
+                    let meta = std::fs::metadata(file)
+                        .with_context(|| format!("failed to stat {}", file.display()))?;
+                    let mtime = FileTime::from_last_modification_time(&meta);
+                    let mut m = Digest::new();
+                    m.update(&mtime.unix_seconds().to_le_bytes());
+                    m.update(&mtime.nanoseconds().to_le_bytes());
+                    m.update(&meta.len().to_le_bytes());
+                    Ok(m.finish())
+                })
+                .collect()
+        })),
+        CompilerCheck::Command(command) => {
+            let executable = executable.to_string_lossy();
+            let mut words = command
+                .split_whitespace()
+                .map(|word| word.replace("%compiler%", &executable));
+            let program = match words.next() {
+                Some(program) => program,
+                None => return f_err(anyhow!("empty compiler_check command")),
+            };
+            let mut cmd = creator.clone().new_command_sync(program);
+            cmd.args(&words.collect::<Vec<_>>())
+                .env_clear()
+                .envs(ref_env(env));
+            trace!("compiler_check: {:?}", cmd);
+            Box::new(run_input_output(cmd, None).map(|output| {
+                let mut m = Digest::new();
+                m.update(&output.stdout);
+                m.update(&output.stderr);
+                vec![m.finish()]
+            }))
+        }
+        CompilerCheck::String(s) => {
+            let mut m = Digest::new();
+            m.update(s.as_bytes());
+            f_ok(vec![m.finish()])
+        }
+    }
 }
 
 #[cfg(test)]
@@ -1177,23 +1551,90 @@ mod test {
         let creator = new_creator();
         let pool = ThreadPool::sized(1);
         next_command(&creator, Ok(MockChild::new(exit_status(0), "\n\ngcc", "")));
-        let c = detect_compiler(creator, &f.bins[0], f.tempdir.path(), &[], &pool, None)
-            .wait()
-            .unwrap()
-            .0;
+        let c = detect_compiler(
+            creator,
+            &f.bins[0],
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+            &Default::default(),
+        )
+        .wait()
+        .unwrap()
+        .0;
         assert_eq!(CompilerKind::C(CCompilerKind::GCC), c.kind());
     }
 
+    #[test]
+    fn test_detect_compiler_kind_emscripten() {
+        let f = TestFixture::new();
+        let creator = new_creator();
+        let pool = ThreadPool::sized(1);
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "em++\n", "")));
+        let c = detect_compiler(
+            creator,
+            &f.bins[0],
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+            &Default::default(),
+        )
+        .wait()
+        .unwrap()
+        .0;
+        assert_eq!(CompilerKind::C(CCompilerKind::Emscripten), c.kind());
+    }
+
+    #[test]
+    fn test_detect_compiler_kind_fortran() {
+        let f = TestFixture::new();
+        let gfortran = f.mk_bin("gfortran").unwrap();
+        let creator = new_creator();
+        let pool = ThreadPool::sized(1);
+        next_command(
+            &creator,
+            Ok(MockChild::new(
+                exit_status(0),
+                "gfortran
+",
+                "",
+            )),
+        );
+        let c = detect_compiler(
+            creator,
+            &gfortran,
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+            &Default::default(),
+        )
+        .wait()
+        .unwrap()
+        .0;
+        assert_eq!(CompilerKind::C(CCompilerKind::Fortran), c.kind());
+    }
+
     #[test]
     fn test_detect_compiler_kind_clang() {
         let f = TestFixture::new();
         let creator = new_creator();
         let pool = ThreadPool::sized(1);
         next_command(&creator, Ok(MockChild::new(exit_status(0), "clang\n", "")));
-        let c = detect_compiler(creator, &f.bins[0], f.tempdir.path(), &[], &pool, None)
-            .wait()
-            .unwrap()
-            .0;
+        let c = detect_compiler(
+            creator,
+            &f.bins[0],
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+            &Default::default(),
+        )
+        .wait()
+        .unwrap()
+        .0;
         assert_eq!(CompilerKind::C(CCompilerKind::Clang), c.kind());
     }
 
@@ -1217,10 +1658,18 @@ mod test {
             &creator,
             Ok(MockChild::new(exit_status(0), &stdout, &String::new())),
         );
-        let c = detect_compiler(creator, &f.bins[0], f.tempdir.path(), &[], &pool, None)
-            .wait()
-            .unwrap()
-            .0;
+        let c = detect_compiler(
+            creator,
+            &f.bins[0],
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+            &Default::default(),
+        )
+        .wait()
+        .unwrap()
+        .0;
         assert_eq!(CompilerKind::C(CCompilerKind::MSVC), c.kind());
     }
 
@@ -1230,10 +1679,18 @@ mod test {
         let creator = new_creator();
         let pool = ThreadPool::sized(1);
         next_command(&creator, Ok(MockChild::new(exit_status(0), "nvcc\n", "")));
-        let c = detect_compiler(creator, &f.bins[0], f.tempdir.path(), &[], &pool, None)
-            .wait()
-            .unwrap()
-            .0;
+        let c = detect_compiler(
+            creator,
+            &f.bins[0],
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+            &Default::default(),
+        )
+        .wait()
+        .unwrap()
+        .0;
         assert_eq!(CompilerKind::C(CCompilerKind::NVCC), c.kind());
     }
 
@@ -1267,26 +1724,135 @@ LLVM version: 6.0",
         next_command(&creator, Ok(MockChild::new(exit_status(0), &sysroot, "")));
         next_command(&creator, Ok(MockChild::new(exit_status(0), &sysroot, "")));
         next_command(&creator, Ok(MockChild::new(exit_status(0), &sysroot, "")));
-        let c = detect_compiler(creator, &rustc, f.tempdir.path(), &[], &pool, None)
-            .wait()
-            .unwrap()
-            .0;
+        // rustc --print=target-list
+        next_command(
+            &creator,
+            Ok(MockChild::new(
+                exit_status(0),
+                "x86_64-unknown-linux-gnu\n",
+                "",
+            )),
+        );
+        let c = detect_compiler(
+            creator,
+            &rustc,
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+            &Default::default(),
+        )
+        .wait()
+        .unwrap()
+        .0;
         assert_eq!(CompilerKind::Rust, c.kind());
     }
 
+    #[test]
+    fn test_detect_compiler_kind_rustdoc() {
+        let f = TestFixture::new();
+        // Windows uses bin, everything else uses lib. Just create both.
+        fs::create_dir(f.tempdir.path().join("lib")).unwrap();
+        fs::create_dir(f.tempdir.path().join("bin")).unwrap();
+        f.mk_bin("rustc").unwrap();
+        let rustdoc = f.mk_bin("rustdoc").unwrap();
+        let creator = new_creator();
+        let pool = ThreadPool::sized(1);
+        // rustc -vV, run on the rustc next to rustdoc
+        next_command(
+            &creator,
+            Ok(MockChild::new(
+                exit_status(0),
+                "\
+rustc 1.27.0 (3eda71b00 2018-06-19)
+binary: rustc
+commit-hash: 3eda71b00ad48d7bf4eef4c443e7f611fd061418
+commit-date: 2018-06-19
+host: x86_64-unknown-linux-gnu
+release: 1.27.0
+LLVM version: 6.0",
+                "",
+            )),
+        );
+        // rustc --print=sysroot
+        let sysroot = f.tempdir.path().to_str().unwrap();
+        next_command(&creator, Ok(MockChild::new(exit_status(0), sysroot, "")));
+        next_command(&creator, Ok(MockChild::new(exit_status(0), sysroot, "")));
+        next_command(&creator, Ok(MockChild::new(exit_status(0), sysroot, "")));
+        // rustc --print=target-list
+        next_command(
+            &creator,
+            Ok(MockChild::new(
+                exit_status(0),
+                "x86_64-unknown-linux-gnu\n",
+                "",
+            )),
+        );
+        let c = detect_compiler(
+            creator,
+            &rustdoc,
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+            &Default::default(),
+        )
+        .wait()
+        .unwrap()
+        .0;
+        assert_eq!(CompilerKind::Rustdoc, c.kind());
+    }
+
     #[test]
     fn test_detect_compiler_kind_diab() {
         let f = TestFixture::new();
         let creator = new_creator();
         let pool = ThreadPool::sized(1);
         next_command(&creator, Ok(MockChild::new(exit_status(0), "\ndiab\n", "")));
-        let c = detect_compiler(creator, &f.bins[0], f.tempdir.path(), &[], &pool, None)
-            .wait()
-            .unwrap()
-            .0;
+        let c = detect_compiler(
+            creator,
+            &f.bins[0],
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+            &Default::default(),
+        )
+        .wait()
+        .unwrap()
+        .0;
         assert_eq!(CompilerKind::C(CCompilerKind::Diab), c.kind());
     }
 
+    #[test]
+    fn test_detect_compiler_kind_generic() {
+        let f = TestFixture::new();
+        let creator = new_creator();
+        let pool = ThreadPool::sized(1);
+        let protoc = f.mk_bin("protoc").unwrap();
+        let config = CompilerConfigs {
+            generic: vec![crate::config::GenericCompilerConfig {
+                name: "protoc".to_owned(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        // The configuration is trusted without running the tool.
+        let c = detect_compiler(
+            creator,
+            &protoc,
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+            &config,
+        )
+        .wait()
+        .unwrap()
+        .0;
+        assert_eq!(CompilerKind::Generic("protoc".to_owned()), c.kind());
+    }
+
     #[test]
     fn test_detect_compiler_kind_unknown() {
         let f = TestFixture::new();
@@ -1302,7 +1868,8 @@ LLVM version: 6.0",
             f.tempdir.path(),
             &[],
             &pool,
-            None
+            None,
+            &Default::default()
         )
         .wait()
         .is_err());
@@ -1320,7 +1887,8 @@ LLVM version: 6.0",
             f.tempdir.path(),
             &[],
             &pool,
-            None
+            None,
+            &Default::default()
         )
         .wait()
         .is_err());
@@ -1346,6 +1914,7 @@ LLVM version: 6.0",
                     &[],
                     &pool,
                     None,
+                    &Default::default(),
                 )
                 .wait()
                 .unwrap()
@@ -1375,14 +1944,70 @@ LLVM version: 6.0",
         let f = TestFixture::new();
         // Pretend to be GCC.
         next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
-        let c = get_compiler_info(creator, &f.bins[0], f.tempdir.path(), &[], &pool, None)
-            .wait()
-            .unwrap()
-            .0;
+        let c = get_compiler_info(
+            creator,
+            &f.bins[0],
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+            &Default::default(),
+        )
+        .wait()
+        .unwrap()
+        .0;
         // digest of an empty file.
         assert_eq!(CompilerKind::C(CCompilerKind::GCC), c.kind());
     }
 
+    #[test]
+    fn test_compiler_check_digests() {
+        let creator = new_creator();
+        let pool = ThreadPool::sized(1);
+        let f = TestFixture::new();
+        let gcc = f.bins[0].clone();
+        let check = |check: &CompilerCheck| {
+            compiler_check_digests(&creator, &gcc, vec![gcc.clone()], check, &[], &pool)
+                .wait()
+                .unwrap()
+        };
+
+        let content = check(&CompilerCheck::Content);
+        let mtime = check(&CompilerCheck::Mtime);
+        assert_eq!(1, content.len());
+        assert_ne!(content, mtime);
+        // Touching the compiler only changes the mtime digest.
+        let later = FileTime::from_unix_time(1_000_000_000, 0);
+        filetime::set_file_mtime(&gcc, later).unwrap();
+        assert_eq!(content, check(&CompilerCheck::Content));
+        assert_ne!(mtime, check(&CompilerCheck::Mtime));
+
+        let version = CompilerCheck::Command("%compiler% --version".to_owned());
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "gcc 10.2.0", "")),
+        );
+        let first = check(&version);
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "gcc 10.2.1", "")),
+        );
+        assert_ne!(first, check(&version));
+        next_command(&creator, Ok(MockChild::new(exit_status(1), "", "nope")));
+        assert!(
+            compiler_check_digests(&creator, &gcc, vec![], &version, &[], &pool)
+                .wait()
+                .is_err()
+        );
+
+        let fixed = CompilerCheck::String("gcc-10".to_owned());
+        assert_eq!(check(&fixed), check(&fixed));
+        assert_ne!(
+            check(&fixed),
+            check(&CompilerCheck::String("gcc-11".to_owned()))
+        );
+    }
+
     #[test]
     fn test_compiler_get_cached_or_compile() {
         drop(env_logger::try_init());
@@ -1393,7 +2018,10 @@ LLVM version: 6.0",
         let storage = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
         let storage: Arc<dyn Storage> = Arc::new(storage);
         // Pretend to be GCC.
-        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        next_command(
+            &creator,
+            Ok(MockChild::new(exit_status(0), "gcc\n\"9.3.0\"", "")),
+        );
         let c = get_compiler_info(
             creator.clone(),
             &f.bins[0],
@@ -1401,6 +2029,7 @@ LLVM version: 6.0",
             &[],
             &pool,
             None,
+            &Default::default(),
         )
         .wait()
         .unwrap()
@@ -1438,6 +2067,7 @@ LLVM version: 6.0",
                     Ok(None),
                     creator.clone(),
                     storage.clone(),
+                    InFlight::new(),
                     arguments.clone(),
                     cwd.to_path_buf(),
                     vec![],
@@ -1458,6 +2088,17 @@ LLVM version: 6.0",
         assert_eq!(exit_status(0), res.status);
         assert_eq!(COMPILER_STDOUT, res.stdout.as_slice());
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
+        // The entry records how it was produced.
+        let entry = walkdir::WalkDir::new(f.tempdir.path().join("cache"))
+            .into_iter()
+            .map(|e| e.unwrap())
+            .find(|e| e.file_type().is_file())
+            .unwrap();
+        let mut entry = CacheRead::from(File::open(entry.path()).unwrap()).unwrap();
+        let metadata = entry.get_metadata().unwrap();
+        assert_eq!("gcc", metadata.compiler);
+        assert_eq!(Some("9.3.0".to_owned()), metadata.compiler_version);
+        assert_eq!(vec!["obj".to_owned()], metadata.outputs);
         // Now compile again, which should be a cache hit.
         fs::remove_file(&obj).unwrap();
         // The preprocessor invocation.
@@ -1472,6 +2113,7 @@ LLVM version: 6.0",
                     Ok(None),
                     creator,
                     storage,
+                    InFlight::new(),
                     arguments,
                     cwd.to_path_buf(),
                     vec![],
@@ -1482,12 +2124,132 @@ LLVM version: 6.0",
             .unwrap();
         // Ensure that the object file was created.
         assert!(fs::metadata(&obj).map(|m| m.len() > 0).unwrap());
-        assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
+        match cached {
+            // The entry records how long the original compilation took.
+            CompileResult::CacheHit(_, saved) => assert!(saved.is_some()),
+            _ => panic!("Unexpected compile result: {:?}", cached),
+        }
         assert_eq!(exit_status(0), res.status);
         assert_eq!(COMPILER_STDOUT, res.stdout.as_slice());
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
     }
 
+    #[test]
+    fn test_in_flight() {
+        let in_flight = InFlight::new();
+        let started = |key| match in_flight.start(key) {
+            InFlightStatus::Started(guard) => guard,
+            InFlightStatus::Waiting(_) => panic!("{} should start", key),
+        };
+        let waiting = |key| match in_flight.start(key) {
+            InFlightStatus::Started(_) => panic!("{} should wait", key),
+            InFlightStatus::Waiting(rx) => rx,
+        };
+        let guard = started("key");
+        let rx = waiting("key");
+        let mut entry = CacheWrite::new();
+        entry.put_stdout(b"compiler stdout").unwrap();
+        let entry = guard.finish(entry).unwrap();
+        // The waiting request gets the entry, which can still be stored.
+        let bytes = rx.wait().unwrap();
+        let mut read = CacheRead::from(io::Cursor::new((*bytes).clone())).unwrap();
+        assert_eq!(b"compiler stdout", &read.get_stdout()[..]);
+        let mut read = CacheRead::from(io::Cursor::new(entry.finish().unwrap())).unwrap();
+        assert_eq!(b"compiler stdout", &read.get_stdout()[..]);
+        // A compilation dropped without an entry cancels the waiting ones.
+        let guard = started("key");
+        let rx = waiting("key");
+        started("other");
+        drop(guard);
+        assert!(rx.wait().is_err());
+        started("key");
+    }
+
+    #[test]
+    fn test_compiler_get_cached_or_compile_assembler() {
+        drop(env_logger::try_init());
+        let creator = new_creator();
+        let f = TestFixture::new();
+        let pool = ThreadPool::sized(1);
+        let mut runtime = Runtime::new().unwrap();
+        let storage = DiskCache::new(&f.tempdir.path().join("cache"), u64::MAX, &pool);
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+        // Pretend to be GCC.
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = get_compiler_info(
+            creator.clone(),
+            &f.bins[0],
+            f.tempdir.path(),
+            &[],
+            &pool,
+            None,
+            &Default::default(),
+        )
+        .wait()
+        .unwrap()
+        .0;
+        f.touch("foo.s").unwrap();
+        // Assembly isn't preprocessed, so the only invocation is the compiler.
+        let obj = f.tempdir.path().join("foo.o");
+        let o = obj.clone();
+        next_command_calls(&creator, move |_| {
+            // Pretend to compile something.
+            let mut f = File::create(&o)?;
+            f.write_all(b"file contents")?;
+            Ok(MockChild::new(exit_status(0), "", ""))
+        });
+        let cwd = f.tempdir.path();
+        let arguments = ovec!["-c", "foo.s", "-o", "foo.o"];
+        let hasher = match c.parse_arguments(&arguments, ".".as_ref()) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Bad result from parse_arguments: {:?}", o),
+        };
+        let hasher2 = hasher.clone();
+        let (cached, res) = runtime
+            .block_on(future::lazy(|| {
+                hasher.get_cached_or_compile(
+                    Ok(None),
+                    creator.clone(),
+                    storage.clone(),
+                    InFlight::new(),
+                    arguments.clone(),
+                    cwd.to_path_buf(),
+                    vec![],
+                    CacheControl::Default,
+                    pool.clone(),
+                )
+            }))
+            .unwrap();
+        match cached {
+            CompileResult::CacheMiss(MissType::Normal, DistType::NoDist, _, f) => {
+                // wait on cache write future so we don't race with it!
+                f.wait().unwrap();
+            }
+            _ => panic!("Unexpected compile result: {:?}", cached),
+        }
+        assert_eq!(exit_status(0), res.status);
+        // Now compile again, which should be a cache hit without running anything.
+        fs::remove_file(&obj).unwrap();
+        let (cached, res) = runtime
+            .block_on(future::lazy(|| {
+                hasher2.get_cached_or_compile(
+                    Ok(None),
+                    creator,
+                    storage,
+                    InFlight::new(),
+                    arguments,
+                    cwd.to_path_buf(),
+                    vec![],
+                    CacheControl::Default,
+                    pool,
+                )
+            }))
+            .unwrap();
+        assert!(fs::metadata(&obj).map(|m| m.len() > 0).unwrap());
+        assert_eq!(CompileResult::CacheHit(Duration::new(0, 0), None), cached);
+        assert_eq!(exit_status(0), res.status);
+    }
+
     #[test]
     #[cfg(feature = "dist-client")]
     fn test_compiler_get_cached_or_compile_dist() {
@@ -1507,6 +2269,7 @@ LLVM version: 6.0",
             &[],
             &pool,
             None,
+            &Default::default(),
         )
         .wait()
         .unwrap()
@@ -1539,6 +2302,7 @@ LLVM version: 6.0",
                     Ok(dist_client.clone()),
                     creator.clone(),
                     storage.clone(),
+                    InFlight::new(),
                     arguments.clone(),
                     cwd.to_path_buf(),
                     vec![],
@@ -1573,6 +2337,7 @@ LLVM version: 6.0",
                     Ok(dist_client.clone()),
                     creator,
                     storage,
+                    InFlight::new(),
                     arguments,
                     cwd.to_path_buf(),
                     vec![],
@@ -1583,7 +2348,7 @@ LLVM version: 6.0",
             .unwrap();
         // Ensure that the object file was created.
         assert!(fs::metadata(&obj).map(|m| m.len() > 0).unwrap());
-        assert_eq!(CompileResult::CacheHit(Duration::new(0, 0)), cached);
+        assert_eq!(CompileResult::CacheHit(Duration::new(0, 0), None), cached);
         assert_eq!(exit_status(0), res.status);
         assert_eq!(COMPILER_STDOUT, res.stdout.as_slice());
         assert_eq!(COMPILER_STDERR, res.stderr.as_slice());
@@ -1609,6 +2374,7 @@ LLVM version: 6.0",
             &[],
             &pool,
             None,
+            &Default::default(),
         )
         .wait()
         .unwrap()
@@ -1647,6 +2413,7 @@ LLVM version: 6.0",
                     Ok(None),
                     creator,
                     storage,
+                    InFlight::new(),
                     arguments.clone(),
                     cwd.to_path_buf(),
                     vec![],
@@ -1688,6 +2455,7 @@ LLVM version: 6.0",
             &[],
             &pool,
             None,
+            &Default::default(),
         )
         .wait()
         .unwrap()
@@ -1729,6 +2497,7 @@ LLVM version: 6.0",
                     Ok(None),
                     creator.clone(),
                     storage.clone(),
+                    InFlight::new(),
                     arguments.clone(),
                     cwd.to_path_buf(),
                     vec![],
@@ -1756,6 +2525,7 @@ LLVM version: 6.0",
                 Ok(None),
                 creator,
                 storage,
+                InFlight::new(),
                 arguments,
                 cwd.to_path_buf(),
                 vec![],
@@ -1803,6 +2573,7 @@ LLVM version: 6.0",
             &[],
             &pool,
             None,
+            &Default::default(),
         )
         .wait()
         .unwrap()
@@ -1831,6 +2602,7 @@ LLVM version: 6.0",
                     Ok(None),
                     creator,
                     storage,
+                    InFlight::new(),
                     arguments,
                     cwd.to_path_buf(),
                     vec![],
@@ -1872,6 +2644,7 @@ LLVM version: 6.0",
             &[],
             &pool,
             None,
+            &Default::default(),
         )
         .wait()
         .unwrap()
@@ -1917,6 +2690,7 @@ LLVM version: 6.0",
                     Ok(Some(dist_client.clone())),
                     creator.clone(),
                     storage.clone(),
+                    InFlight::new(),
                     arguments.clone(),
                     cwd.to_path_buf(),
                     vec![],
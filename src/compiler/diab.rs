@@ -17,8 +17,10 @@ use crate::compiler::args::{
     ArgDisposition, ArgInfo, ArgToStringResult, ArgsIter, Argument, FromArg, IntoArg,
     NormalizedDisposition, PathTransformerFn, SearchableArgInfo,
 };
-use crate::compiler::c::{CCompilerImpl, CCompilerKind, Language, ParsedArguments};
-use crate::compiler::{Cacheable, ColorMode, CompileCommand, CompilerArguments};
+use crate::compiler::c::{
+    parse_each_input, CCompiler, CCompilerImpl, CCompilerKind, Language, ParsedArguments,
+};
+use crate::compiler::{Cacheable, ColorMode, CompileCommand, CompilerArguments, DetectedCompiler};
 use crate::dist;
 use crate::errors::*;
 use crate::mock_command::{CommandCreatorSync, RunCommand};
@@ -31,7 +33,7 @@ use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::process;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Diab;
 
 impl CCompilerImpl for Diab {
@@ -41,6 +43,9 @@ impl CCompilerImpl for Diab {
     fn plusplus(&self) -> bool {
         false
     }
+    fn detected(compiler: &CCompiler<Self>) -> DetectedCompiler {
+        DetectedCompiler::Diab(compiler.clone())
+    }
     fn parse_arguments(
         &self,
         arguments: &[OsString],
@@ -152,8 +157,9 @@ pub fn parse_arguments<S>(
     arg_info: S,
 ) -> CompilerArguments<ParsedArguments>
 where
-    S: SearchableArgInfo<ArgData>,
+    S: SearchableArgInfo<ArgData> + Copy,
 {
+    let mut all_args = vec![];
     let mut common_args = vec![];
     let mut compilation = false;
     let mut compilation_flag = OsString::new();
@@ -169,6 +175,7 @@ where
 
     for arg in ArgsIter::new(it, arg_info) {
         let arg = try_or_cannot_cache!(arg, "argument parse");
+        all_args.push(arg.clone());
         // Check if the value part of this argument begins with '@'. If so, we either
         // failed to expand it, or it was a concatenated argument - either way, bail.
         // We refuse to cache concatenated arguments (like "-include@foo") because they're a
@@ -246,9 +253,15 @@ where
     if !compilation {
         return CompilerArguments::NotCompilation;
     }
-    // Can't cache compilations with multiple inputs.
+    // Compilations with multiple inputs are split into one compilation per
+    // input, unless the inputs would share an output.
     if multiple_input {
-        cannot_cache!("multiple input files");
+        if output_arg.is_some() || !dependency_args.is_empty() {
+            cannot_cache!("multiple input files");
+        }
+        return parse_each_input(&all_args, |arguments| {
+            parse_arguments(arguments, cwd, arg_info)
+        });
     }
     let input = match input_arg {
         Some(i) => i,
@@ -256,9 +269,14 @@ where
         None => cannot_cache!("no input file"),
     };
     let language = match Language::from_file_name(Path::new(&input)) {
-        Some(l) => l,
-        None => cannot_cache!("unknown source language"),
+        Some(l) if !l.is_fortran() => l,
+        _ => cannot_cache!("unknown source language"),
     };
+    // Dependency files are written by the preprocessor, which isn't run for
+    // inputs that don't need it.
+    if !language.needs_preprocessor() && !dependency_args.is_empty() {
+        cannot_cache!("dependency file without preprocessing");
+    }
 
     let output = output_arg
         .map(PathBuf::from)
@@ -277,6 +295,7 @@ where
         preprocessor_args,
         common_args,
         extra_hash_files: vec![],
+        module_outputs: vec![],
         msvc_show_includes: false,
         profile_generate: false,
         // FIXME: Implement me.
@@ -678,6 +697,7 @@ mod test {
             preprocessor_args: vec![],
             common_args: vec![],
             extra_hash_files: vec![],
+            module_outputs: vec![],
             msvc_show_includes: false,
             profile_generate: false,
             color_mode: ColorMode::Auto,
@@ -0,0 +1,274 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::compiler::args::*;
+use crate::compiler::c::{CCompiler, CCompilerImpl, CCompilerKind, ParsedArguments};
+use crate::compiler::gcc::ArgData::*;
+use crate::compiler::{clang, gcc, Cacheable, CompileCommand, CompilerArguments, DetectedCompiler};
+use crate::dist;
+use crate::mock_command::CommandCreatorSync;
+use crate::util::OsStrExt;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::errors::*;
+
+/// A struct on which to implement `CCompilerImpl`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Emscripten {
+    /// true iff this is em++.
+    pub emplusplus: bool,
+}
+
+impl CCompilerImpl for Emscripten {
+    fn kind(&self) -> CCompilerKind {
+        CCompilerKind::Emscripten
+    }
+    fn plusplus(&self) -> bool {
+        self.emplusplus
+    }
+    fn detected(compiler: &CCompiler<Self>) -> DetectedCompiler {
+        DetectedCompiler::Emscripten(compiler.clone())
+    }
+    fn hashes_env_var(&self, var: &OsStr) -> bool {
+        // Settings like `EMCC_CFLAGS` and `EM_CACHE`.
+        var.starts_with("EMCC_") || var.starts_with("EM_")
+    }
+    fn config_files(
+        &self,
+        executable: &Path,
+        cwd: &Path,
+        env_vars: &[(OsString, OsString)],
+    ) -> Vec<PathBuf> {
+        find_config(executable, cwd, env_vars).into_iter().collect()
+    }
+    fn parse_arguments(
+        &self,
+        arguments: &[OsString],
+        cwd: &Path,
+    ) -> CompilerArguments<ParsedArguments> {
+        let mut parsed = gcc::parse_arguments(
+            arguments,
+            cwd,
+            (&gcc::ARGS[..], &clang::ARGS[..], &ARGS[..]),
+            self.emplusplus,
+            self.kind(),
+        );
+        if let CompilerArguments::Ok(ref mut parsed) = parsed {
+            let files = settings_files(&parsed.common_args, cwd);
+            parsed.extra_hash_files.extend(files);
+        }
+        parsed
+    }
+
+    fn preprocess<T>(
+        &self,
+        creator: &T,
+        executable: &Path,
+        parsed_args: &ParsedArguments,
+        cwd: &Path,
+        env_vars: &[(OsString, OsString)],
+        may_dist: bool,
+        rewrite_includes_only: bool,
+    ) -> SFuture<process::Output>
+    where
+        T: CommandCreatorSync,
+    {
+        gcc::preprocess(
+            creator,
+            executable,
+            parsed_args,
+            cwd,
+            env_vars,
+            may_dist,
+            self.kind(),
+            rewrite_includes_only,
+        )
+    }
+
+    fn generate_compile_commands(
+        &self,
+        path_transformer: &mut dist::PathTransformer,
+        executable: &Path,
+        parsed_args: &ParsedArguments,
+        cwd: &Path,
+        env_vars: &[(OsString, OsString)],
+        rewrite_includes_only: bool,
+    ) -> Result<(CompileCommand, Option<dist::CompileCommand>, Cacheable)> {
+        // emcc is a Python script driving clang, with its own configuration
+        // and system libraries, so it can't be packaged as a toolchain.
+        gcc::generate_compile_commands(
+            path_transformer,
+            executable,
+            parsed_args,
+            cwd,
+            env_vars,
+            self.kind(),
+            rewrite_includes_only,
+        )
+        .map(|(command, _, cacheable)| (command, None, cacheable))
+    }
+}
+
+/// Find the configuration file emcc reads: the one named by `EM_CONFIG`, or
+/// else `.emscripten` next to emcc, as set up by emsdk, or in the home
+/// directory.
+fn find_config(
+    executable: &Path,
+    cwd: &Path,
+    env_vars: &[(OsString, OsString)],
+) -> Option<PathBuf> {
+    let var = |name: &str| {
+        env_vars
+            .iter()
+            .find(|&(k, _)| k == name)
+            .map(|(_, v)| PathBuf::from(v))
+    };
+    if let Some(config) = var("EM_CONFIG") {
+        return Some(cwd.join(config)).filter(|p| p.is_file());
+    }
+    let emscripten_root = fs::canonicalize(executable)
+        .ok()
+        .and_then(|p| p.parent().map(Path::to_owned));
+    emscripten_root
+        .into_iter()
+        .chain(var("HOME"))
+        .map(|dir| dir.join(".emscripten"))
+        .find(|p| p.is_file())
+}
+
+/// Find the files read for `-sSETTING=@file` arguments, which are
+/// normalized to that form in `common_args`.
+fn settings_files(common_args: &[OsString], cwd: &Path) -> Vec<PathBuf> {
+    common_args
+        .iter()
+        .map(|arg| arg.to_string_lossy())
+        .filter(|arg| arg.starts_with("-s"))
+        .filter_map(|arg| arg.find("=@").map(|pos| cwd.join(&arg[pos + 2..])))
+        .collect()
+}
+
+counted_array!(pub static ARGS: [ArgInfo<gcc::ArgData>; _] = [
+    take_arg!("--cache", PathBuf, Separated, PassThroughPath),
+    take_arg!("--closure", OsString, Separated, PassThrough),
+    take_arg!("--em-config", PathBuf, Separated, ExtraHashFile),
+    take_arg!("--embed-file", OsString, Separated, TooHard),
+    take_arg!("--exclude-file", OsString, Separated, TooHard),
+    take_arg!("--extern-post-js", OsString, Separated, TooHard),
+    take_arg!("--extern-pre-js", OsString, Separated, TooHard),
+    take_arg!("--js-library", OsString, Separated, TooHard),
+    take_arg!("--llvm-lto", OsString, Separated, PassThrough),
+    take_arg!("--llvm-opts", OsString, Separated, PassThrough),
+    take_arg!("--memory-init-file", OsString, Separated, PassThrough),
+    take_arg!("--post-js", OsString, Separated, TooHard),
+    take_arg!("--pre-js", OsString, Separated, TooHard),
+    take_arg!("--preload-file", OsString, Separated, TooHard),
+    take_arg!("--shell-file", OsString, Separated, TooHard),
+    take_arg!("-s", OsString, CanBeSeparated, PassThrough),
+]);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::*;
+    use crate::test::utils::*;
+
+    fn parse_arguments_(arguments: Vec<String>) -> CompilerArguments<ParsedArguments> {
+        let arguments = arguments.iter().map(OsString::from).collect::<Vec<_>>();
+        Emscripten { emplusplus: false }.parse_arguments(&arguments, ".".as_ref())
+    }
+
+    macro_rules! parses {
+        ( $( $s:expr ),* ) => {
+            match parse_arguments_(vec![ $( $s.to_string(), )* ]) {
+                CompilerArguments::Ok(a) => a,
+                o => panic!("Got unexpected parse result: {:?}", o),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_arguments_settings() {
+        let a = parses!(
+            "-c",
+            "foo.c",
+            "-o",
+            "foo.o",
+            "-s",
+            "USE_PTHREADS=1",
+            "-sEXPORTED_FUNCTIONS=@exports.txt",
+            "-std=c11",
+            "-target",
+            "wasm32-unknown-emscripten",
+            "--em-config",
+            "my.emscripten"
+        );
+        assert_map_contains!(a.outputs, ("obj", PathBuf::from("foo.o")));
+        assert_eq!(
+            ovec![
+                "-sUSE_PTHREADS=1",
+                "-sEXPORTED_FUNCTIONS=@exports.txt",
+                "-std=c11",
+                "-target",
+                "wasm32-unknown-emscripten",
+                "--em-config",
+                "my.emscripten"
+            ],
+            a.common_args
+        );
+        assert_eq!(
+            vec![
+                Path::new(".").join("my.emscripten"),
+                Path::new(".").join("exports.txt")
+            ],
+            a.extra_hash_files
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_link_only() {
+        assert_eq!(
+            CompilerArguments::CannotCache("--preload-file", None),
+            parse_arguments_(stringvec![
+                "-c",
+                "foo.c",
+                "-o",
+                "foo.o",
+                "--preload-file",
+                "assets"
+            ])
+        );
+    }
+
+    #[test]
+    fn test_find_config() {
+        let f = TestFixture::new();
+        let emcc = f.mk_bin("emcc").unwrap();
+        let cwd = f.tempdir.path();
+        let home = vec![(OsString::from("HOME"), cwd.join("home").into_os_string())];
+        assert_eq!(None, find_config(&emcc, cwd, &home));
+        let home_config = f.touch("home/.emscripten").unwrap();
+        assert_eq!(Some(home_config), find_config(&emcc, cwd, &home));
+        let emsdk_config = fs::canonicalize(&emcc)
+            .unwrap()
+            .with_file_name(".emscripten");
+        fs::write(&emsdk_config, "LLVM_ROOT = '/emsdk/upstream/bin'").unwrap();
+        assert_eq!(Some(emsdk_config), find_config(&emcc, cwd, &home));
+        let env_config = f.touch("em_config").unwrap();
+        let env = vec![(OsString::from("EM_CONFIG"), OsString::from("em_config"))];
+        assert_eq!(Some(env_config), find_config(&emcc, cwd, &env));
+    }
+}
@@ -0,0 +1,467 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::compiler::args::*;
+use crate::compiler::c::{CCompiler, CCompilerImpl, CCompilerKind, Language, ParsedArguments};
+use crate::compiler::gcc::ArgData::*;
+use crate::compiler::{gcc, Cacheable, CompileCommand, CompilerArguments, DetectedCompiler};
+use crate::dist;
+use crate::mock_command::CommandCreatorSync;
+use crate::util::OsStrExt;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::errors::*;
+
+/// A struct on which to implement `CCompilerImpl`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Fortran {
+    /// true iff this is flang, which names submodule files differently.
+    pub flang: bool,
+}
+
+impl CCompilerImpl for Fortran {
+    fn kind(&self) -> CCompilerKind {
+        CCompilerKind::Fortran
+    }
+    fn plusplus(&self) -> bool {
+        false
+    }
+    fn detected(compiler: &CCompiler<Self>) -> DetectedCompiler {
+        DetectedCompiler::Fortran(compiler.clone())
+    }
+    fn parse_arguments(
+        &self,
+        arguments: &[OsString],
+        cwd: &Path,
+    ) -> CompilerArguments<ParsedArguments> {
+        match gcc::parse_arguments(
+            arguments,
+            cwd,
+            (&gcc::ARGS[..], &ARGS[..]),
+            false,
+            self.kind(),
+        ) {
+            CompilerArguments::Ok(parsed) => add_modules(parsed, cwd, self.flang),
+            // Sources compiled together can use the modules of the ones before
+            // them, so they can't be compiled separately.
+            CompilerArguments::Multiple(_) => {
+                CompilerArguments::CannotCache("multiple input files", None)
+            }
+            other => other,
+        }
+    }
+
+    fn preprocess<T>(
+        &self,
+        creator: &T,
+        executable: &Path,
+        parsed_args: &ParsedArguments,
+        cwd: &Path,
+        env_vars: &[(OsString, OsString)],
+        may_dist: bool,
+        rewrite_includes_only: bool,
+    ) -> SFuture<process::Output>
+    where
+        T: CommandCreatorSync,
+    {
+        gcc::preprocess(
+            creator,
+            executable,
+            parsed_args,
+            cwd,
+            env_vars,
+            may_dist,
+            self.kind(),
+            rewrite_includes_only,
+        )
+    }
+
+    fn generate_compile_commands(
+        &self,
+        path_transformer: &mut dist::PathTransformer,
+        executable: &Path,
+        parsed_args: &ParsedArguments,
+        cwd: &Path,
+        env_vars: &[(OsString, OsString)],
+        rewrite_includes_only: bool,
+    ) -> Result<(CompileCommand, Option<dist::CompileCommand>, Cacheable)> {
+        // The modules a compilation uses live in the local build tree, and
+        // the ones it writes aren't known to the dist server.
+        gcc::generate_compile_commands(
+            path_transformer,
+            executable,
+            parsed_args,
+            cwd,
+            env_vars,
+            self.kind(),
+            rewrite_includes_only,
+        )
+        .map(|(command, _, cacheable)| (command, None, cacheable))
+    }
+}
+
+/// Whether `executable` is named like a Fortran compiler, which is detected
+/// with a Fortran source instead of a C one.
+pub fn is_fortran_compiler(executable: &Path) -> bool {
+    let name = match executable.file_stem() {
+        Some(name) => name.to_string_lossy().to_lowercase(),
+        None => return false,
+    };
+    name.contains("fortran") || name.starts_with("flang") || name == "f77" || name == "f95"
+}
+
+/// Apply `-cpp`/`-nocpp` to the language, and find the module files `parsed`
+/// uses and writes, as well as the files it `include`s.
+///
+/// The source isn't preprocessed to find them, so a `use` or `module`
+/// statement produced by a macro or in an `#include`d file is missed.
+fn add_modules(
+    mut parsed: ParsedArguments,
+    cwd: &Path,
+    flang: bool,
+) -> CompilerArguments<ParsedArguments> {
+    if !parsed.language.is_fortran() {
+        return CompilerArguments::Ok(parsed);
+    }
+    let mut module_dir = PathBuf::new();
+    let mut args = parsed.common_args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "-cpp" {
+            parsed.language = Language::FortranWithCpp;
+        } else if arg == "-nocpp" {
+            parsed.language = Language::Fortran;
+        } else if arg == "-module-dir" {
+            module_dir = args.next().map(PathBuf::from).unwrap_or_default();
+        } else if let Some(dir) = arg.split_prefix("-J") {
+            module_dir = dir.into();
+        }
+    }
+    let include_dirs = parsed
+        .preprocessor_args
+        .iter()
+        .filter_map(|arg| arg.split_prefix("-I"))
+        .map(|dir| cwd.join(dir))
+        .collect::<Vec<_>>();
+
+    let input = cwd.join(&parsed.input);
+    // A source that can't be read fails to compile anyway.
+    let source = match fs::read(&input) {
+        Ok(source) => source,
+        Err(_) => return CompilerArguments::Ok(parsed),
+    };
+    let units = ProgramUnits::scan(&String::from_utf8_lossy(&source));
+
+    for name in &units.modules {
+        let module = module_dir.join(format!("{}.mod", name));
+        parsed.module_outputs.push(module);
+        if units.separate_procedures && !flang {
+            parsed
+                .module_outputs
+                .push(module_dir.join(format!("{}.smod", name)));
+        }
+    }
+    for (ancestor, name) in &units.submodules {
+        let submodule = if flang {
+            format!("{}-{}.mod", ancestor, name)
+        } else {
+            format!("{}@{}.smod", ancestor, name)
+        };
+        parsed.module_outputs.push(module_dir.join(submodule));
+    }
+    // Outputs are stored in the cache under their file names.
+    if parsed
+        .module_outputs
+        .iter()
+        .any(|path| path.to_str().is_none())
+    {
+        cannot_cache!("non-UTF-8 module path");
+    }
+
+    // Modules are looked up in the working directory, then like includes,
+    // then in the directory modules are written to. Intrinsic modules ship
+    // with the compiler and aren't found here.
+    let module_dirs = Some(cwd.to_owned())
+        .into_iter()
+        .chain(include_dirs.iter().cloned())
+        .chain(Some(cwd.join(&module_dir)))
+        .collect::<Vec<_>>();
+    let used = units
+        .uses
+        .iter()
+        .filter(|name| !units.modules.contains(name))
+        .filter_map(|name| find_file(&module_dirs, &format!("{}.mod", name)));
+    parsed.extra_hash_files.extend(used);
+
+    let include_dirs = input
+        .parent()
+        .map(Path::to_owned)
+        .into_iter()
+        .chain(include_dirs)
+        .collect::<Vec<_>>();
+    let included = units
+        .includes
+        .iter()
+        .filter_map(|name| find_file(&include_dirs, name));
+    parsed.extra_hash_files.extend(included);
+    CompilerArguments::Ok(parsed)
+}
+
+/// Return the first of `dirs` containing a file called `name`.
+fn find_file(dirs: &[PathBuf], name: &str) -> Option<PathBuf> {
+    dirs.iter().map(|dir| dir.join(name)).find(|p| p.is_file())
+}
+
+/// The modules and files a Fortran source refers to, with names lowercased
+/// as the compilers do for module files.
+#[derive(Debug, Default, PartialEq)]
+struct ProgramUnits {
+    /// Modules from `use` statements, other than intrinsic ones.
+    uses: Vec<String>,
+    /// Modules defined by the source.
+    modules: Vec<String>,
+    /// Submodules defined by the source, with their ancestor module.
+    submodules: Vec<(String, String)>,
+    /// Whether a module declares separate module procedures, for which
+    /// gfortran also writes a `.smod` file.
+    separate_procedures: bool,
+    /// Files from `include` lines, as written.
+    includes: Vec<String>,
+}
+
+impl ProgramUnits {
+    fn scan(source: &str) -> Self {
+        let mut units = ProgramUnits::default();
+        for line in source.lines() {
+            let line = line.split('!').next().unwrap_or("").trim();
+            let lower = line.to_ascii_lowercase();
+            let mut words = lower
+                .split(|c: char| c.is_whitespace() || c == ',' || c == ':' || c == '(' || c == ')')
+                .filter(|w| !w.is_empty());
+            match words.next() {
+                Some("use") => {
+                    let mut intrinsic = false;
+                    for word in words {
+                        match word {
+                            "intrinsic" => intrinsic = true,
+                            "non_intrinsic" => {}
+                            name if !intrinsic => {
+                                if !units.uses.iter().any(|u| u == name) {
+                                    units.uses.push(name.to_owned());
+                                }
+                                break;
+                            }
+                            _ => break,
+                        }
+                    }
+                }
+                Some("module") => match words.next() {
+                    Some("function") | Some("subroutine") => units.separate_procedures = true,
+                    Some("procedure") | None => {}
+                    Some(name) => units.modules.push(name.to_owned()),
+                },
+                Some("submodule") => {
+                    let names = words.collect::<Vec<_>>();
+                    if let (Some(ancestor), Some(name)) = (names.first(), names.last()) {
+                        units
+                            .submodules
+                            .push(((*ancestor).to_owned(), (*name).to_owned()));
+                    }
+                }
+                Some("include") => {
+                    let name = line[7..].trim().trim_matches(|c| c == '\'' || c == '"');
+                    units.includes.push(name.to_owned());
+                }
+                Some(_) => {
+                    // Prefixed separate module procedures, like
+                    // `pure module function`.
+                    let words = lower.split_whitespace().collect::<Vec<_>>();
+                    if words
+                        .windows(2)
+                        .any(|w| w[0] == "module" && (w[1] == "function" || w[1] == "subroutine"))
+                    {
+                        units.separate_procedures = true;
+                    }
+                }
+                None => {}
+            }
+        }
+        units
+    }
+}
+
+counted_array!(pub static ARGS: [ArgInfo<gcc::ArgData>; _] = [
+    take_arg!("-J", PathBuf, CanBeSeparated, PassThroughPath),
+    take_arg!("-fintrinsic-modules-path", PathBuf, CanBeSeparated('='), PassThroughPath),
+    take_arg!("-module-dir", PathBuf, Separated, PassThroughPath),
+]);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::*;
+    use crate::test::utils::*;
+
+    fn parse_arguments_(
+        arguments: Vec<String>,
+        cwd: &Path,
+        flang: bool,
+    ) -> CompilerArguments<ParsedArguments> {
+        let arguments = arguments.iter().map(OsString::from).collect::<Vec<_>>();
+        Fortran { flang }.parse_arguments(&arguments, cwd)
+    }
+
+    #[test]
+    fn test_scan_program_units() {
+        let source = "
+module shapes
+  use, intrinsic :: iso_c_binding
+  use Geometry, only: area
+  use :: units
+  include 'constants.inc'
+  interface
+    pure module function perimeter(s)
+    end function
+  end interface
+end module shapes
+! module commented_out
+submodule (shapes:shapes_impl) shapes_area
+end submodule
+";
+        assert_eq!(
+            ProgramUnits {
+                uses: stringvec!["geometry", "units"],
+                modules: stringvec!["shapes"],
+                submodules: vec![("shapes".to_owned(), "shapes_area".to_owned())],
+                separate_procedures: true,
+                includes: stringvec!["constants.inc"],
+            },
+            ProgramUnits::scan(source)
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_modules() {
+        let f = TestFixture::new();
+        let cwd = f.tempdir.path();
+        f.touch("mods/geometry.mod").unwrap();
+        f.touch("include/units.mod").unwrap();
+        f.touch("include/constants.inc").unwrap();
+        fs::write(
+            cwd.join("shapes.f90"),
+            "module shapes\nuse geometry\nuse units\nuse shapes_base\ninclude 'constants.inc'\nend module\n",
+        )
+        .unwrap();
+        let parsed = match parse_arguments_(
+            stringvec![
+                "-c",
+                "shapes.f90",
+                "-o",
+                "shapes.o",
+                "-Iinclude",
+                "-J",
+                "mods"
+            ],
+            cwd,
+            false,
+        ) {
+            CompilerArguments::Ok(parsed) => parsed,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(Language::Fortran, parsed.language);
+        assert_eq!(ovec!["-Jmods"], parsed.common_args);
+        assert_eq!(
+            vec![PathBuf::from("mods/shapes.mod")],
+            parsed.module_outputs
+        );
+        assert_eq!(
+            vec![
+                cwd.join("mods/geometry.mod"),
+                cwd.join("include/units.mod"),
+                cwd.join("include/constants.inc"),
+            ],
+            parsed.extra_hash_files
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_parse_arguments_non_utf8_module_dir() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let f = TestFixture::new();
+        let cwd = f.tempdir.path();
+        fs::write(cwd.join("shapes.f90"), "module shapes\nend module\n").unwrap();
+        let mut arguments = ovec!["-c", "shapes.f90", "-J"];
+        arguments.push(OsString::from_vec(b"mods\xff".to_vec()));
+        assert_eq!(
+            CompilerArguments::CannotCache("non-UTF-8 module path", None),
+            Fortran { flang: false }.parse_arguments(&arguments, cwd)
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_submodule_flang() {
+        let f = TestFixture::new();
+        let cwd = f.tempdir.path();
+        fs::write(
+            cwd.join("impl.F90"),
+            "submodule (shapes) shapes_impl\nend submodule\n",
+        )
+        .unwrap();
+        let parsed = match parse_arguments_(
+            stringvec!["-c", "impl.F90", "-module-dir", "mods"],
+            cwd,
+            true,
+        ) {
+            CompilerArguments::Ok(parsed) => parsed,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(Language::FortranWithCpp, parsed.language);
+        assert_eq!(
+            vec![PathBuf::from("mods/shapes-shapes_impl.mod")],
+            parsed.module_outputs
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_cpp_flag() {
+        let parsed =
+            match parse_arguments_(stringvec!["-c", "foo.f90", "-cpp"], Path::new("."), false) {
+                CompilerArguments::Ok(parsed) => parsed,
+                o => panic!("Got unexpected parse result: {:?}", o),
+            };
+        assert_eq!(Language::FortranWithCpp, parsed.language);
+    }
+
+    #[test]
+    fn test_parse_arguments_multiple_inputs() {
+        assert_eq!(
+            CompilerArguments::CannotCache("multiple input files", None),
+            parse_arguments_(stringvec!["-c", "a.f90", "b.f90"], Path::new("."), false)
+        );
+    }
+
+    #[test]
+    fn test_is_fortran_compiler() {
+        assert!(is_fortran_compiler(Path::new("/usr/bin/gfortran")));
+        assert!(is_fortran_compiler(Path::new(
+            "x86_64-linux-gnu-gfortran-12"
+        )));
+        assert!(is_fortran_compiler(Path::new("flang-new")));
+        assert!(!is_fortran_compiler(Path::new("/usr/bin/gcc")));
+    }
+}
@@ -13,15 +13,19 @@
 // limitations under the License.
 
 use crate::compiler::args::*;
-use crate::compiler::c::{CCompilerImpl, CCompilerKind, Language, ParsedArguments};
-use crate::compiler::{clang, Cacheable, ColorMode, CompileCommand, CompilerArguments};
+use crate::compiler::c::{
+    parse_each_input, CCompiler, CCompilerImpl, CCompilerKind, Language, ParsedArguments,
+};
+use crate::compiler::{
+    clang, Cacheable, ColorMode, CompileCommand, CompilerArguments, DetectedCompiler,
+};
 use crate::dist;
 use crate::mock_command::{CommandCreatorSync, RunCommand};
 use crate::util::{run_input_output, OsStrExt};
 use log::Level::Trace;
 use std::collections::HashMap;
 use std::ffi::OsString;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::process;
@@ -29,7 +33,7 @@ use std::process;
 use crate::errors::*;
 
 /// A struct on which to implement `CCompilerImpl`.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GCC {
     pub gplusplus: bool,
 }
@@ -41,6 +45,9 @@ impl CCompilerImpl for GCC {
     fn plusplus(&self) -> bool {
         self.gplusplus
     }
+    fn detected(compiler: &CCompiler<Self>) -> DetectedCompiler {
+        DetectedCompiler::GCC(compiler.clone())
+    }
     fn parse_arguments(
         &self,
         arguments: &[OsString],
@@ -127,6 +134,18 @@ ArgData! { pub
     Arch(OsString),
     PedanticFlag,
     Standard(OsString),
+    // Clang modules (`-fmodules`, `-fcxx-modules`) are only cacheable when
+    // implicit module builds are turned off.
+    Modules,
+    NoImplicitModules,
+    // GCC's C++ modules support, which needs an explicit module mapper file.
+    ModulesTs,
+    ModuleMapper(PathBuf),
+    // A prebuilt module (BMI) consumed by the compilation, as `[name=]path`.
+    ModuleFile(OsString),
+    // A BMI produced alongside the object file. An empty path means the
+    // default location next to the object file.
+    ModuleOutput(PathBuf),
 }
 
 use self::ArgData::*;
@@ -168,6 +187,8 @@ counted_array!(pub static ARGS: [ArgInfo<ArgData>; _] = [
     take_arg!("-b", OsString, Separated, PassThrough),
     flag!("-c", DoCompilation),
     take_arg!("-fdiagnostics-color", OsString, Concatenated('='), DiagnosticsColor),
+    take_arg!("-fmodule-mapper", PathBuf, Concatenated('='), ModuleMapper),
+    flag!("-fmodules-ts", ModulesTs),
     flag!("-fno-diagnostics-color", NoDiagnosticsColorFlag),
     flag!("-fno-working-directory", PreprocessorArgumentFlag),
     flag!("-fplugin=libcc1plugin", TooHardFlag),
@@ -224,8 +245,9 @@ pub fn parse_arguments<S>(
     kind: CCompilerKind,
 ) -> CompilerArguments<ParsedArguments>
 where
-    S: SearchableArgInfo<ArgData>,
+    S: SearchableArgInfo<ArgData> + Copy,
 {
+    let mut all_args = vec![];
     let mut output_arg = None;
     let mut input_arg = None;
     let mut dep_target = None;
@@ -248,6 +270,11 @@ where
     let mut xclangs: Vec<OsString> = vec![];
     let mut color_mode = ColorMode::Auto;
     let mut seen_arch = None;
+    let mut modules_flag = None;
+    let mut implicit_modules = true;
+    let mut modules_ts = false;
+    let mut module_mapper = None;
+    let mut module_output = None;
 
     // Custom iterator to expand `@` arguments which stand for reading a file
     // and interpreting it as a list of more arguments.
@@ -255,6 +282,7 @@ where
 
     for arg in ArgsIter::new(it, arg_info) {
         let arg = try_or_cannot_cache!(arg, "argument parse");
+        all_args.push(arg.clone());
         // Check if the value part of this argument begins with '@'. If so, we either
         // failed to expand it, or it was a concatenated argument - either way, bail.
         // We refuse to cache concatenated arguments (like "-include@foo") because they're a
@@ -325,9 +353,16 @@ where
                 language = match lang.to_string_lossy().as_ref() {
                     "c" => Some(Language::C),
                     "c++" => Some(Language::Cxx),
+                    "c++-module" => Some(Language::CxxModule),
                     "objective-c" => Some(Language::ObjectiveC),
                     "objective-c++" => Some(Language::ObjectiveCxx),
                     "cu" => Some(Language::Cuda),
+                    "assembler" => Some(Language::Assembler),
+                    "assembler-with-cpp" => Some(Language::AssemblerWithCpp),
+                    "cpp-output" => Some(Language::PreprocessedC),
+                    "c++-cpp-output" => Some(Language::PreprocessedCxx),
+                    "f77" | "f95" => Some(Language::Fortran),
+                    "f77-cpp-input" | "f95-cpp-input" => Some(Language::FortranWithCpp),
                     _ => cannot_cache!("-x"),
                 };
             }
@@ -339,6 +374,12 @@ where
                 seen_arch = Some(arch.clone());
             }
             Some(XClang(s)) => xclangs.push(s.clone()),
+            Some(Modules) => modules_flag = arg.flag_str(),
+            Some(NoImplicitModules) => implicit_modules = false,
+            Some(ModulesTs) => modules_ts = true,
+            Some(ModuleMapper(path)) => module_mapper = Some(cwd.join(path)),
+            Some(ModuleFile(value)) => extra_hash_files.push(cwd.join(module_file_path(value))),
+            Some(ModuleOutput(path)) => module_output = Some(path.clone()),
             None => match arg {
                 Argument::Raw(ref val) => {
                     if input_arg.is_some() {
@@ -362,7 +403,12 @@ where
             | Some(NoDiagnosticsColorFlag)
             | Some(Arch(_))
             | Some(PassThrough(_))
-            | Some(PassThroughPath(_)) => &mut common_args,
+            | Some(PassThroughPath(_))
+            | Some(Modules)
+            | Some(NoImplicitModules)
+            | Some(ModulesTs)
+            | Some(ModuleMapper(_))
+            | Some(ModuleFile(_)) => &mut common_args,
             Some(ExtraHashFile(path)) => {
                 extra_hash_files.push(cwd.join(path));
                 &mut common_args
@@ -371,7 +417,13 @@ where
             | Some(PreprocessorArgument(_))
             | Some(PreprocessorArgumentPath(_)) => &mut preprocessor_args,
             Some(DepArgumentPath(_)) | Some(NeedDepTarget) => &mut dependency_args,
-            Some(DoCompilation) | Some(Language(_)) | Some(Output(_)) | Some(XClang(_))
+            // Like `-o`, the BMI path is an output and gets passed back in
+            // `generate_compile_commands`.
+            Some(DoCompilation)
+            | Some(Language(_))
+            | Some(Output(_))
+            | Some(ModuleOutput(_))
+            | Some(XClang(_))
             | Some(DepTarget(_)) => continue,
             Some(TooHardFlag) | Some(TooHard(_)) => unreachable!(),
             None => match arg {
@@ -407,7 +459,13 @@ where
             | Some(Output(_))
             | Some(TooHardFlag)
             | Some(XClang(_))
-            | Some(TooHard(_)) => cannot_cache!(arg
+            | Some(TooHard(_))
+            | Some(Modules)
+            | Some(NoImplicitModules)
+            | Some(ModulesTs)
+            | Some(ModuleMapper(_))
+            | Some(ModuleFile(_))
+            | Some(ModuleOutput(_)) => cannot_cache!(arg
                 .flag_str()
                 .unwrap_or("Can't handle complex arguments through clang",)),
             None => match arg {
@@ -457,9 +515,19 @@ where
     if !compilation {
         return CompilerArguments::NotCompilation;
     }
-    // Can't cache compilations with multiple inputs.
+    // Compilations with multiple inputs are split into one compilation per
+    // input, unless the inputs would share an output.
     if multiple_input {
-        cannot_cache!("multiple input files");
+        if output_arg.is_some()
+            || dep_target.is_some()
+            || need_explicit_dep_argument_path == Some(false)
+            || module_output.is_some()
+        {
+            cannot_cache!("multiple input files");
+        }
+        return parse_each_input(&all_args, |arguments| {
+            parse_arguments(arguments, cwd, arg_info, plusplus, kind.clone())
+        });
     }
     let input = match input_arg {
         Some(i) => i,
@@ -469,8 +537,10 @@ where
     let language = match language {
         None => {
             let mut lang = Language::from_file_name(Path::new(&input));
-            if let (Some(Language::C), true) = (lang, plusplus) {
-                lang = Some(Language::Cxx);
+            match (lang, plusplus) {
+                (Some(Language::C), true) => lang = Some(Language::Cxx),
+                (Some(Language::PreprocessedC), true) => lang = Some(Language::PreprocessedCxx),
+                _ => {}
             }
             lang
         }
@@ -480,6 +550,33 @@ where
         Some(l) => l,
         None => cannot_cache!("unknown source language"),
     };
+    // Only the Fortran compilers know which modules a compilation uses.
+    if language.is_fortran() && kind != CCompilerKind::Fortran {
+        cannot_cache!("fortran source");
+    }
+    // Only clang understands `-x c++-module`, and GCC builds module
+    // interfaces through `-fmodules-ts` instead.
+    if language == Language::CxxModule && kind != CCompilerKind::Clang {
+        cannot_cache!("c++ module interface");
+    }
+    // nvcc only takes C, C++ and CUDA sources with `-x`.
+    if kind == CCompilerKind::NVCC
+        && !matches!(language, Language::C | Language::Cxx | Language::Cuda)
+    {
+        cannot_cache!("nvcc input language");
+    }
+    // The assembler reads files named by `.include` and `.incbin` itself,
+    // without the preprocessor or us knowing about them.
+    if matches!(language, Language::Assembler | Language::AssemblerWithCpp)
+        && uses_assembler_includes(&cwd.join(&input))
+    {
+        cannot_cache!(".include");
+    }
+    // Dependency files are written by the preprocessor, which isn't run for
+    // inputs that don't need it.
+    if !language.needs_preprocessor() && !dependency_args.is_empty() {
+        cannot_cache!("dependency file without preprocessing");
+    }
     let mut outputs = HashMap::new();
     let output = match output_arg {
         // We can't cache compilation that doesn't go to a file
@@ -499,6 +596,43 @@ where
         outputs.insert("gcno", gcno);
         profile_generate = true;
     }
+    // Implicit clang module builds read and write a module cache behind our
+    // back, so only explicit module builds can be cached.
+    if let (Some(flag), true) = (modules_flag, implicit_modules) {
+        cannot_cache!(flag);
+    }
+    if let Some(module_output) = module_output {
+        let pcm = if module_output.as_os_str().is_empty() {
+            output.with_extension("pcm")
+        } else {
+            module_output
+        };
+        outputs.insert("pcm", pcm);
+    }
+    // Without a mapper file, GCC looks modules up in (and writes them to) its
+    // `gcm.cache` repository, which we can't know the contents of.
+    if modules_ts {
+        let mapper = match module_mapper {
+            Some(ref mapper) if is_module_mapper_file(mapper) => mapper,
+            _ => cannot_cache!("-fmodules-ts"),
+        };
+        let entries = try_or_cannot_cache!(parse_module_mapper(mapper, cwd), "-fmodule-mapper");
+        let exported = module_interface_name(&cwd.join(&input));
+        for (name, cmi) in entries {
+            if exported.as_ref() == Some(&name) {
+                outputs.insert("gcm", cmi);
+            } else if cmi.is_file() {
+                extra_hash_files.push(cmi);
+            }
+        }
+        if exported.is_some() && !outputs.contains_key("gcm") {
+            cannot_cache!(
+                "-fmodule-mapper",
+                "exported module is not in the mapper".to_owned()
+            );
+        }
+        extra_hash_files.push(mapper.clone());
+    }
     if need_explicit_dep_target {
         dependency_args.push(dep_flag);
         dependency_args.push(dep_target.unwrap_or_else(|| output.clone().into_os_string()));
@@ -519,6 +653,7 @@ where
         preprocessor_args,
         common_args,
         extra_hash_files,
+        module_outputs: vec![],
         msvc_show_includes: false,
         profile_generate,
         color_mode,
@@ -544,9 +679,16 @@ where
     let language = match parsed_args.language {
         Language::C => "c",
         Language::Cxx => "c++",
+        Language::CxxModule => "c++-module",
         Language::ObjectiveC => "objective-c",
         Language::ObjectiveCxx => "objective-c++",
         Language::Cuda => "cu",
+        Language::Assembler => "assembler",
+        Language::AssemblerWithCpp => "assembler-with-cpp",
+        Language::PreprocessedC => "cpp-output",
+        Language::PreprocessedCxx => "c++-cpp-output",
+        Language::Fortran => "f95",
+        Language::FortranWithCpp => "f95-cpp-input",
     };
     let mut cmd = creator.clone().new_command_sync(executable);
     cmd.arg("-x").arg(language).arg("-E");
@@ -617,9 +759,16 @@ pub fn generate_compile_commands(
     let language = match parsed_args.language {
         Language::C => "c",
         Language::Cxx => "c++",
+        Language::CxxModule => "c++-module",
         Language::ObjectiveC => "objective-c",
         Language::ObjectiveCxx => "objective-c++",
         Language::Cuda => "cu",
+        Language::Assembler => "assembler",
+        Language::AssemblerWithCpp => "assembler-with-cpp",
+        Language::PreprocessedC => "cpp-output",
+        Language::PreprocessedCxx => "c++-cpp-output",
+        Language::Fortran => "f95",
+        Language::FortranWithCpp => "f95-cpp-input",
     };
     let mut arguments: Vec<OsString> = vec![
         "-x".into(),
@@ -631,6 +780,11 @@ pub fn generate_compile_commands(
     ];
     arguments.extend(parsed_args.preprocessor_args.clone());
     arguments.extend(parsed_args.common_args.clone());
+    if let Some(pcm) = parsed_args.outputs.get("pcm") {
+        let mut arg = OsString::from("-fmodule-output=");
+        arg.push(pcm);
+        arguments.push(arg);
+    }
     let command = CompileCommand {
         executable: executable.to_owned(),
         arguments,
@@ -646,14 +800,27 @@ pub fn generate_compile_commands(
         let mut language: String = match parsed_args.language {
             Language::C => "c",
             Language::Cxx => "c++",
+            Language::CxxModule => "c++-module",
             Language::ObjectiveC => "objective-c",
             Language::ObjectiveCxx => "objective-c++",
             Language::Cuda => "cu",
+            Language::Assembler => "assembler",
+            Language::AssemblerWithCpp => "assembler-with-cpp",
+            Language::PreprocessedC => "cpp-output",
+            Language::PreprocessedCxx => "c++-cpp-output",
+            Language::Fortran => "f95",
+            Language::FortranWithCpp => "f95-cpp-input",
         }
         .into();
         if !rewrite_includes_only {
             match parsed_args.language {
                 Language::C => language = "cpp-output".into(),
+                Language::AssemblerWithCpp => language = "assembler".into(),
+                Language::FortranWithCpp => language = "f95".into(),
+                Language::Assembler
+                | Language::PreprocessedC
+                | Language::PreprocessedCxx
+                | Language::Fortran => {}
                 _ => language.push_str("-cpp-output"),
             }
         }
@@ -682,9 +849,21 @@ pub fn generate_compile_commands(
             if rewrite_includes_only && !parsed_args.suppress_rewrite_includes_only {
                 arguments.push("-fdirectives-only".into());
             }
-            arguments.push("-fpreprocessed".into());
+            match parsed_args.language {
+                Language::Assembler
+                | Language::AssemblerWithCpp
+                | Language::Fortran
+                | Language::FortranWithCpp => {}
+                _ => arguments.push("-fpreprocessed".into()),
+            }
         }
         arguments.extend(dist::osstrings_to_strings(&parsed_args.common_args)?);
+        if let Some(pcm) = parsed_args.outputs.get("pcm") {
+            arguments.push(format!(
+                "-fmodule-output={}",
+                path_transformer.as_dist(pcm)?
+            ));
+        }
         Some(dist::CompileCommand {
             executable: path_transformer.as_dist(&executable)?,
             arguments,
@@ -696,6 +875,86 @@ pub fn generate_compile_commands(
     Ok((command, dist_command, Cacheable::Yes))
 }
 
+/// Return the path part of a `-fmodule-file=[name=]path` value.
+fn module_file_path(value: &OsString) -> PathBuf {
+    match value
+        .to_str()
+        .and_then(|v| v.find('=').map(|i| &v[i + 1..]))
+    {
+        Some(path) => PathBuf::from(path),
+        None => PathBuf::from(value),
+    }
+}
+
+/// Whether a `-fmodule-mapper` value names a plain mapper file, as opposed to
+/// a mapper program (`|prog`), a socket (`=sock`, `host:port`) or a pipe (`<>`).
+fn is_module_mapper_file(mapper: &Path) -> bool {
+    let name = mapper.to_string_lossy();
+    let is_special = mapper
+        .file_name()
+        .and_then(|f| f.to_str())
+        .map_or(true, |f| f.starts_with(&['|', '=', '<'][..]))
+        || name.contains('?');
+    !is_special && mapper.is_file()
+}
+
+/// Parse a GCC module mapper file, returning the module names and the paths
+/// of their CMIs.
+///
+/// Each line is either `$root <dir>`, which sets the directory relative CMI
+/// paths are resolved against (`cwd` by default), or `<module> <cmi>`.
+fn parse_module_mapper(mapper: &Path, cwd: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut contents = String::new();
+    File::open(mapper)?.read_to_string(&mut contents)?;
+    let mut root = cwd.to_owned();
+    let mut entries = vec![];
+    for line in contents.lines() {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some(w), _) if w.starts_with('#') => {}
+            (Some("$root"), Some(dir)) => root = cwd.join(dir),
+            (Some(name), Some(cmi)) => entries.push((name.to_owned(), root.join(cmi))),
+            (None, _) => {}
+            (Some(_), None) => bail!("malformed module mapper line: `{}`", line),
+        }
+    }
+    Ok(entries)
+}
+
+/// Find the name of the module that `input` is the interface unit of, by
+/// looking for an `export module <name>;` declaration.
+///
+/// This doesn't run the preprocessor, so a declaration produced by a macro
+/// isn't found and the module's CMI won't be treated as an output.
+fn module_interface_name(input: &Path) -> Option<String> {
+    let mut contents = String::new();
+    File::open(input)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .ok()?;
+    contents.lines().find_map(|line| {
+        let mut words = line.trim().trim_end_matches(';').split_whitespace();
+        match (words.next(), words.next(), words.next(), words.next()) {
+            (Some("export"), Some("module"), Some(name), None) if line.trim().ends_with(';') => {
+                Some(name.to_owned())
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Whether the assembly source at `input` uses the `.include` or `.incbin`
+/// directives. A source that can't be read is left for the compiler to
+/// report on.
+fn uses_assembler_includes(input: &Path) -> bool {
+    let source = match fs::read(input) {
+        Ok(source) => source.to_ascii_lowercase(),
+        Err(_) => return false,
+    };
+    [&b".include"[..], &b".incbin"[..]]
+        .iter()
+        .any(|needle| source.windows(needle.len()).any(|w| w == *needle))
+}
+
 pub struct ExpandIncludeFile<'a> {
     cwd: &'a Path,
     stack: Vec<OsString>,
@@ -784,6 +1043,15 @@ mod test {
         parse_arguments(&args, ".".as_ref(), &ARGS[..], plusplus, CCompilerKind::GCC)
     }
 
+    macro_rules! parses {
+        ( $( $s:expr ),* ) => {
+            match parse_arguments_(vec![ $( $s.to_string(), )* ], false) {
+                CompilerArguments::Ok(a) => a,
+                o => panic!("Got unexpected parse result: {:?}", o),
+            }
+        }
+    }
+
     #[test]
     fn test_parse_arguments_simple() {
         let args = stringvec!["-c", "foo.c", "-o", "foo.o"];
@@ -1256,6 +1524,125 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_parse_arguments_assembly() {
+        let a = parses!("-c", "foo.S", "-o", "foo.o");
+        assert_eq!(Language::AssemblerWithCpp, a.language);
+        assert!(a.language.needs_preprocessor());
+        let a = parses!("-c", "foo.s", "-o", "foo.o");
+        assert_eq!(Language::Assembler, a.language);
+        assert!(!a.language.needs_preprocessor());
+        let a = parses!("-x", "assembler-with-cpp", "-c", "foo.asm", "-o", "foo.o");
+        assert_eq!(Language::AssemblerWithCpp, a.language);
+        assert_eq!(
+            CompilerArguments::CannotCache("dependency file without preprocessing", None),
+            parse_arguments_(stringvec!["-c", "foo.s", "-o", "foo.o", "-MD"], false)
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_assembler_includes() {
+        let f = TestFixture::new();
+        create_file(f.tempdir.path(), "plain.s", |mut f| {
+            f.write_all(b"\t.text\n\tnop\n")
+        })
+        .unwrap();
+        create_file(f.tempdir.path(), "include.s", |mut f| {
+            f.write_all(b"\t.include \"macros.inc\"\n\tnop\n")
+        })
+        .unwrap();
+        create_file(f.tempdir.path(), "incbin.S", |mut f| {
+            f.write_all(b"blob:\n\t.INCBIN \"blob.bin\"\n")
+        })
+        .unwrap();
+        let parse = |input: &str| {
+            let args = ovec!["-c", input, "-o", "foo.o"];
+            parse_arguments(
+                &args,
+                f.tempdir.path(),
+                &ARGS[..],
+                false,
+                CCompilerKind::GCC,
+            )
+        };
+        match parse("plain.s") {
+            CompilerArguments::Ok(a) => assert_eq!(Language::Assembler, a.language),
+            o => panic!("Got unexpected parse result: {:?}", o),
+        }
+        assert_eq!(
+            CompilerArguments::CannotCache(".include", None),
+            parse("include.s")
+        );
+        assert_eq!(
+            CompilerArguments::CannotCache(".include", None),
+            parse("incbin.S")
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_preprocessed() {
+        let a = parses!("-c", "foo.i", "-o", "foo.o");
+        assert_eq!(Language::PreprocessedC, a.language);
+        let a = parses!("-c", "foo.ii", "-o", "foo.o");
+        assert_eq!(Language::PreprocessedCxx, a.language);
+        let a = parses!("-x", "cpp-output", "-c", "foo.pp", "-o", "foo.o");
+        assert_eq!(Language::PreprocessedC, a.language);
+        match parse_arguments_(stringvec!["-c", "foo.i", "-o", "foo.o"], true) {
+            CompilerArguments::Ok(a) => assert_eq!(Language::PreprocessedCxx, a.language),
+            o => panic!("Got unexpected parse result: {:?}", o),
+        }
+    }
+
+    #[test]
+    fn test_hashed_args_preprocessed() {
+        let a = parses!("-c", "foo.c", "-o", "foo.o", "-nostdinc", "-Wall");
+        assert_eq!(ovec!["-Wall"], a.hashed_args().into_owned());
+        let a = parses!("-c", "foo.i", "-o", "foo.o", "-nostdinc", "-Wall");
+        assert_eq!(ovec!["-Wall", "-nostdinc"], a.hashed_args().into_owned());
+    }
+
+    #[test]
+    fn test_parse_arguments_multiple_inputs() {
+        let args = stringvec!["-c", "foo.c", "-MD", "-DFOO", "bar.cpp", "-Wall"];
+        let parsed = match parse_arguments_(args, false) {
+            CompilerArguments::Multiple(parsed) => parsed,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(2, parsed.len());
+        assert_eq!(Some("foo.c"), parsed[0].input.to_str());
+        assert_eq!(Language::C, parsed[0].language);
+        assert_map_contains!(parsed[0].outputs, ("obj", PathBuf::from("foo.o")));
+        assert_eq!(
+            ovec!["-MD", "-MT", "foo.o", "-MF", "foo.d"],
+            parsed[0].dependency_args
+        );
+        assert_eq!(Some("bar.cpp"), parsed[1].input.to_str());
+        assert_eq!(Language::Cxx, parsed[1].language);
+        assert_map_contains!(parsed[1].outputs, ("obj", PathBuf::from("bar.o")));
+        assert_eq!(
+            ovec!["-MD", "-MT", "bar.o", "-MF", "bar.d"],
+            parsed[1].dependency_args
+        );
+        for p in &parsed {
+            assert_eq!(ovec!["-DFOO", "-Wall"], p.common_args);
+        }
+    }
+
+    #[test]
+    fn test_parse_arguments_multiple_inputs_shared_output() {
+        assert_eq!(
+            CompilerArguments::CannotCache("multiple input files", None),
+            parse_arguments_(
+                stringvec!["-c", "foo.c", "-MD", "-MF", "a.d", "bar.c"],
+                false
+            )
+        );
+        assert_eq!(
+            CompilerArguments::CannotCache("-save-temps", None),
+            parse_arguments_(stringvec!["-c", "foo.c", "bar.c", "-save-temps"], false)
+        );
+    }
+
     #[test]
     fn test_parse_arguments_link() {
         assert_eq!(
@@ -1326,6 +1713,89 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_parse_arguments_cxx_module_not_clang() {
+        assert_eq!(
+            CompilerArguments::CannotCache("c++ module interface", None),
+            parse_arguments_(stringvec!["-c", "foo.cppm", "-o", "foo.o"], true)
+        );
+        assert_eq!(
+            CompilerArguments::CannotCache("c++ module interface", None),
+            parse_arguments_(
+                stringvec!["-x", "c++-module", "-c", "foo.cpp", "-o", "foo.o"],
+                true
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_modules_ts() {
+        assert_eq!(
+            CompilerArguments::CannotCache("-fmodules-ts", None),
+            parse_arguments_(
+                stringvec!["-fmodules-ts", "-c", "foo.cpp", "-o", "foo.o"],
+                true
+            )
+        );
+        assert_eq!(
+            CompilerArguments::CannotCache("-fmodules-ts", None),
+            parse_arguments_(
+                stringvec![
+                    "-fmodules-ts",
+                    "-fmodule-mapper=|mapper-server",
+                    "-c",
+                    "foo.cpp",
+                    "-o",
+                    "foo.o"
+                ],
+                true
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_module_mapper() {
+        let f = TestFixture::new();
+        f.touch("bar.gcm").unwrap();
+        let mapper = create_file(f.tempdir.path(), "modules.map", |mut f| {
+            f.write_all(b"# modules\n$root .\nfoo foo.gcm\nbar bar.gcm\nbaz baz.gcm\n")
+        })
+        .unwrap();
+        create_file(f.tempdir.path(), "foo.cpp", |mut f| {
+            f.write_all(b"module;\n#include <vector>\nexport module foo;\nimport bar;\n")
+        })
+        .unwrap();
+        let args = ovec![
+            "-fmodules-ts",
+            "-fmodule-mapper=modules.map",
+            "-c",
+            "foo.cpp",
+            "-o",
+            "foo.o"
+        ];
+        let ParsedArguments {
+            outputs,
+            common_args,
+            extra_hash_files,
+            ..
+        } = match parse_arguments(&args, f.tempdir.path(), &ARGS[..], true, CCompilerKind::GCC) {
+            CompilerArguments::Ok(args) => args,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        let root = f.tempdir.path().join(".");
+        assert_map_contains!(
+            outputs,
+            ("obj", PathBuf::from("foo.o")),
+            ("gcm", root.join("foo.gcm"))
+        );
+        assert_eq!(
+            ovec!["-fmodules-ts", "-fmodule-mapper=modules.map"],
+            common_args
+        );
+        // baz.gcm doesn't exist, so it can't be an input.
+        assert_eq!(vec![root.join("bar.gcm"), mapper], extra_hash_files);
+    }
+
     #[test]
     fn at_signs() {
         let td = tempfile::Builder::new()
@@ -1375,6 +1845,7 @@ mod test {
             preprocessor_args: vec![],
             common_args: vec![],
             extra_hash_files: vec![],
+            module_outputs: vec![],
             msvc_show_includes: false,
             profile_generate: false,
             color_mode: ColorMode::Auto,
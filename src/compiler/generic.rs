@@ -0,0 +1,628 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#[cfg(feature = "dist-client")]
+use crate::compiler::DistPackagers;
+use crate::compiler::{
+    compiler_check_digests, Cacheable, ColorMode, Compilation, CompileCommand, Compiler,
+    CompilerArguments, CompilerHasher, CompilerKind, DetectedCompiler, HashResult,
+};
+use crate::config::GenericCompilerConfig;
+use crate::dist;
+#[cfg(feature = "dist-client")]
+use crate::dist::pkg;
+use crate::mock_command::CommandCreatorSync;
+use crate::util::{hash_all, Digest, HashToDigest};
+use futures::Future;
+use futures_03::executor::ThreadPool;
+use std::borrow::Cow;
+use std::ffi::OsString;
+#[cfg(feature = "dist-client")]
+use std::fs;
+use std::hash::Hash;
+use std::path::{Path, PathBuf};
+
+use crate::errors::*;
+
+/// Version number for cache key.
+const CACHE_VERSION: &[u8] = b"1";
+
+/// A tool described by a `[[compiler.generic]]` section of the
+/// configuration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Generic {
+    /// The path to the tool's executable.
+    pub(crate) executable: PathBuf,
+    /// The digest of the tool's executable.
+    executable_digest: String,
+    /// The description of the tool, restored from the current configuration
+    /// by its name.
+    pub(crate) config: GenericCompilerConfig,
+}
+
+/// A struct on which to hang a `CompilerHasher` impl.
+#[derive(Debug, Clone)]
+pub struct GenericHasher {
+    /// The path to the tool's executable.
+    executable: PathBuf,
+    /// The digest of the tool's executable.
+    executable_digest: String,
+    /// The description of the tool.
+    config: GenericCompilerConfig,
+    /// Parsed arguments from the tool invocation.
+    parsed_args: ParsedArguments,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedArguments {
+    /// The full commandline.
+    arguments: Vec<OsString>,
+    /// The arguments that are hashed, leaving out ignored flags.
+    hashed_arguments: Vec<OsString>,
+    /// The input files.
+    inputs: Vec<PathBuf>,
+    /// The output files, keyed by their position.
+    outputs: Vec<(String, PathBuf)>,
+}
+
+/// A struct on which to hang a `Compilation` impl.
+#[derive(Debug, Clone)]
+pub struct GenericCompilation {
+    /// The path to the tool's executable.
+    executable: PathBuf,
+    /// The full commandline.
+    arguments: Vec<OsString>,
+    /// The output files.
+    outputs: Vec<(String, PathBuf)>,
+    /// The current working directory
+    cwd: PathBuf,
+    /// The environment variables
+    env_vars: Vec<(OsString, OsString)>,
+}
+
+impl Generic {
+    pub fn new<T>(
+        executable: PathBuf,
+        config: GenericCompilerConfig,
+        creator: &T,
+        env_vars: &[(OsString, OsString)],
+        pool: &ThreadPool,
+    ) -> SFuture<Generic>
+    where
+        T: CommandCreatorSync,
+    {
+        let digests = compiler_check_digests(
+            creator,
+            &executable,
+            vec![executable.clone()],
+            &config.compiler_check,
+            env_vars,
+            pool,
+        );
+        Box::new(digests.map(move |digests| Generic {
+            executable,
+            executable_digest: digests.concat(),
+            config,
+        }))
+    }
+}
+
+impl<T> Compiler<T> for Generic
+where
+    T: CommandCreatorSync,
+{
+    fn kind(&self) -> CompilerKind {
+        CompilerKind::Generic(self.config.name.clone())
+    }
+    fn executable(&self) -> &Path {
+        &self.executable
+    }
+    #[cfg(feature = "dist-client")]
+    fn get_toolchain_packager(&self) -> Box<dyn pkg::ToolchainPackager> {
+        Box::new(GenericToolchainPackager)
+    }
+    fn parse_arguments(
+        &self,
+        arguments: &[OsString],
+        cwd: &Path,
+    ) -> CompilerArguments<Box<dyn CompilerHasher<T> + 'static>> {
+        match parse_arguments(arguments, cwd, &self.config) {
+            CompilerArguments::Ok(args) => CompilerArguments::Ok(Box::new(GenericHasher {
+                executable: self.executable.clone(),
+                executable_digest: self.executable_digest.clone(),
+                config: self.config.clone(),
+                parsed_args: args,
+            })),
+            CompilerArguments::NotCompilation => CompilerArguments::NotCompilation,
+            CompilerArguments::CannotCache(why, extra_info) => {
+                CompilerArguments::CannotCache(why, extra_info)
+            }
+            CompilerArguments::Multiple(_) => {
+                CompilerArguments::CannotCache("multiple compilations", None)
+            }
+        }
+    }
+
+    fn detected(&self) -> Option<DetectedCompiler> {
+        Some(DetectedCompiler::Generic(self.clone()))
+    }
+
+    fn box_clone(&self) -> Box<dyn Compiler<T>> {
+        Box::new((*self).clone())
+    }
+}
+
+/// Return whether `arg` is one of `flags`, along with the value it carries
+/// itself if the flag ends in `=`.
+fn match_flag<'a>(arg: &'a str, flags: &[String]) -> Option<Option<&'a str>> {
+    flags.iter().find_map(|flag| {
+        if flag.ends_with('=') {
+            if arg.starts_with(flag.as_str()) {
+                Some(Some(&arg[flag.len()..]))
+            } else {
+                None
+            }
+        } else if arg == flag {
+            Some(None)
+        } else {
+            None
+        }
+    })
+}
+
+/// What the value of an argument is.
+enum ArgKind<'a> {
+    /// An input file, given with a flag or as an argument of its own.
+    Input { flag: bool },
+    /// An output file.
+    Output,
+    /// An output directory, to which these files are written for each input.
+    OutputDir(&'a [String]),
+    /// Something other than a file.
+    Value,
+}
+
+/// The path of an output file in an output directory, described by
+/// `template` for `input`.
+fn output_dir_file(dir: &Path, template: &str, input: &Path) -> PathBuf {
+    let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+    let path = input.with_extension("");
+    let file = template
+        .replace("{stem}", &stem)
+        .replace("{path}", &path.to_string_lossy());
+    dir.join(file)
+}
+
+/// Parse `arguments` as described by `config`. Invocations without outputs,
+/// like `--version`, aren't compilations.
+fn parse_arguments(
+    arguments: &[OsString],
+    cwd: &Path,
+    config: &GenericCompilerConfig,
+) -> CompilerArguments<ParsedArguments> {
+    let mut hashed_arguments = vec![];
+    let mut inputs = vec![];
+    let mut outputs = vec![];
+    let mut sources = vec![];
+    let mut output_dirs = vec![];
+    let mut it = arguments.iter();
+    while let Some(arg) = it.next() {
+        let s = match arg.to_str() {
+            Some(s) => s,
+            None => cannot_cache!("non-UTF-8 argument"),
+        };
+        if s.starts_with('@') {
+            cannot_cache!("@");
+        }
+        if match_flag(s, &config.ignored_flags).is_some() {
+            continue;
+        }
+        hashed_arguments.push(arg.clone());
+        let output_dir = config.output_dirs.iter().find_map(|(flag, files)| {
+            match_flag(s, std::slice::from_ref(flag)).map(|v| (v, files))
+        });
+        let (value, kind) = if let Some(v) = match_flag(s, &config.input_flags) {
+            (v, ArgKind::Input { flag: true })
+        } else if let Some(v) = match_flag(s, &config.output_flags) {
+            (v, ArgKind::Output)
+        } else if let Some((v, files)) = output_dir {
+            // We can't tell which files in an output directory this
+            // invocation wrote unless the configuration says, and restoring
+            // all of them would overwrite the outputs of other invocations
+            // sharing the directory.
+            if files.is_empty() {
+                cannot_cache!("output directory", s.to_owned());
+            }
+            (v, ArgKind::OutputDir(files))
+        } else if let Some(v) = match_flag(s, &config.value_flags) {
+            (v, ArgKind::Value)
+        } else if s.starts_with('-') && s != "-" {
+            continue;
+        } else {
+            (Some(s), ArgKind::Input { flag: false })
+        };
+        let value = match value {
+            Some(value) => PathBuf::from(value),
+            None => match it.next() {
+                Some(next) => {
+                    hashed_arguments.push(next.clone());
+                    PathBuf::from(next)
+                }
+                None => cannot_cache!("missing flag value", s.to_owned()),
+            },
+        };
+        if value.as_os_str() == "-" {
+            match kind {
+                ArgKind::Value => continue,
+                _ => cannot_cache!("stdin or stdout"),
+            }
+        }
+        match kind {
+            ArgKind::Input { flag } => {
+                if !flag {
+                    sources.push(value.clone());
+                }
+                inputs.push(cwd.join(value));
+            }
+            ArgKind::Output => outputs.push(value),
+            ArgKind::OutputDir(files) => output_dirs.push((value, files)),
+            ArgKind::Value => {}
+        }
+    }
+    for (dir, files) in output_dirs {
+        for source in &sources {
+            outputs.extend(files.iter().map(|f| output_dir_file(&dir, f, source)));
+        }
+    }
+    let outputs: Vec<_> = outputs
+        .into_iter()
+        .enumerate()
+        .map(|(i, output)| (format!("output{}", i), output))
+        .collect();
+    if outputs.is_empty() {
+        return CompilerArguments::NotCompilation;
+    }
+    CompilerArguments::Ok(ParsedArguments {
+        arguments: arguments.to_owned(),
+        hashed_arguments,
+        inputs,
+        outputs,
+    })
+}
+
+impl<T> CompilerHasher<T> for GenericHasher
+where
+    T: CommandCreatorSync,
+{
+    fn generate_hash_key(
+        self: Box<Self>,
+        _creator: &T,
+        cwd: PathBuf,
+        env_vars: Vec<(OsString, OsString)>,
+        _may_dist: bool,
+        pool: &ThreadPool,
+        _rewrite_includes_only: bool,
+    ) -> SFuture<HashResult> {
+        let GenericHasher {
+            executable,
+            executable_digest,
+            config,
+            parsed_args:
+                ParsedArguments {
+                    arguments,
+                    hashed_arguments,
+                    inputs,
+                    outputs,
+                },
+        } = *self;
+        trace!("[{}]: generate_hash_key", config.name);
+        Box::new(hash_all(&inputs, pool).map(move |input_hashes| {
+            // If you change any of the inputs to the hash, you should change `CACHE_VERSION`.
+            let mut m = Digest::new();
+            // Hash inputs:
+            // 1. A version
+            m.update(CACHE_VERSION);
+            // 2. The tool's executable
+            m.update(executable_digest.as_bytes());
+            let weak_toolchain_key = m.clone().finish();
+            // 3. The commandline, leaving out ignored flags
+            for arg in &hashed_arguments {
+                arg.hash(&mut HashToDigest { digest: &mut m });
+            }
+            // 4. The digest of all input files
+            for h in input_hashes {
+                m.update(h.as_bytes());
+            }
+            // 5. The environment variables the configuration asks for
+            let mut env_vars = env_vars;
+            env_vars.sort();
+            for (var, val) in env_vars.iter() {
+                if config.env.is_hashed(var, false) {
+                    var.hash(&mut HashToDigest { digest: &mut m });
+                    m.update(&b"="[..]);
+                    val.hash(&mut HashToDigest { digest: &mut m });
+                }
+            }
+            HashResult {
+                key: m.finish(),
+                compilation: Box::new(GenericCompilation {
+                    executable,
+                    arguments,
+                    outputs,
+                    cwd,
+                    env_vars,
+                }),
+                weak_toolchain_key,
+            }
+        }))
+    }
+
+    fn color_mode(&self) -> ColorMode {
+        ColorMode::Auto
+    }
+
+    fn kind(&self) -> CompilerKind {
+        CompilerKind::Generic(self.config.name.clone())
+    }
+
+    fn output_pretty(&self) -> Cow<'_, str> {
+        match self.parsed_args.outputs.first() {
+            Some((_, path)) => path.to_string_lossy(),
+            None => Cow::Borrowed(&self.config.name),
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn CompilerHasher<T>> {
+        Box::new((*self).clone())
+    }
+}
+
+impl Compilation for GenericCompilation {
+    fn generate_compile_commands(
+        &self,
+        _path_transformer: &mut dist::PathTransformer,
+        _rewrite_includes_only: bool,
+    ) -> Result<(CompileCommand, Option<dist::CompileCommand>, Cacheable)> {
+        let command = CompileCommand {
+            executable: self.executable.clone(),
+            arguments: self.arguments.clone(),
+            env_vars: self.env_vars.clone(),
+            cwd: self.cwd.clone(),
+        };
+        // Nothing is known about what the tool needs to run elsewhere.
+        Ok((command, None, Cacheable::Yes))
+    }
+
+    #[cfg(feature = "dist-client")]
+    fn into_dist_packagers(
+        self: Box<Self>,
+        _path_transformer: dist::PathTransformer,
+    ) -> Result<DistPackagers> {
+        bail!("generic compilers are never distributed")
+    }
+
+    fn outputs<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a str, &'a Path)> + 'a> {
+        Box::new(self.outputs.iter().map(|(k, v)| (k.as_str(), &**v)))
+    }
+}
+
+#[cfg(feature = "dist-client")]
+struct GenericToolchainPackager;
+
+#[cfg(feature = "dist-client")]
+impl pkg::ToolchainPackager for GenericToolchainPackager {
+    fn write_pkg(self: Box<Self>, _f: fs::File) -> Result<()> {
+        bail!("generic compilers are never distributed")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::utils::*;
+    use std::collections::BTreeMap;
+
+    fn protoc() -> GenericCompilerConfig {
+        let mut output_dirs = BTreeMap::new();
+        output_dirs.insert(
+            "--cpp_out=".to_owned(),
+            stringvec!["{path}.pb.cc", "{path}.pb.h"],
+        );
+        output_dirs.insert("--python_out=".to_owned(), vec![]);
+        GenericCompilerConfig {
+            name: "protoc".to_owned(),
+            input_flags: stringvec!["--descriptor_set_in="],
+            output_flags: stringvec!["-o"],
+            output_dirs,
+            value_flags: stringvec!["--proto_path"],
+            ignored_flags: stringvec!["--error_format="],
+            ..Default::default()
+        }
+    }
+
+    fn parse_arguments_(arguments: Vec<String>) -> CompilerArguments<ParsedArguments> {
+        let arguments = arguments.iter().map(OsString::from).collect::<Vec<_>>();
+        parse_arguments(&arguments, Path::new("/src"), &protoc())
+    }
+
+    #[test]
+    fn test_parse_arguments() {
+        let parsed = match parse_arguments_(stringvec![
+            "--error_format=msvs",
+            "-o",
+            "bar.desc",
+            "-o",
+            "foo.desc",
+            "--descriptor_set_in=deps.desc",
+            "-I.",
+            "foo.proto"
+        ]) {
+            CompilerArguments::Ok(parsed) => parsed,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(
+            ovec![
+                "-o",
+                "bar.desc",
+                "-o",
+                "foo.desc",
+                "--descriptor_set_in=deps.desc",
+                "-I.",
+                "foo.proto"
+            ],
+            parsed.hashed_arguments
+        );
+        assert_eq!(
+            vec![
+                PathBuf::from("/src/deps.desc"),
+                PathBuf::from("/src/foo.proto")
+            ],
+            parsed.inputs
+        );
+        assert_eq!(
+            vec![
+                ("output0".to_owned(), PathBuf::from("bar.desc")),
+                ("output1".to_owned(), PathBuf::from("foo.desc"))
+            ],
+            parsed.outputs
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_output_dir() {
+        let parsed = match parse_arguments_(stringvec![
+            "--proto_path",
+            ".",
+            "--cpp_out=gen",
+            "protos/foo.proto",
+            "bar.proto"
+        ]) {
+            CompilerArguments::Ok(parsed) => parsed,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(
+            ovec![
+                "--proto_path",
+                ".",
+                "--cpp_out=gen",
+                "protos/foo.proto",
+                "bar.proto"
+            ],
+            parsed.hashed_arguments
+        );
+        assert_eq!(
+            vec![
+                PathBuf::from("/src/protos/foo.proto"),
+                PathBuf::from("/src/bar.proto")
+            ],
+            parsed.inputs
+        );
+        assert_eq!(
+            vec![
+                ("output0".to_owned(), PathBuf::from("gen/protos/foo.pb.cc")),
+                ("output1".to_owned(), PathBuf::from("gen/protos/foo.pb.h")),
+                ("output2".to_owned(), PathBuf::from("gen/bar.pb.cc")),
+                ("output3".to_owned(), PathBuf::from("gen/bar.pb.h"))
+            ],
+            parsed.outputs
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_not_compilation() {
+        assert_eq!(
+            CompilerArguments::NotCompilation,
+            parse_arguments_(stringvec!["--version"])
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_cannot_cache() {
+        assert_eq!(
+            CompilerArguments::CannotCache("@", None),
+            parse_arguments_(stringvec!["@args.txt"])
+        );
+        assert_eq!(
+            CompilerArguments::CannotCache("missing flag value", Some("-o".to_owned())),
+            parse_arguments_(stringvec!["foo.proto", "-o"])
+        );
+        assert_eq!(
+            CompilerArguments::CannotCache("stdin or stdout", None),
+            parse_arguments_(stringvec!["foo.proto", "-o", "-"])
+        );
+        assert_eq!(
+            CompilerArguments::CannotCache("output directory", Some("--python_out=gen".to_owned())),
+            parse_arguments_(stringvec!["--python_out=gen", "foo.proto"])
+        );
+    }
+
+    #[test]
+    fn test_generate_hash_key() {
+        let f = TestFixture::new();
+        let pool = ThreadPool::sized(1);
+        let creator = new_creator();
+        f.touch("foo.proto").unwrap();
+        let mut config = protoc();
+        config.env.include = stringvec!["PROTOC_*"];
+        let hash_key = |env: &[(&str, &str)], arguments: Vec<String>| {
+            let arguments = arguments.iter().map(OsString::from).collect::<Vec<_>>();
+            let parsed_args = match parse_arguments(&arguments, f.tempdir.path(), &config) {
+                CompilerArguments::Ok(parsed) => parsed,
+                o => panic!("Got unexpected parse result: {:?}", o),
+            };
+            let hasher = Box::new(GenericHasher {
+                executable: "protoc".into(),
+                executable_digest: "abcd".to_owned(),
+                config: config.clone(),
+                parsed_args,
+            });
+            let env = env
+                .iter()
+                .map(|(k, v)| (OsString::from(k), OsString::from(v)))
+                .collect();
+            hasher
+                .generate_hash_key(
+                    &creator,
+                    f.tempdir.path().to_owned(),
+                    env,
+                    false,
+                    &pool,
+                    false,
+                )
+                .wait()
+                .unwrap()
+                .key
+        };
+        let key = hash_key(&[], stringvec!["-o", "foo.desc", "foo.proto"]);
+        assert_eq!(
+            key,
+            hash_key(
+                &[("HOME", "/home")],
+                stringvec!["-o", "foo.desc", "foo.proto", "--error_format=gcc"]
+            )
+        );
+        assert_ne!(
+            key,
+            hash_key(
+                &[("PROTOC_FLAGS", "x")],
+                stringvec!["-o", "foo.desc", "foo.proto"]
+            )
+        );
+        f.touch("bar.proto").unwrap();
+        assert_ne!(
+            key,
+            hash_key(&[], stringvec!["-o", "foo.desc", "bar.proto"])
+        );
+    }
+}
@@ -0,0 +1,372 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compilers found by `detect_compiler`, stored on disk so that a new server
+//! doesn't need to run and hash every compiler again.
+//!
+//! An entry is only used while the files the compiler was identified by
+//! keep their size and modification time, and while the configuration and
+//! the version of sccache stay the same.
+
+use crate::compiler::c::{CCompiler, CCompilerImpl};
+use crate::compiler::clang::Clang;
+use crate::compiler::diab::Diab;
+use crate::compiler::emscripten::Emscripten;
+use crate::compiler::fortran::Fortran;
+use crate::compiler::gcc::GCC;
+use crate::compiler::generic::Generic;
+use crate::compiler::msvc::MSVC;
+use crate::compiler::nvcc::NVCC;
+use crate::compiler::rust::{Rust, RustupProxy};
+use crate::compiler::rustdoc::{Rustdoc, RustdocProxy};
+use crate::compiler::{Compiler, CompilerProxy};
+use crate::config::CompilerConfigs;
+use crate::mock_command::CommandCreatorSync;
+use crate::util::Digest;
+use filetime::FileTime;
+use futures::Future;
+use futures_03::executor::ThreadPool;
+use std::ffi::OsString;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+use crate::errors::*;
+
+/// A compiler as stored in the compiler info cache. The configuration it
+/// was created with isn't stored; the current one is used instead.
+#[derive(Clone, Serialize, Deserialize)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum DetectedCompiler {
+    GCC(CCompiler<GCC>),
+    Clang(CCompiler<Clang>),
+    MSVC(CCompiler<MSVC>),
+    NVCC(CCompiler<NVCC>),
+    Diab(CCompiler<Diab>),
+    Emscripten(CCompiler<Emscripten>),
+    Fortran(CCompiler<Fortran>),
+    Rust(Rust),
+    Rustdoc(Rustdoc),
+    Generic(Generic),
+}
+
+impl DetectedCompiler {
+    /// The files the compiler was identified by, its executable first.
+    fn files(&self) -> Result<Vec<PathBuf>> {
+        Ok(match self {
+            DetectedCompiler::GCC(c) => vec![c.executable.clone()],
+            DetectedCompiler::Clang(c) => vec![c.executable.clone()],
+            DetectedCompiler::MSVC(c) => vec![c.executable.clone()],
+            DetectedCompiler::NVCC(c) => vec![c.executable.clone()],
+            DetectedCompiler::Diab(c) => vec![c.executable.clone()],
+            DetectedCompiler::Emscripten(c) => vec![c.executable.clone()],
+            DetectedCompiler::Fortran(c) => vec![c.executable.clone()],
+            DetectedCompiler::Rust(rust) => rust.detection_files()?,
+            DetectedCompiler::Rustdoc(rustdoc) => {
+                let mut files = vec![rustdoc.executable.clone()];
+                files.extend(rustdoc.rustc.detection_files()?);
+                files
+            }
+            DetectedCompiler::Generic(generic) => vec![generic.executable.clone()],
+        })
+    }
+
+    /// Turn this back into a compiler, with the current `config`.
+    pub fn into_compiler<T>(
+        self,
+        config: &CompilerConfigs,
+        env_vars: &[(OsString, OsString)],
+        pool: &ThreadPool,
+    ) -> SFuture<Box<dyn Compiler<T>>>
+    where
+        T: CommandCreatorSync,
+    {
+        fn c_compiler<T, I>(
+            mut c: CCompiler<I>,
+            config: &CompilerConfigs,
+        ) -> SFuture<Box<dyn Compiler<T>>>
+        where
+            T: CommandCreatorSync,
+            I: CCompilerImpl,
+        {
+            c.config = config.c.clone();
+            f_ok(Box::new(c))
+        }
+
+        match self {
+            DetectedCompiler::GCC(c) => c_compiler(c, config),
+            DetectedCompiler::Clang(c) => c_compiler(c, config),
+            DetectedCompiler::MSVC(c) => c_compiler(c, config),
+            DetectedCompiler::NVCC(c) => c_compiler(c, config),
+            DetectedCompiler::Diab(c) => c_compiler(c, config),
+            DetectedCompiler::Emscripten(c) => c_compiler(c, config),
+            DetectedCompiler::Fortran(c) => c_compiler(c, config),
+            DetectedCompiler::Rust(rust) => Box::new(
+                rust.restore(config.rust.clone(), env_vars, pool)
+                    .map(|rust| Box::new(rust) as Box<dyn Compiler<T>>),
+            ),
+            DetectedCompiler::Rustdoc(mut rustdoc) => {
+                rustdoc.rustc.config = config.rust.clone();
+                f_ok(Box::new(rustdoc))
+            }
+            DetectedCompiler::Generic(mut generic) => {
+                let name = generic.config.name.clone();
+                match config.generic.iter().find(|g| g.name == name) {
+                    Some(generic_config) => {
+                        generic.config = generic_config.clone();
+                        f_ok(Box::new(generic))
+                    }
+                    None => f_err(anyhow!("generic compiler {} is no longer configured", name)),
+                }
+            }
+        }
+    }
+}
+
+/// A compiler proxy as stored in the compiler info cache, by the path of the
+/// proxy executable.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum DetectedProxy {
+    Rustup(PathBuf),
+    Rustdoc(PathBuf),
+}
+
+impl DetectedProxy {
+    /// Turn this back into a proxy.
+    pub fn into_proxy<T>(self) -> Result<Box<dyn CompilerProxy<T>>>
+    where
+        T: CommandCreatorSync,
+    {
+        Ok(match self {
+            DetectedProxy::Rustup(path) => Box::new(RustupProxy::new(path)?),
+            DetectedProxy::Rustdoc(path) => Box::new(RustdocProxy::new(RustupProxy::new(path)?)),
+        })
+    }
+}
+
+/// The size and modification time of a file, which tell whether it changed.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct FileStamp {
+    path: PathBuf,
+    size: u64,
+    mtime_secs: i64,
+    mtime_nanos: u32,
+}
+
+impl FileStamp {
+    fn new(path: &Path) -> Result<FileStamp> {
+        let attr = fs::metadata(path)?;
+        let mtime = FileTime::from_last_modification_time(&attr);
+        Ok(FileStamp {
+            path: path.to_owned(),
+            size: attr.len(),
+            mtime_secs: mtime.unix_seconds(),
+            mtime_nanos: mtime.nanoseconds(),
+        })
+    }
+
+    fn is_current(&self) -> bool {
+        FileStamp::new(&self.path).ok().as_ref() == Some(self)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[allow(clippy::large_enum_variant)]
+enum Detected {
+    Compiler {
+        compiler: DetectedCompiler,
+        /// The output digest of a `compiler_check` command.
+        check_digests: Option<Vec<String>>,
+    },
+    Proxy(DetectedProxy),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    /// The digest of the sccache version and configuration the entry was
+    /// made with.
+    config_digest: String,
+    /// The path the entry is for.
+    path: PathBuf,
+    /// The files the entry depends on.
+    files: Vec<FileStamp>,
+    detected: Detected,
+}
+
+/// A directory holding a file of detection results for each compiler path.
+pub struct CompilerInfoCache {
+    dir: PathBuf,
+    config_digest: String,
+}
+
+impl CompilerInfoCache {
+    pub fn new(dir: PathBuf, config: &CompilerConfigs) -> CompilerInfoCache {
+        let mut m = Digest::new();
+        m.update(env!("CARGO_PKG_VERSION").as_bytes());
+        m.update(&serde_json::to_vec(config).expect("compiler configs are serializable"));
+        CompilerInfoCache {
+            dir,
+            config_digest: m.finish(),
+        }
+    }
+
+    /// Return the compiler stored for the executable at `path`, along with
+    /// the output digest of its `compiler_check` command, if still valid.
+    pub fn get_compiler(&self, path: &Path) -> Option<(DetectedCompiler, Option<Vec<String>>)> {
+        match self.get(path) {
+            Some(Detected::Compiler {
+                compiler,
+                check_digests,
+            }) => Some((compiler, check_digests)),
+            _ => None,
+        }
+    }
+
+    /// Return the proxy stored for the executable at `path`, if still valid.
+    pub fn get_proxy(&self, path: &Path) -> Option<DetectedProxy> {
+        match self.get(path) {
+            Some(Detected::Proxy(proxy)) => Some(proxy),
+            _ => None,
+        }
+    }
+
+    /// Store `compiler`, by the path of its executable.
+    pub fn put_compiler(&self, compiler: DetectedCompiler, check_digests: Option<Vec<String>>) {
+        let files = match compiler.files() {
+            Ok(files) => files,
+            Err(e) => {
+                debug!("Not storing compiler info: {}", e);
+                return;
+            }
+        };
+        let path = files[0].clone();
+        let detected = Detected::Compiler {
+            compiler,
+            check_digests,
+        };
+        self.put(&path, files, detected);
+    }
+
+    /// Store `proxy`, found at `path`.
+    pub fn put_proxy(&self, path: &Path, proxy: DetectedProxy) {
+        self.put(path, vec![path.to_owned()], Detected::Proxy(proxy));
+    }
+
+    fn entry_path(&self, path: &Path) -> PathBuf {
+        let mut m = Digest::new();
+        m.update(path.to_string_lossy().as_bytes());
+        self.dir.join(format!("{}.json", m.finish()))
+    }
+
+    fn get(&self, path: &Path) -> Option<Detected> {
+        let entry_path = self.entry_path(path);
+        let entry: Entry = match fs::read(&entry_path)
+            .map_err(Error::from)
+            .and_then(|bytes| serde_json::from_slice(&bytes).map_err(Error::from))
+        {
+            Ok(entry) => entry,
+            Err(e) => {
+                trace!("No compiler info for {:?}: {}", path, e);
+                return None;
+            }
+        };
+        if entry.config_digest != self.config_digest
+            || entry.path != path
+            || !entry.files.iter().all(FileStamp::is_current)
+        {
+            debug!("Stale compiler info for {:?}", path);
+            return None;
+        }
+        Some(entry.detected)
+    }
+
+    fn put(&self, path: &Path, files: Vec<PathBuf>, detected: Detected) {
+        let res = files
+            .iter()
+            .map(|file| FileStamp::new(file))
+            .collect::<Result<Vec<_>>>()
+            .and_then(|files| {
+                let entry = Entry {
+                    config_digest: self.config_digest.clone(),
+                    path: path.to_owned(),
+                    files,
+                    detected,
+                };
+                fs::create_dir_all(&self.dir)?;
+                // Write to a temporary file first, so that other servers
+                // never read a partial entry.
+                let mut tmp = NamedTempFile::new_in(&self.dir)?;
+                tmp.write_all(&serde_json::to_vec(&entry)?)?;
+                tmp.persist(self.entry_path(path))?;
+                Ok(())
+            });
+        if let Err(e) = res {
+            warn!("Failed to store compiler info for {:?}: {}", path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::c::CCompilerKind;
+    use crate::compiler::{get_compiler_info, CompilerKind};
+    use crate::config::Sloppiness;
+    use crate::mock_command::*;
+    use crate::test::utils::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_compiler_info_cache() {
+        let f = TestFixture::new();
+        let pool = ThreadPool::sized(1);
+        let creator = new_creator();
+        let config = CompilerConfigs::default();
+        let cache = CompilerInfoCache::new(f.tempdir.path().join("info"), &config);
+        let gcc = f.mk_bin("gcc").unwrap();
+        next_command(&creator, Ok(MockChild::new(exit_status(0), "gcc", "")));
+        let c = get_compiler_info(creator, &gcc, f.tempdir.path(), &[], &pool, None, &config)
+            .wait()
+            .unwrap()
+            .0;
+        assert!(cache.get_compiler(&gcc).is_none());
+        cache.put_compiler(c.detected().unwrap(), Some(stringvec!["abcd"]));
+
+        // The entry is used while nothing changes.
+        let (detected, check_digests) = cache.get_compiler(&gcc).unwrap();
+        assert_eq!(Some(stringvec!["abcd"]), check_digests);
+        let restored = detected
+            .into_compiler::<Arc<Mutex<MockCommandCreator>>>(&config, &[], &pool)
+            .wait()
+            .unwrap();
+        assert_eq!(CompilerKind::C(CCompilerKind::GCC), restored.kind());
+        assert!(cache.get_proxy(&gcc).is_none());
+
+        // Other configurations don't use it.
+        let mut other_config = config.clone();
+        other_config.c.sloppiness = vec![Sloppiness::SystemHeaders];
+        let other = CompilerInfoCache::new(f.tempdir.path().join("info"), &other_config);
+        assert!(other.get_compiler(&gcc).is_none());
+
+        // Nor does a changed compiler.
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&gcc)
+            .unwrap()
+            .write_all(b"new")
+            .unwrap();
+        assert!(cache.get_compiler(&gcc).is_none());
+    }
+}
@@ -20,9 +20,15 @@ mod clang;
 #[allow(clippy::module_inception)]
 mod compiler;
 mod diab;
+mod emscripten;
+mod fortran;
 mod gcc;
+mod generic;
+mod info_cache;
 mod msvc;
 mod nvcc;
 mod rust;
+mod rustdoc;
 
 pub use crate::compiler::compiler::*;
+pub use crate::compiler::info_cache::{CompilerInfoCache, DetectedCompiler, DetectedProxy};
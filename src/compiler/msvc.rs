@@ -13,9 +13,12 @@
 // limitations under the License.
 
 use crate::compiler::args::*;
-use crate::compiler::c::{CCompilerImpl, CCompilerKind, Language, ParsedArguments};
+use crate::compiler::c::{
+    parse_each_input, CCompiler, CCompilerImpl, CCompilerKind, Language, ParsedArguments,
+};
 use crate::compiler::{
     clang, gcc, write_temp_file, Cacheable, ColorMode, CompileCommand, CompilerArguments,
+    DetectedCompiler,
 };
 use crate::dist;
 use crate::mock_command::{CommandCreatorSync, RunCommand};
@@ -36,7 +39,7 @@ use crate::errors::*;
 /// A struct on which to implement `CCompilerImpl`.
 ///
 /// Needs a little bit of state just to persist `includes_prefix`.
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct MSVC {
     /// The prefix used in the output of `-showIncludes`.
     pub includes_prefix: String,
@@ -50,6 +53,9 @@ impl CCompilerImpl for MSVC {
     fn plusplus(&self) -> bool {
         false
     }
+    fn detected(compiler: &CCompiler<Self>) -> DetectedCompiler {
+        DetectedCompiler::MSVC(compiler.clone())
+    }
     fn parse_arguments(
         &self,
         arguments: &[OsString],
@@ -446,8 +452,10 @@ pub fn parse_arguments(
     cwd: &Path,
     is_clang: bool,
 ) -> CompilerArguments<ParsedArguments> {
+    let mut all_args = vec![];
     let mut output_arg = None;
     let mut input_arg = None;
+    let mut multiple_input = false;
     let mut common_args = vec![];
     let mut preprocessor_args = vec![];
     let mut dependency_args = vec![];
@@ -464,6 +472,7 @@ pub fn parse_arguments(
 
     for arg in ArgsIter::new(arguments.iter().cloned(), (&ARGS[..], &SLASH_ARGS[..])) {
         let arg = try_or_cannot_cache!(arg, "argument parse");
+        all_args.push(arg.clone());
         match arg.get_data() {
             Some(PassThrough) | Some(PassThroughWithPath(_)) | Some(PassThroughWithSuffix(_)) => {}
             Some(TooHardFlag) | Some(TooHard(_)) | Some(TooHardPath(_)) => {
@@ -500,19 +509,16 @@ pub fn parse_arguments(
             }
             Some(XClang(s)) => xclangs.push(s.clone()),
             Some(Clang(s)) => clangs.push(s.clone()),
-            None => {
-                match arg {
-                    Argument::Raw(ref val) => {
-                        if input_arg.is_some() {
-                            // Can't cache compilations with multiple inputs.
-                            cannot_cache!("multiple input files");
-                        }
-                        input_arg = Some(val.clone());
+            None => match arg {
+                Argument::Raw(ref val) => {
+                    if input_arg.is_some() {
+                        multiple_input = true;
                     }
-                    Argument::UnknownFlag(ref flag) => common_args.push(flag.clone()),
-                    _ => unreachable!(),
+                    input_arg = Some(val.clone());
                 }
-            }
+                Argument::UnknownFlag(ref flag) => common_args.push(flag.clone()),
+                _ => unreachable!(),
+            },
         }
         match arg.get_data() {
             Some(PreprocessorArgument(_)) | Some(PreprocessorArgumentPath(_)) => preprocessor_args
@@ -544,7 +550,8 @@ pub fn parse_arguments(
             // -MP tells the compiler to build with multiple processes and is used
             // to spread multiple compilations when there are multiple inputs.
             // Either we have multiple inputs on the command line, and we're going
-            // to bail out and not cache, or -MP is not going to be useful.
+            // to split them into one compilation each, or -MP is not going to be
+            // useful.
             // -MP also implies -FS.
             //
             // -FS forces synchronous access to PDB files via a MSPDBSRV process.
@@ -585,9 +592,23 @@ pub fn parse_arguments(
             // Eagerly bail if it looks like we need to do more complicated work
             use crate::compiler::gcc::ArgData::*;
             let mut args = match arg.get_data() {
-                Some(SplitDwarf) | Some(TestCoverage) | Some(Coverage) | Some(DoCompilation)
-                | Some(PedanticFlag) | Some(Standard(_)) | Some(Language(_)) | Some(Output(_))
-                | Some(TooHardFlag) | Some(XClang(_)) | Some(TooHard(_)) => cannot_cache!(arg
+                Some(SplitDwarf)
+                | Some(TestCoverage)
+                | Some(Coverage)
+                | Some(DoCompilation)
+                | Some(PedanticFlag)
+                | Some(Standard(_))
+                | Some(Language(_))
+                | Some(Output(_))
+                | Some(TooHardFlag)
+                | Some(XClang(_))
+                | Some(TooHard(_))
+                | Some(Modules)
+                | Some(NoImplicitModules)
+                | Some(ModulesTs)
+                | Some(ModuleMapper(_))
+                | Some(ModuleFile(_))
+                | Some(ModuleOutput(_)) => cannot_cache!(arg
                     .flag_str()
                     .unwrap_or("Can't handle complex arguments through clang",)),
                 None => match arg {
@@ -633,10 +654,28 @@ pub fn parse_arguments(
     if !compilation {
         return CompilerArguments::NotCompilation;
     }
+    // Compilations with multiple inputs are split into one compilation per
+    // input, unless the inputs would share an output.
+    if multiple_input {
+        if output_arg.is_some() || depfile.is_some() || debug_info {
+            cannot_cache!("multiple input files");
+        }
+        return parse_each_input(&all_args, |arguments| {
+            parse_arguments(arguments, cwd, is_clang)
+        });
+    }
     let (input, language) = match input_arg {
         Some(i) => match Language::from_file_name(Path::new(&i)) {
+            // cl.exe doesn't compile assembly, preprocessed or Fortran
+            // sources by extension.
+            Some(Language::Assembler)
+            | Some(Language::AssemblerWithCpp)
+            | Some(Language::PreprocessedC)
+            | Some(Language::PreprocessedCxx)
+            | Some(Language::Fortran)
+            | Some(Language::FortranWithCpp)
+            | None => cannot_cache!("unknown source language"),
             Some(l) => (i.to_owned(), l),
-            None => cannot_cache!("unknown source language"),
         },
         // We can't cache compilation without an input.
         None => cannot_cache!("no input file"),
@@ -675,6 +714,7 @@ pub fn parse_arguments(
         preprocessor_args,
         common_args,
         extra_hash_files,
+        module_outputs: vec![],
         msvc_show_includes: show_includes,
         profile_generate,
         // FIXME: implement color_mode for msvc.
@@ -1262,6 +1302,30 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_parse_arguments_multiple_inputs() {
+        let args = ovec!["-c", "foo.c", "-Ibar", "bar.cpp", "-MP"];
+        let parsed = match parse_arguments(args) {
+            CompilerArguments::Multiple(parsed) => parsed,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(2, parsed.len());
+        assert_eq!(Some("foo.c"), parsed[0].input.to_str());
+        assert_eq!(Language::C, parsed[0].language);
+        assert_map_contains!(parsed[0].outputs, ("obj", PathBuf::from("foo.obj")));
+        assert_eq!(Some("bar.cpp"), parsed[1].input.to_str());
+        assert_eq!(Language::Cxx, parsed[1].language);
+        assert_map_contains!(parsed[1].outputs, ("obj", PathBuf::from("bar.obj")));
+        for p in &parsed {
+            assert_eq!(ovec!["-Ibar"], p.preprocessor_args);
+            assert!(p.common_args.is_empty());
+        }
+        assert_eq!(
+            CompilerArguments::CannotCache("multiple input files", None),
+            parse_arguments(ovec!["-c", "foo.c", "bar.c", "-Zi", "-Fdfoo.pdb"])
+        );
+    }
+
     #[test]
     fn test_parse_arguments_unsupported() {
         assert_eq!(
@@ -1318,6 +1382,7 @@ mod test {
             preprocessor_args: vec![],
             common_args: vec![],
             extra_hash_files: vec![],
+            module_outputs: vec![],
             msvc_show_includes: false,
             profile_generate: false,
             color_mode: ColorMode::Auto,
@@ -1362,6 +1427,7 @@ mod test {
             preprocessor_args: vec![],
             common_args: vec![],
             extra_hash_files: vec![],
+            module_outputs: vec![],
             msvc_show_includes: false,
             profile_generate: false,
             color_mode: ColorMode::Auto,
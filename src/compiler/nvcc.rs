@@ -15,9 +15,11 @@
 #![allow(unused_imports, dead_code, unused_variables)]
 
 use crate::compiler::args::*;
-use crate::compiler::c::{CCompilerImpl, CCompilerKind, Language, ParsedArguments};
+use crate::compiler::c::{CCompiler, CCompilerImpl, CCompilerKind, Language, ParsedArguments};
 use crate::compiler::gcc::ArgData::*;
-use crate::compiler::{gcc, write_temp_file, Cacheable, CompileCommand, CompilerArguments};
+use crate::compiler::{
+    gcc, write_temp_file, Cacheable, CompileCommand, CompilerArguments, DetectedCompiler,
+};
 use crate::dist;
 use crate::mock_command::{CommandCreator, CommandCreatorSync, RunCommand};
 use crate::util::{run_input_output, OsStrExt};
@@ -32,7 +34,7 @@ use std::process;
 use crate::errors::*;
 
 /// A unit struct on which to implement `CCompilerImpl`.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct NVCC;
 
 impl CCompilerImpl for NVCC {
@@ -42,6 +44,9 @@ impl CCompilerImpl for NVCC {
     fn plusplus(&self) -> bool {
         false
     }
+    fn detected(compiler: &CCompiler<Self>) -> DetectedCompiler {
+        DetectedCompiler::NVCC(compiler.clone())
+    }
     fn parse_arguments(
         &self,
         arguments: &[OsString],
@@ -72,9 +77,16 @@ impl CCompilerImpl for NVCC {
         let language = match parsed_args.language {
             Language::C => "c",
             Language::Cxx => "c++",
+            Language::CxxModule => "c++-module",
             Language::ObjectiveC => "objective-c",
             Language::ObjectiveCxx => "objective-c++",
             Language::Cuda => "cu",
+            Language::Assembler => "assembler",
+            Language::AssemblerWithCpp => "assembler-with-cpp",
+            Language::PreprocessedC => "cpp-output",
+            Language::PreprocessedCxx => "c++-cpp-output",
+            Language::Fortran => "f95",
+            Language::FortranWithCpp => "f95-cpp-input",
         };
 
         let initialize_cmd_and_args = || {
@@ -229,6 +241,24 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_parse_arguments_cxx_module() {
+        assert_eq!(
+            CompilerArguments::CannotCache("c++ module interface", None),
+            parse_arguments_(stringvec!["-c", "foo.cppm", "-o", "foo.o"])
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_unsupported_language() {
+        for input in &["foo.s", "foo.S", "foo.i", "foo.ii"] {
+            assert_eq!(
+                CompilerArguments::CannotCache("nvcc input language", None),
+                parse_arguments_(stringvec!["-c", *input, "-o", "foo.o"])
+            );
+        }
+    }
+
     #[test]
     fn test_parse_arguments_simple_c() {
         let a = parses!("-c", "foo.c", "-o", "foo.o");
@@ -14,11 +14,13 @@
 
 use crate::compiler::args::*;
 use crate::compiler::{
-    Cacheable, ColorMode, Compilation, CompileCommand, Compiler, CompilerArguments, CompilerHasher,
-    CompilerKind, CompilerProxy, HashResult,
+    compiler_check_digests, Cacheable, ColorMode, Compilation, CompileCommand, Compiler,
+    CompilerArguments, CompilerHasher, CompilerKind, CompilerProxy, DetectedCompiler,
+    DetectedProxy, HashResult,
 };
 #[cfg(feature = "dist-client")]
 use crate::compiler::{DistPackagers, OutputsRewriter};
+use crate::config::{IncrementalPolicy, RustCompilerConfig};
 use crate::dist;
 #[cfg(feature = "dist-client")]
 use crate::dist::pkg;
@@ -38,10 +40,9 @@ use std::borrow::Cow;
 #[cfg(feature = "dist-client")]
 use std::collections::hash_map::RandomState;
 use std::collections::{HashMap, HashSet};
-use std::env::consts::DLL_EXTENSION;
-#[cfg(feature = "dist-client")]
-use std::env::consts::{DLL_PREFIX, EXE_EXTENSION};
-use std::ffi::OsString;
+use std::env;
+use std::env::consts::{DLL_EXTENSION, DLL_PREFIX, EXE_EXTENSION};
+use std::ffi::{OsStr, OsString};
 use std::fmt;
 use std::fs;
 use std::hash::Hash;
@@ -65,6 +66,10 @@ const RLIB_EXTENSION: &str = "rlib";
 #[cfg(feature = "dist-client")]
 const RMETA_EXTENSION: &str = "rmeta";
 
+/// Names of the archive member holding the crate metadata in an rlib, in
+/// current and older versions of rustc.
+const RLIB_METADATA_MEMBERS: &[&[u8]] = &[b"lib.rmeta", b"rust.metadata.bin"];
+
 /// Directory in the sysroot containing binary to which rustc is linked.
 #[cfg(feature = "dist-client")]
 const BINS_DIR: &str = "bin";
@@ -78,19 +83,29 @@ const LIBS_DIR: &str = "lib";
 const LIBS_DIR: &str = "bin";
 
 /// A struct on which to hang a `Compiler` impl.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Rust {
     /// The path to the rustc executable.
-    executable: PathBuf,
+    pub(crate) executable: PathBuf,
     /// The host triple for this rustc.
     host: String,
+    /// The first line of `rustc -vV`, e.g. `rustc 1.50.0 (cb75ad5db 2021-02-10)`.
+    #[serde(default)]
+    pub(crate) version: Option<String>,
     /// The path to the rustc sysroot.
     sysroot: PathBuf,
+    /// The built-in targets, from `rustc --print target-list`.
+    #[serde(default)]
+    pub(crate) builtin_targets: Vec<String>,
     /// The digests of all the shared libraries in rustc's $sysroot/lib (or /bin on Windows).
-    compiler_shlibs_digests: Vec<String>,
+    pub(crate) compiler_shlibs_digests: Vec<String>,
     /// A shared, caching reader for rlib dependencies
     #[cfg(feature = "dist-client")]
+    #[serde(skip)]
     rlib_dep_reader: Option<Arc<RlibDepReader>>,
+    /// Rust settings from the configuration, restored rather than stored.
+    #[serde(skip)]
+    pub(crate) config: RustCompilerConfig,
 }
 
 /// A struct on which to hang a `CompilerHasher` impl.
@@ -100,13 +115,19 @@ pub struct RustHasher {
     executable: PathBuf,
     /// The host triple for this rustc.
     host: String,
+    /// The first line of `rustc -vV`.
+    version: Option<String>,
     /// The path to the rustc sysroot.
     sysroot: PathBuf,
+    /// The built-in targets, from `rustc --print target-list`.
+    builtin_targets: Vec<String>,
     /// The digests of all the shared libraries in rustc's $sysroot/lib (or /bin on Windows).
     compiler_shlibs_digests: Vec<String>,
     /// A shared, caching reader for rlib dependencies
     #[cfg(feature = "dist-client")]
     rlib_dep_reader: Option<Arc<RlibDepReader>>,
+    /// Rust settings from the configuration.
+    config: RustCompilerConfig,
     /// Parsed arguments from the rustc invocation
     parsed_args: ParsedArguments,
 }
@@ -130,6 +151,12 @@ pub struct ParsedArguments {
     crate_link_paths: Vec<PathBuf>,
     /// Static libraries linked to in the compile.
     staticlibs: Vec<PathBuf>,
+    /// Other native libraries and files passed to the linker, if the crate is linked.
+    link_inputs: Vec<PathBuf>,
+    /// The linker passed with `-C linker`.
+    linker: Option<PathBuf>,
+    /// The target passed with `--target`.
+    target: Option<ArgTarget>,
     /// The crate name passed to --crate-name.
     crate_name: String,
     /// The crate types that will be generated
@@ -143,6 +170,11 @@ pub struct ParsedArguments {
     color_mode: ColorMode,
     /// Whether `--json` was passed to this invocation.
     has_json: bool,
+    /// The `-C incremental` argument left out of `arguments`, to compile
+    /// with on a cache miss.
+    incremental: Option<Argument<ArgData>>,
+    /// The options deciding which debuginfo files the linker writes.
+    debuginfo: DebugInfoOptions,
 }
 
 /// A struct on which to hang a `Compilation` impl.
@@ -169,19 +201,72 @@ pub struct RustCompilation {
     crate_name: String,
     /// The crate types that will be generated
     crate_types: CrateTypes,
+    /// The spec of a custom target, if any.
+    target_spec: Option<PathBuf>,
     /// If dependency info is being emitted, the name of the dep info file.
     dep_info: Option<PathBuf>,
     /// The current working directory
     cwd: PathBuf,
     /// The environment variables
     env_vars: Vec<(OsString, OsString)>,
+    /// The `-C incremental` argument to compile with, without caching.
+    incremental: Option<Argument<ArgData>>,
 }
 
 // The selection of crate types for this compilation
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Default)]
 pub struct CrateTypes {
     rlib: bool,
     staticlib: bool,
+    bin: bool,
+    dylib: bool,
+    cdylib: bool,
+    proc_macro: bool,
+}
+
+impl CrateTypes {
+    /// Whether rustc runs the linker to produce any of these crate types.
+    fn needs_linker(&self) -> bool {
+        self.bin || self.dylib || self.cdylib || self.proc_macro
+    }
+}
+
+/// The `-C` options deciding which debuginfo files are written next to a
+/// linked crate.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DebugInfoOptions {
+    /// Whether `-C debuginfo` asks for any debuginfo.
+    enabled: bool,
+    /// The value of `-C split-debuginfo`, if passed.
+    split: Option<String>,
+    /// Whether `-C strip` removes debuginfo.
+    stripped: bool,
+}
+
+impl DebugInfoOptions {
+    /// The debuginfo written next to `output`, a linked crate built for
+    /// `triple`, which `--print file-names` doesn't list. A name ending in
+    /// `/` is a directory, which is only cached if it exists.
+    fn outputs(&self, output: &str, triple: &str) -> Vec<String> {
+        let path = Path::new(output);
+        let with_extension = |ext| path.with_extension(ext).to_string_lossy().into_owned();
+        if triple.contains("-msvc") {
+            // The MSVC linker always writes a PDB unless debuginfo is stripped.
+            match path.extension() {
+                Some(ext) if !self.stripped && (ext == "exe" || ext == "dll") => {
+                    vec![with_extension("pdb")]
+                }
+                _ => vec![],
+            }
+        } else if triple.contains("-apple-") {
+            // dsymutil only runs for packed debuginfo, the default.
+            vec![format!("{}.dSYM/", output)]
+        } else if self.enabled && self.split.as_deref() == Some("packed") {
+            vec![with_extension("dwp")]
+        } else {
+            vec![]
+        }
+    }
 }
 
 lazy_static! {
@@ -194,10 +279,10 @@ lazy_static! {
 }
 
 /// Version number for cache key.
-const CACHE_VERSION: &[u8] = b"6";
+const CACHE_VERSION: &[u8] = b"7";
 
 /// Get absolute paths for all source files listed in rustc's dep-info output.
-fn get_source_files<T>(
+pub(crate) fn get_source_files<T>(
     creator: &T,
     crate_name: &str,
     executable: &Path,
@@ -205,12 +290,13 @@ fn get_source_files<T>(
     cwd: &Path,
     env_vars: &[(OsString, OsString)],
     pool: &ThreadPool,
-) -> SFuture<Vec<PathBuf>>
+) -> SFuture<(Vec<PathBuf>, Vec<EnvDep>)>
 where
     T: CommandCreatorSync,
 {
     let start = time::Instant::now();
-    // Get the full list of source files from rustc's dep-info.
+    // Get the full list of source files, and the environment variables read
+    // by `env!` and `option_env!`, from rustc's dep-info.
     let temp_dir = ftry!(tempfile::Builder::new()
         .prefix("sccache")
         .tempdir()
@@ -236,23 +322,28 @@ where
             parse_dep_file(&dep_file, &cwd)
                 .with_context(|| format!("Failed to parse dep info for {}", name2))
         });
-        Box::new(parsed.map(move |files| {
+        Box::new(parsed.map(move |(files, env_deps)| {
             trace!(
-                "[{}]: got {} source files from dep-info in {}",
+                "[{}]: got {} source files and {} env deps from dep-info in {}",
                 crate_name,
                 files.len(),
+                env_deps.len(),
                 fmt_duration_as_secs(&start.elapsed())
             );
             // Just to make sure we capture temp_dir.
             drop(temp_dir);
-            files
+            (files, env_deps)
         }))
     }))
 }
 
-/// Parse dependency info from `file` and return a Vec of files mentioned.
+/// An environment variable read during compilation, and its value if it was set.
+pub(crate) type EnvDep = (String, Option<String>);
+
+/// Parse dependency info from `file` and return a Vec of files mentioned,
+/// along with the environment variables the crate depends on.
 /// Treat paths as relative to `cwd`.
-fn parse_dep_file<T, U>(file: T, cwd: U) -> Result<Vec<PathBuf>>
+fn parse_dep_file<T, U>(file: T, cwd: U) -> Result<(Vec<PathBuf>, Vec<EnvDep>)>
 where
     T: AsRef<Path>,
     U: AsRef<Path>,
@@ -260,7 +351,27 @@ where
     let mut f = fs::File::open(file)?;
     let mut deps = String::new();
     f.read_to_string(&mut deps)?;
-    Ok(parse_dep_info(&deps, cwd))
+    Ok((parse_dep_info(&deps, cwd), parse_env_dep_info(&deps)))
+}
+
+/// Parse the `# env-dep:VAR=value` lines rustc writes to dep-info for
+/// `env!` and `option_env!`. Variables that weren't set have no `=value`.
+fn parse_env_dep_info(dep_info: &str) -> Vec<EnvDep> {
+    const PREFIX: &str = "# env-dep:";
+    let mut env_deps: Vec<_> = dep_info
+        .lines()
+        .filter(|line| line.starts_with(PREFIX))
+        .map(|line| {
+            let dep = &line[PREFIX.len()..];
+            match dep.find('=') {
+                Some(pos) => (dep[..pos].to_owned(), Some(dep[pos + 1..].to_owned())),
+                None => (dep.to_owned(), None),
+            }
+        })
+        .collect();
+    env_deps.sort();
+    env_deps.dedup();
+    env_deps
 }
 
 fn parse_dep_info<T>(dep_info: &str, cwd: T) -> Vec<PathBuf>
@@ -344,15 +455,38 @@ where
     }))
 }
 
+/// List the shared libraries in rustc's `sysroot`, which identify it along
+/// with rustc itself.
+fn sysroot_libs(sysroot: &Path) -> Result<Vec<PathBuf>> {
+    let libs_path = sysroot.join(LIBS_DIR);
+    let libs = fs::read_dir(&libs_path)
+        .with_context(|| format!("Failed to list rustc sysroot: `{:?}`", libs_path))?
+        .filter_map(|e| {
+            e.ok().and_then(|e| {
+                e.file_type().ok().and_then(|t| {
+                    let p = e.path();
+                    if t.is_file() && p.extension().map(|e| e == DLL_EXTENSION).unwrap_or(false) {
+                        Some(p)
+                    } else {
+                        None
+                    }
+                })
+            })
+        })
+        .collect();
+    Ok(libs)
+}
+
 impl Rust {
-    /// Create a new Rust compiler instance, calculating the hashes of
-    /// all the shared libraries in its sysroot.
+    /// Create a new Rust compiler instance, identifying it by all the shared
+    /// libraries in its sysroot, as `config.compiler_check` says.
     pub fn new<T>(
         mut creator: T,
         executable: PathBuf,
         env_vars: &[(OsString, OsString)],
         rustc_verbose_version: &str,
         dist_archive: Option<PathBuf>,
+        config: RustCompilerConfig,
         pool: ThreadPool,
     ) -> SFuture<Rust>
     where
@@ -365,7 +499,13 @@ impl Rust {
             .map(|l| &l[6..])
             .context("rustc verbose version didn't have a line for `host:`"))
         .to_string();
+        let version = rustc_verbose_version.lines().next().map(str::to_owned);
 
+        let check_creator = creator.clone();
+        let check_env = env_vars.to_owned();
+        let mut targets_creator = creator.clone();
+        let targets_env = env_vars.to_owned();
+        let targets_executable = executable.clone();
         // it's fine to use the `executable` directly no matter if proxied or not
         let mut cmd = creator.new_command_sync(&executable);
         cmd.stdout(process::Stdio::piped())
@@ -378,24 +518,7 @@ impl Rust {
             //debug!("output.and_then: {}", output);
             let outstr = String::from_utf8(output.stdout).context("Error parsing sysroot")?;
             let sysroot = PathBuf::from(outstr.trim_end());
-            let libs_path = sysroot.join(LIBS_DIR);
-            let mut libs = fs::read_dir(&libs_path)
-                .with_context(|| format!("Failed to list rustc sysroot: `{:?}`", libs_path))?
-                .filter_map(|e| {
-                    e.ok().and_then(|e| {
-                        e.file_type().ok().and_then(|t| {
-                            let p = e.path();
-                            if t.is_file()
-                                && p.extension().map(|e| e == DLL_EXTENSION).unwrap_or(false)
-                            {
-                                Some(p)
-                            } else {
-                                None
-                            }
-                        })
-                    })
-                })
-                .collect::<Vec<_>>();
+            let mut libs = sysroot_libs(&sysroot)?;
             if let Some(path) = dist_archive {
                 trace!("Hashing {:?} along with rustc libs.", path);
                 libs.push(path);
@@ -403,6 +526,29 @@ impl Rust {
             libs.sort();
             Ok((sysroot, libs))
         });
+        // Built-in targets aren't looked for as JSON specs, so a rustc that
+        // can't list them has all its targets treated as possibly custom.
+        let sysroot_and_libs = sysroot_and_libs.and_then(move |(sysroot, libs)| {
+            let mut cmd = targets_creator.new_command_sync(&targets_executable);
+            cmd.stdout(process::Stdio::piped())
+                .stderr(process::Stdio::null())
+                .arg("--print=target-list")
+                .env_clear()
+                .envs(ref_env(&targets_env));
+            run_input_output(cmd, None).then(move |output| -> Result<_> {
+                let builtin_targets = match output {
+                    Ok(output) => String::from_utf8_lossy(&output.stdout)
+                        .lines()
+                        .map(str::to_owned)
+                        .collect(),
+                    Err(e) => {
+                        warn!("Failed to list the built-in rustc targets: {}", e);
+                        vec![]
+                    }
+                };
+                Ok((sysroot, libs, builtin_targets))
+            })
+        });
 
         #[cfg(feature = "dist-client")]
         let rlib_dep_reader = {
@@ -412,7 +558,7 @@ impl Rust {
         };
 
         #[cfg(feature = "dist-client")]
-        return Box::new(sysroot_and_libs.join(rlib_dep_reader).and_then(move |((sysroot, libs), rlib_dep_reader)| {
+        return Box::new(sysroot_and_libs.join(rlib_dep_reader).and_then(move |((sysroot, libs, builtin_targets), rlib_dep_reader)| {
             let rlib_dep_reader = match rlib_dep_reader {
                 Ok(r) => Some(Arc::new(r)),
                 Err(e) => {
@@ -420,27 +566,90 @@ impl Rust {
                     None
                 },
             };
-            hash_all(&libs, &pool).map(move |digests| {
+            let digests = compiler_check_digests(
+                &check_creator,
+                &executable,
+                libs,
+                &config.compiler_check,
+                &check_env,
+                &pool,
+            );
+            digests.map(move |digests| {
                 Rust {
                     executable,
                     host,
+                    version,
                     sysroot,
+                    builtin_targets,
                     compiler_shlibs_digests: digests,
                     rlib_dep_reader,
+                    config,
                 }
             })
         }));
 
         #[cfg(not(feature = "dist-client"))]
-        return Box::new(sysroot_and_libs.and_then(move |(sysroot, libs)| {
-            hash_all(&libs, &pool).map(move |digests| Rust {
+        return Box::new(sysroot_and_libs.and_then(move |(sysroot, libs, targets)| {
+            let digests = compiler_check_digests(
+                &check_creator,
+                &executable,
+                libs,
+                &config.compiler_check,
+                &check_env,
+                &pool,
+            );
+            digests.map(move |digests| Rust {
                 executable,
                 host,
+                version,
                 sysroot,
+                builtin_targets: targets,
                 compiler_shlibs_digests: digests,
+                config,
             })
         }));
     }
+
+    /// The files this compiler was identified by: rustc and the shared
+    /// libraries in its sysroot.
+    pub(crate) fn detection_files(&self) -> Result<Vec<PathBuf>> {
+        let mut files = vec![self.executable.clone()];
+        files.extend(sysroot_libs(&self.sysroot)?);
+        Ok(files)
+    }
+
+    /// Restore a compiler read back from the compiler info cache, with the
+    /// current `config`.
+    pub(crate) fn restore(
+        mut self,
+        config: RustCompilerConfig,
+        env_vars: &[(OsString, OsString)],
+        pool: &ThreadPool,
+    ) -> SFuture<Rust> {
+        self.config = config;
+        #[cfg(feature = "dist-client")]
+        {
+            let executable = self.executable.clone();
+            let env_vars = env_vars.to_owned();
+            let rlib_dep_reader =
+                pool.spawn_fn(move || Ok(RlibDepReader::new_with_check(executable, &env_vars)));
+            Box::new(rlib_dep_reader.map(move |rlib_dep_reader| {
+                self.rlib_dep_reader = match rlib_dep_reader {
+                    Ok(r) => Some(Arc::new(r)),
+                    Err(e) => {
+                        warn!("Failed to initialise RlibDepDecoder, distributed compiles will be inefficient: {}", e);
+                        None
+                    }
+                };
+                self
+            }))
+        }
+        #[cfg(not(feature = "dist-client"))]
+        {
+            let _ = (env_vars, pool);
+            f_ok(self)
+        }
+    }
 }
 
 impl<T> Compiler<T> for Rust
@@ -450,6 +659,9 @@ where
     fn kind(&self) -> CompilerKind {
         CompilerKind::Rust
     }
+    fn executable(&self) -> &Path {
+        &self.executable
+    }
     #[cfg(feature = "dist-client")]
     fn get_toolchain_packager(&self) -> Box<dyn pkg::ToolchainPackager> {
         Box::new(RustToolchainPackager {
@@ -472,23 +684,31 @@ where
         arguments: &[OsString],
         cwd: &Path,
     ) -> CompilerArguments<Box<dyn CompilerHasher<T> + 'static>> {
-        match parse_arguments(arguments, cwd) {
+        match parse_arguments(arguments, cwd, self.config.incremental) {
             CompilerArguments::Ok(args) => CompilerArguments::Ok(Box::new(RustHasher {
                 executable: self.executable.clone(), // if rustup exists, this must already contain the true resolved compiler path
                 host: self.host.clone(),
+                version: self.version.clone(),
                 sysroot: self.sysroot.clone(),
+                builtin_targets: self.builtin_targets.clone(),
                 compiler_shlibs_digests: self.compiler_shlibs_digests.clone(),
                 #[cfg(feature = "dist-client")]
                 rlib_dep_reader: self.rlib_dep_reader.clone(),
+                config: self.config.clone(),
                 parsed_args: args,
             })),
             CompilerArguments::NotCompilation => CompilerArguments::NotCompilation,
             CompilerArguments::CannotCache(why, extra_info) => {
                 CompilerArguments::CannotCache(why, extra_info)
             }
+            CompilerArguments::Multiple(_) => unreachable!("rustc compiles a single crate"),
         }
     }
 
+    fn detected(&self) -> Option<DetectedCompiler> {
+        Some(DetectedCompiler::Rust(self.clone()))
+    }
+
     fn box_clone(&self) -> Box<dyn Compiler<T>> {
         Box::new((*self).clone())
     }
@@ -548,6 +768,10 @@ where
         Box::new(lookup)
     }
 
+    fn detected(&self) -> Option<DetectedProxy> {
+        Some(DetectedProxy::Rustup(self.proxy_executable.clone()))
+    }
+
     fn box_clone(&self) -> Box<dyn CompilerProxy<T>> {
         Box::new((*self).clone())
     }
@@ -715,24 +939,54 @@ macro_rules! make_os_string {
 
 #[derive(Clone, Debug, PartialEq)]
 struct ArgCrateTypes {
-    rlib: bool,
-    staticlib: bool,
+    types: CrateTypes,
     others: HashSet<String>,
 }
+impl ArgCrateTypes {
+    fn types_string(&self) -> String {
+        let ArgCrateTypes {
+            types:
+                CrateTypes {
+                    rlib,
+                    staticlib,
+                    bin,
+                    dylib,
+                    cdylib,
+                    proc_macro,
+                },
+            ref others,
+        } = *self;
+        let mut types: Vec<_> = others
+            .iter()
+            .map(String::as_str)
+            .chain(if rlib { Some("rlib") } else { None })
+            .chain(if staticlib { Some("staticlib") } else { None })
+            .chain(if bin { Some("bin") } else { None })
+            .chain(if dylib { Some("dylib") } else { None })
+            .chain(if cdylib { Some("cdylib") } else { None })
+            .chain(if proc_macro { Some("proc-macro") } else { None })
+            .collect();
+        types.sort_unstable();
+        types.join(",")
+    }
+}
 impl FromArg for ArgCrateTypes {
     fn process(arg: OsString) -> ArgParseResult<Self> {
         let arg = String::process(arg)?;
         let mut crate_types = ArgCrateTypes {
-            rlib: false,
-            staticlib: false,
+            types: Default::default(),
             others: HashSet::new(),
         };
         for ty in arg.split(',') {
             match ty {
                 // It is assumed that "lib" always refers to "rlib", which
                 // is true right now but may not be in the future
-                "lib" | "rlib" => crate_types.rlib = true,
-                "staticlib" => crate_types.staticlib = true,
+                "lib" | "rlib" => crate_types.types.rlib = true,
+                "staticlib" => crate_types.types.staticlib = true,
+                "bin" => crate_types.types.bin = true,
+                "dylib" => crate_types.types.dylib = true,
+                "cdylib" => crate_types.types.cdylib = true,
+                "proc-macro" => crate_types.types.proc_macro = true,
                 other => {
                     crate_types.others.insert(other.to_owned());
                 }
@@ -743,36 +997,10 @@ impl FromArg for ArgCrateTypes {
 }
 impl IntoArg for ArgCrateTypes {
     fn into_arg_os_string(self) -> OsString {
-        let ArgCrateTypes {
-            rlib,
-            staticlib,
-            others,
-        } = self;
-        let mut types: Vec<_> = others
-            .iter()
-            .map(String::as_str)
-            .chain(if rlib { Some("rlib") } else { None })
-            .chain(if staticlib { Some("staticlib") } else { None })
-            .collect();
-        types.sort_unstable();
-        let types_string = types.join(",");
-        types_string.into()
+        self.types_string().into()
     }
     fn into_arg_string(self, _transformer: PathTransformerFn<'_>) -> ArgToStringResult {
-        let ArgCrateTypes {
-            rlib,
-            staticlib,
-            others,
-        } = self;
-        let mut types: Vec<_> = others
-            .iter()
-            .map(String::as_str)
-            .chain(if rlib { Some("rlib") } else { None })
-            .chain(if staticlib { Some("staticlib") } else { None })
-            .collect();
-        types.sort_unstable();
-        let types_string = types.join(",");
-        Ok(types_string)
+        Ok(self.types_string())
     }
 }
 
@@ -862,9 +1090,9 @@ impl IntoArg for ArgCodegen {
 }
 
 #[derive(Clone, Debug, PartialEq)]
-struct ArgExtern {
-    name: String,
-    path: PathBuf,
+pub(crate) struct ArgExtern {
+    pub(crate) name: String,
+    pub(crate) path: PathBuf,
 }
 impl FromArg for ArgExtern {
     fn process(arg: OsString) -> ArgParseResult<Self> {
@@ -890,7 +1118,7 @@ impl IntoArg for ArgExtern {
 }
 
 #[derive(Clone, Debug, PartialEq)]
-enum ArgTarget {
+pub(crate) enum ArgTarget {
     Name(String),
     Path(PathBuf),
     Unsure(OsString),
@@ -921,6 +1149,49 @@ impl FromArg for ArgTarget {
         ))
     }
 }
+impl ArgTarget {
+    /// The name of the target, as a triple for built-in targets.
+    fn triple(&self) -> Cow<'_, str> {
+        match self {
+            ArgTarget::Name(s) => Cow::Borrowed(s),
+            ArgTarget::Path(p) => p
+                .file_stem()
+                .map(|s| s.to_string_lossy())
+                .unwrap_or_default(),
+            ArgTarget::Unsure(s) => s.to_string_lossy(),
+        }
+    }
+
+    /// Find the JSON spec of a custom target the way rustc does: a path
+    /// ending in `.json` is used as is, a built-in target has none, and
+    /// otherwise `<target>.json` is looked for in `RUST_TARGET_PATH`, which
+    /// defaults to the current directory.
+    pub(crate) fn find_spec(
+        &self,
+        builtin_targets: &[String],
+        cwd: &Path,
+        env_vars: &[(OsString, OsString)],
+    ) -> Option<PathBuf> {
+        let name = match self {
+            ArgTarget::Path(p) => return Some(cwd.join(p)),
+            ArgTarget::Name(s) => OsStr::new(s),
+            ArgTarget::Unsure(s) => s.as_os_str(),
+        };
+        if builtin_targets.iter().any(|t| name == OsStr::new(t)) {
+            return None;
+        }
+        let mut file_name = name.to_owned();
+        file_name.push(".json");
+        let target_path = env_vars
+            .iter()
+            .find(|&(k, _)| k == "RUST_TARGET_PATH")
+            .map(|(_, v)| v.clone())
+            .unwrap_or_default();
+        env::split_paths(&target_path)
+            .map(|dir| cwd.join(dir).join(&file_name))
+            .find(|p| p.is_file())
+    }
+}
 impl IntoArg for ArgTarget {
     fn into_arg_os_string(self) -> OsString {
         match self {
@@ -999,22 +1270,29 @@ counted_array!(static ARGS: [ArgInfo<ArgData>; _] = [
     take_arg!("-o", PathBuf, CanBeSeparated, TooHardPath),
 ]);
 
-fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<ParsedArguments> {
+fn parse_arguments(
+    arguments: &[OsString],
+    cwd: &Path,
+    incremental_policy: IncrementalPolicy,
+) -> CompilerArguments<ParsedArguments> {
     let mut args = vec![];
 
     let mut emit: Option<HashSet<String>> = None;
     let mut input = None;
     let mut output_dir = None;
     let mut crate_name = None;
-    let mut crate_types = CrateTypes {
-        rlib: false,
-        staticlib: false,
-    };
+    let mut crate_types = CrateTypes::default();
     let mut extra_filename = None;
     let mut externs = vec![];
     let mut crate_link_paths = vec![];
     let mut static_lib_names = vec![];
+    let mut dynamic_lib_names = vec![];
     let mut static_link_paths: Vec<PathBuf> = vec![];
+    let mut linker = None;
+    let mut link_args = vec![];
+    let mut target_name = None;
+    let mut incremental = None;
+    let mut debuginfo = DebugInfoOptions::default();
     let mut color_mode = ColorMode::Auto;
     let mut has_json = false;
 
@@ -1030,6 +1308,8 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
             Some(LinkLibrary(ArgLinkLibrary { kind, name })) => {
                 if kind == "static" {
                     static_lib_names.push(name.to_owned())
+                } else if kind == "dylib" {
+                    dynamic_lib_names.push(name.to_owned())
                 }
             }
             Some(LinkPath(ArgLinkPath { kind, path })) => {
@@ -1049,20 +1329,20 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
                 }
                 emit = Some(value.split(',').map(str::to_owned).collect())
             }
-            Some(CrateType(ArgCrateTypes {
-                rlib,
-                staticlib,
-                others,
-            })) => {
-                // We can't cache non-rlib/staticlib crates, because rustc invokes the
-                // system linker to link them, and we don't know about all the linker inputs.
+            Some(CrateType(ArgCrateTypes { types, others })) => {
+                // We don't know what rustc does for crate types it doesn't
+                // document, so we can't cache those.
                 if !others.is_empty() {
                     let others: Vec<&str> = others.iter().map(String::as_str).collect();
                     let others_string = others.join(",");
                     cannot_cache!("crate-type", others_string)
                 }
-                crate_types.rlib |= rlib;
-                crate_types.staticlib |= staticlib;
+                crate_types.rlib |= types.rlib;
+                crate_types.staticlib |= types.staticlib;
+                crate_types.bin |= types.bin;
+                crate_types.dylib |= types.dylib;
+                crate_types.cdylib |= types.cdylib;
+                crate_types.proc_macro |= types.proc_macro;
             }
             Some(CrateName(value)) => crate_name = Some(value.clone()),
             Some(OutDir(value)) => output_dir = Some(value.clone()),
@@ -1079,7 +1359,24 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
                     // Longer-term we would like to figure out better integration between
                     // sccache and rustc in the incremental scenario:
                     // https://github.com/mozilla/sccache/issues/236
-                    ("incremental", _) => cannot_cache!("incremental"),
+                    // The configuration may instead allow caching a
+                    // non-incremental build in its place.
+                    ("incremental", _) if incremental_policy == IncrementalPolicy::Uncached => {
+                        cannot_cache!("incremental")
+                    }
+                    ("linker", Some(value)) => linker = Some(PathBuf::from(value)),
+                    ("link-arg", Some(value)) => link_args.push(value.to_owned()),
+                    ("link-args", Some(value)) => {
+                        link_args.extend(value.split_whitespace().map(str::to_owned))
+                    }
+                    ("debuginfo", value) => {
+                        debuginfo.enabled = !matches!(value.as_deref(), Some("0") | Some("none"))
+                    }
+                    ("split-debuginfo", value) => debuginfo.split = value.clone(),
+                    ("strip", value) => {
+                        debuginfo.stripped =
+                            matches!(value.as_deref(), Some("debuginfo") | Some("symbols"))
+                    }
                     (_, _) => (),
                 }
             }
@@ -1095,10 +1392,7 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
                 has_json = true;
             }
             Some(PassThrough(_)) => (),
-            Some(Target(target)) => match target {
-                ArgTarget::Path(_) | ArgTarget::Unsure(_) => cannot_cache!("target"),
-                ArgTarget::Name(_) => (),
-            },
+            Some(Target(target)) => target_name = Some(target.clone()),
             None => {
                 match arg {
                     Argument::Raw(ref val) => {
@@ -1117,6 +1411,11 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
         // strip colors if necessary.
         match arg.get_data() {
             Some(Color(_)) => {}
+            Some(CodeGen(ArgCodegen { opt, .. })) if opt == "incremental" => {
+                if incremental_policy == IncrementalPolicy::Lookup {
+                    incremental = Some(arg.normalize(NormalizedDisposition::Separated));
+                }
+            }
             _ => args.push(arg.normalize(NormalizedDisposition::Separated)),
         }
     }
@@ -1143,14 +1442,10 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
     if !emit.is_empty() && !emit.contains("link") && !emit.contains("metadata") {
         return CompilerArguments::NotCompilation;
     }
-    // If it's not an rlib and not a staticlib then crate-type wasn't passed,
-    // so it will usually be inferred as a binary, though the `#![crate_type`
-    // annotation may dictate otherwise - either way, we don't know what to do.
-    if let CrateTypes {
-        rlib: false,
-        staticlib: false,
-    } = crate_types
-    {
+    // If crate-type wasn't passed, it will usually be inferred as a binary,
+    // though the `#![crate_type` annotation may dictate otherwise - either
+    // way, we don't know what to do.
+    if crate_types == CrateTypes::default() {
         cannot_cache!("crate-type", "No crate-type passed".to_owned())
     }
     // We won't cache invocations that are outputting anything but
@@ -1191,6 +1486,13 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
             None
         })
         .collect();
+    // When the crate is linked, the linker also reads dynamic libraries and
+    // any files named in link arguments.
+    let link_inputs = if crate_types.needs_linker() {
+        find_link_inputs(&dynamic_lib_names, &static_link_paths, &link_args, cwd)
+    } else {
+        vec![]
+    };
     // We'll figure out the source files and outputs later in
     // `generate_hash_key` where we can run rustc.
     // Cargo doesn't deterministically order --externs, and we need the hash inputs in a
@@ -1203,14 +1505,154 @@ fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<Pars
         externs,
         crate_link_paths,
         staticlibs,
+        link_inputs,
+        linker,
+        target: target_name,
         crate_name,
         dep_info: dep_info.map(|s| s.into()),
         emit,
         color_mode,
         has_json,
+        incremental,
+        debuginfo,
     })
 }
 
+/// Hash the environment variables that may affect the output of a Rust
+/// compile. Ideally we'd hash only those referenced via `env!` in the
+/// program, and we do hash those found in the dep-info, but build scripts and
+/// proc macros may read others. Hashing all environment variables is too
+/// much, so by default we also hash the CARGO_ env vars and hope that's
+/// sufficient.
+/// Upstream Rust issue tracking getting information about env! usage:
+/// https://github.com/rust-lang/rust/issues/40364
+pub(crate) fn hash_env(
+    m: &mut Digest,
+    config: &RustCompilerConfig,
+    env_vars: &[(OsString, OsString)],
+    env_deps: Vec<EnvDep>,
+) {
+    for (var, val) in env_vars {
+        // CARGO_MAKEFLAGS will have jobserver info which is extremely non-cacheable.
+        let default = var.eq("CARGO") || (var.starts_with("CARGO_") && var != "CARGO_MAKEFLAGS");
+        if config.env.is_hashed(var, default) {
+            var.hash(&mut HashToDigest { digest: m });
+            m.update(b"=");
+            val.hash(&mut HashToDigest { digest: m });
+        }
+    }
+    // Environment variables read with `env!` and `option_env!`, as found in
+    // the dep-info, including whether they were set.
+    for (var, val) in env_deps {
+        if config.env.is_hashed(OsStr::new(&var), true) {
+            var.hash(&mut HashToDigest { digest: m });
+            val.hash(&mut HashToDigest { digest: m });
+        }
+    }
+}
+
+/// Hash the crate metadata in each of `externs`: all of an rmeta file, the
+/// metadata member of an rlib, and all of anything else, like proc macros.
+pub(crate) fn hash_all_metadata(externs: &[PathBuf], pool: &ThreadPool) -> SFuture<Vec<String>> {
+    let externs = externs.to_owned();
+    pool.spawn_fn(move || externs.iter().map(|e| hash_metadata(e)).collect())
+}
+
+fn hash_metadata(path: &Path) -> Result<String> {
+    if path.extension().map(|e| e == "rlib").unwrap_or(false) {
+        match hash_rlib_metadata(path) {
+            Ok(Some(digest)) => return Ok(digest),
+            Ok(None) => debug!("No metadata found in {:?}, hashing all of it", path),
+            Err(e) => debug!(
+                "Failed to read {:?} as an rlib, hashing all of it: {}",
+                path, e
+            ),
+        }
+    }
+    Digest::reader_sync(fs::File::open(path)?)
+}
+
+fn hash_rlib_metadata(path: &Path) -> Result<Option<String>> {
+    let mut archive = ar::Archive::new(fs::File::open(path)?);
+    while let Some(entry) = archive.next_entry() {
+        let entry = entry?;
+        if RLIB_METADATA_MEMBERS.contains(&entry.header().identifier()) {
+            return Digest::reader_sync(entry).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+/// Locate the dynamic libraries in `lib_names` within `link_paths`, and the
+/// files named in `link_args`. Libraries that aren't found are assumed to be
+/// system libraries, like system headers for C compilers.
+fn find_link_inputs(
+    lib_names: &[String],
+    link_paths: &[PathBuf],
+    link_args: &[String],
+    cwd: &Path,
+) -> Vec<PathBuf> {
+    let mut inputs: Vec<PathBuf> = lib_names
+        .iter()
+        .filter_map(|name| {
+            let file_names = [
+                format!("{}{}.{}", DLL_PREFIX, name, DLL_EXTENSION),
+                format!("lib{}.a", name),
+                format!("{}.lib", name),
+            ];
+            link_paths
+                .iter()
+                .flat_map(|path| file_names.iter().map(move |f| path.join(f)))
+                .find(|p| p.is_file())
+        })
+        .collect();
+    // Link arguments may name a file directly, or as the value of an option
+    // like `-Wl,--version-script=foo.map`.
+    for arg in link_args {
+        let value = arg.rsplit(&['=', ','][..]).next().unwrap_or(arg);
+        let path = cwd.join(value);
+        if !value.is_empty() && path.is_file() && !inputs.contains(&path) {
+            inputs.push(path);
+        }
+    }
+    inputs
+}
+
+/// Find the linker rustc runs for `target`: the one passed with `-C linker`,
+/// or else the default for the target.
+fn find_linker(
+    linker: Option<&Path>,
+    target: &str,
+    host: &str,
+    sysroot: &Path,
+    cwd: &Path,
+    env_vars: &[(OsString, OsString)],
+) -> Option<PathBuf> {
+    let linker = match linker {
+        Some(linker) => linker.to_owned(),
+        None if target.contains("-msvc") => PathBuf::from("link.exe"),
+        None if target.starts_with("wasm32") => {
+            let rust_lld = sysroot
+                .join("lib")
+                .join("rustlib")
+                .join(host)
+                .join("bin")
+                .join("rust-lld")
+                .with_extension(EXE_EXTENSION);
+            return Some(rust_lld).filter(|p| p.is_file());
+        }
+        None => PathBuf::from("cc"),
+    };
+    if linker.components().count() > 1 {
+        return Some(cwd.join(linker));
+    }
+    let path = env_vars
+        .iter()
+        .find(|&(k, _)| k == "PATH")
+        .map(|(_, v)| v.clone());
+    which::which_in(linker, path, cwd).ok()
+}
+
 impl<T> CompilerHasher<T> for RustHasher
 where
     T: CommandCreatorSync,
@@ -1227,10 +1669,13 @@ where
         let RustHasher {
             executable,
             host,
+            version: _,
             sysroot,
+            builtin_targets,
             compiler_shlibs_digests,
             #[cfg(feature = "dist-client")]
             rlib_dep_reader,
+            config,
             parsed_args:
                 ParsedArguments {
                     arguments,
@@ -1238,11 +1683,16 @@ where
                     externs,
                     crate_link_paths,
                     staticlibs,
+                    link_inputs,
+                    linker,
+                    target,
                     crate_name,
                     crate_types,
                     dep_info,
                     emit,
                     has_json,
+                    incremental,
+                    debuginfo,
                     ..
                 },
         } = *self;
@@ -1283,22 +1733,72 @@ where
             &env_vars,
             pool,
         );
-        let source_files_and_hashes = source_files.and_then(move |source_files| {
+        let source_files_and_hashes = source_files.and_then(move |(source_files, env_deps)| {
             hash_all(&source_files, &source_hashes_pool)
-                .map(|source_hashes| (source_files, source_hashes))
+                .map(|source_hashes| (source_files, source_hashes, env_deps))
         });
-        // Hash the contents of the externs listed on the commandline.
+        // Hash the contents of the externs listed on the commandline. With
+        // early cutoff, a crate that isn't linked only depends on the
+        // metadata of its externs, so changes to their code alone don't
+        // invalidate it.
         trace!("[{}]: hashing {} externs", crate_name, externs.len());
         let abs_externs = externs.iter().map(|e| cwd.join(e)).collect::<Vec<_>>();
-        let extern_hashes = hash_all(&abs_externs, pool);
+        let only_rlib = crate_types
+            == CrateTypes {
+                rlib: true,
+                ..Default::default()
+            };
+        let extern_hashes = if config.early_cutoff && only_rlib {
+            hash_all_metadata(&abs_externs, pool)
+        } else {
+            hash_all(&abs_externs, pool)
+        };
         // Hash the contents of the staticlibs listed on the commandline.
         trace!("[{}]: hashing {} staticlibs", crate_name, staticlibs.len());
         let abs_staticlibs = staticlibs.iter().map(|s| cwd.join(s)).collect::<Vec<_>>();
         let staticlib_hashes = hash_all(&abs_staticlibs, pool);
+        // Hash the spec of a custom target, and the linker and its other inputs
+        // if the crate is linked.
+        let target_spec = target
+            .as_ref()
+            .and_then(|t| t.find_spec(&builtin_targets, &cwd, &env_vars));
+        let extra_inputs: Vec<PathBuf> = target_spec.iter().cloned().chain(link_inputs).collect();
+        let extra_input_hashes = hash_all(&extra_inputs, pool);
+        let triple = target
+            .as_ref()
+            .map(|t| t.triple().into_owned())
+            .unwrap_or_else(|| host.clone());
+        let linker_hash: SFuture<Option<String>> = if crate_types.needs_linker() {
+            match find_linker(linker.as_deref(), &triple, &host, &sysroot, &cwd, &env_vars) {
+                Some(linker) => {
+                    trace!("[{}]: hashing linker {:?}", crate_name, linker);
+                    Box::new(Digest::file(linker, pool).map(Some))
+                }
+                None => {
+                    // rustc will fail to link if the linker really is missing.
+                    debug!("[{}]: failed to find the linker", crate_name);
+                    f_ok(None)
+                }
+            }
+        } else {
+            f_ok(None)
+        };
         let creator = creator.clone();
-        let hashes = source_files_and_hashes.join3(extern_hashes, staticlib_hashes);
+        let hashes = source_files_and_hashes.join5(
+            extern_hashes,
+            staticlib_hashes,
+            extra_input_hashes,
+            linker_hash,
+        );
         Box::new(hashes.and_then(
-            move |((source_files, source_hashes), extern_hashes, staticlib_hashes)| -> SFuture<_> {
+            move |(
+                (source_files, source_hashes, env_deps),
+                extern_hashes,
+                staticlib_hashes,
+                extra_input_hashes,
+                linker_hash,
+            )|
+                  -> SFuture<_> {
                 // If you change any of the inputs to the hash, you should change `CACHE_VERSION`.
                 let mut m = Digest::new();
                 // Hash inputs:
@@ -1343,19 +1843,17 @@ where
                 // 4. The digest of all source files (this includes src file from cmdline).
                 // 5. The digest of all files listed on the commandline (self.externs).
                 // 6. The digest of all static libraries listed on the commandline (self.staticlibs).
+                // 7. The digest of the target spec and other linker inputs (self.link_inputs).
                 for h in source_hashes
                     .into_iter()
                     .chain(extern_hashes)
                     .chain(staticlib_hashes)
+                    .chain(extra_input_hashes)
                 {
                     m.update(h.as_bytes());
                 }
-                // 7. Environment variables. Ideally we'd use anything referenced
-                // via env! in the program, but we don't have a way to determine that
-                // currently, and hashing all environment variables is too much, so
-                // we'll just hash the CARGO_ env vars and hope that's sufficient.
-                // Upstream Rust issue tracking getting information about env! usage:
-                // https://github.com/rust-lang/rust/issues/40364
+                // 8. Environment variables, and those read with `env!` and
+                // `option_env!` (see `hash_env`).
                 let mut env_vars: Vec<_> = env_vars
                     .iter()
                     // Filter out RUSTC_COLOR since we control color usage with command line flags.
@@ -1364,16 +1862,13 @@ where
                     .cloned()
                     .collect();
                 env_vars.sort();
-                for &(ref var, ref val) in env_vars.iter() {
-                    // CARGO_MAKEFLAGS will have jobserver info which is extremely non-cacheable.
-                    if var.eq("CARGO") || (var.starts_with("CARGO_") && var != "CARGO_MAKEFLAGS") {
-                        var.hash(&mut HashToDigest { digest: &mut m });
-                        m.update(b"=");
-                        val.hash(&mut HashToDigest { digest: &mut m });
-                    }
-                }
-                // 8. The cwd of the compile. This will wind up in the rlib.
+                hash_env(&mut m, &config, &env_vars, env_deps);
+                // 9. The cwd of the compile. This will wind up in the rlib.
                 cwd.hash(&mut HashToDigest { digest: &mut m });
+                // 10. The digest of the linker, if the crate is linked.
+                if let Some(linker_hash) = linker_hash {
+                    m.update(linker_hash.as_bytes());
+                }
                 // Turn arguments into a simple Vec<OsString> to calculate outputs.
                 let flat_os_string_arguments: Vec<OsString> = os_string_arguments
                     .into_iter()
@@ -1428,6 +1923,20 @@ where
                             }
                         }
 
+                        // The linker may write debuginfo next to a linked crate.
+                        if crate_types.needs_linker() && emit.contains("link") {
+                            let debuginfo_outputs: Vec<_> = outputs
+                                .iter()
+                                .filter(|o| {
+                                    ![".rlib", ".rmeta", ".a", ".lib"]
+                                        .iter()
+                                        .any(|ext| o.ends_with(ext))
+                                })
+                                .flat_map(|o| debuginfo.outputs(o, &triple))
+                                .collect();
+                            outputs.extend(debuginfo_outputs);
+                        }
+
                         // Convert output files into a map of basename -> full
                         // path, and remove some unneeded / non-existing ones,
                         // see https://github.com/rust-lang/rust/pull/68799.
@@ -1459,6 +1968,7 @@ where
                             .into_iter()
                             .chain(abs_externs)
                             .chain(abs_staticlibs)
+                            .chain(extra_inputs)
                             .collect();
 
                         HashResult {
@@ -1473,9 +1983,11 @@ where
                                 crate_link_paths,
                                 crate_name,
                                 crate_types,
+                                target_spec,
                                 dep_info,
                                 cwd,
                                 env_vars,
+                                incremental,
                                 #[cfg(feature = "dist-client")]
                                 rlib_dep_reader,
                             }),
@@ -1491,6 +2003,14 @@ where
         self.parsed_args.color_mode
     }
 
+    fn kind(&self) -> CompilerKind {
+        CompilerKind::Rust
+    }
+
+    fn version(&self) -> Option<String> {
+        self.version.clone()
+    }
+
     fn output_pretty(&self) -> Cow<'_, str> {
         Cow::Borrowed(&self.parsed_args.crate_name)
     }
@@ -1514,6 +2034,7 @@ impl Compilation for RustCompilation {
             ref env_vars,
             ref host,
             ref sysroot,
+            ref incremental,
             ..
         } = *self;
 
@@ -1531,6 +2052,7 @@ impl Compilation for RustCompilation {
             executable: executable.to_owned(),
             arguments: arguments
                 .iter()
+                .chain(incremental)
                 .flat_map(|arg| arg.iter_os_strings())
                 .collect(),
             env_vars: env_vars.to_owned(),
@@ -1541,6 +2063,12 @@ impl Compilation for RustCompilation {
         let dist_command = None;
         #[cfg(feature = "dist-client")]
         let dist_command = (|| {
+            // The linker and its inputs aren't part of the toolchain package,
+            // and the incremental compilation state is local.
+            if self.crate_types.needs_linker() || incremental.is_some() {
+                return None;
+            }
+
             macro_rules! try_string_arg {
                 ($e:expr) => {
                     match $e {
@@ -1558,6 +2086,16 @@ impl Compilation for RustCompilation {
 
             // flat_map would be nice but the lifetimes don't work out
             for argument in arguments.iter() {
+                if let (Some(Target(_)), Some(target_spec)) =
+                    (argument.get_data(), self.target_spec.as_ref())
+                {
+                    // The spec may have been found via RUST_TARGET_PATH, so
+                    // point straight at the copy shipped with the inputs.
+                    saw_target = true;
+                    dist_arguments.push("--target".to_owned());
+                    dist_arguments.push(path_transformer.as_dist(target_spec)?);
+                    continue;
+                }
                 let path_transformer_fn = &mut |p: &Path| path_transformer.as_dist(p);
                 if let Argument::Raw(input_path) = argument {
                     // Need to explicitly handle the input argument as it's not parsed as a path
@@ -1642,7 +2180,14 @@ impl Compilation for RustCompilation {
             })
         })();
 
-        Ok((command, dist_command, Cacheable::Yes))
+        // An incremental build stands in for the non-incremental one that was
+        // looked up, so don't store it under the same key.
+        let cacheable = if incremental.is_some() {
+            Cacheable::No
+        } else {
+            Cacheable::Yes
+        };
+        Ok((command, dist_command, cacheable))
     }
 
     #[cfg(feature = "dist-client")]
@@ -1848,6 +2393,10 @@ impl pkg::InputsPackager for RustInputsPackager {
             CrateTypes {
                 rlib: true,
                 staticlib: false,
+                bin: false,
+                dylib: false,
+                cdylib: false,
+                proc_macro: false,
             }
         );
 
@@ -2301,7 +2850,7 @@ mod test {
 
     fn _parse_arguments(arguments: &[String]) -> CompilerArguments<ParsedArguments> {
         let arguments = arguments.iter().map(OsString::from).collect::<Vec<_>>();
-        parse_arguments(&arguments, ".".as_ref())
+        parse_arguments(&arguments, ".".as_ref(), IncrementalPolicy::Uncached)
     }
 
     macro_rules! parses {
@@ -2522,6 +3071,48 @@ mod test {
         assert_eq!(r, CompilerArguments::CannotCache("incremental", None))
     }
 
+    #[test]
+    fn test_parse_arguments_incremental_policy() {
+        let args = ovec![
+            "--emit",
+            "link",
+            "foo.rs",
+            "--out-dir",
+            "out",
+            "--crate-name",
+            "foo",
+            "--crate-type",
+            "lib",
+            "-C",
+            "incremental=/foo"
+        ];
+        let plain = match parse_arguments(&args[..9], ".".as_ref(), IncrementalPolicy::Uncached) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        let h = match parse_arguments(&args, ".".as_ref(), IncrementalPolicy::Strip) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(h, plain);
+        let h = match parse_arguments(&args, ".".as_ref(), IncrementalPolicy::Lookup) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(h.arguments, plain.arguments);
+        assert_eq!(
+            h.incremental,
+            Some(Argument::WithValue(
+                "-C",
+                ArgData::CodeGen(ArgCodegen {
+                    opt: "incremental".into(),
+                    value: Some("/foo".into()),
+                }),
+                ArgDisposition::Separated,
+            ))
+        );
+    }
+
     #[test]
     fn test_parse_arguments_dep_info_no_extra_filename() {
         let h = parses!(
@@ -2580,6 +3171,47 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_parse_arguments_link_inputs() {
+        let f = TestFixture::new();
+        let lib = format!("{}bar.{}", DLL_PREFIX, DLL_EXTENSION);
+        f.touch(&format!("native/{}", lib)).unwrap();
+        f.touch("link.x").unwrap();
+        let args = ovec![
+            "--crate-name",
+            "foo",
+            "--crate-type",
+            "cdylib",
+            "--emit",
+            "link",
+            "-L",
+            "native=native",
+            "-l",
+            "bar",
+            "-l",
+            "system",
+            "-C",
+            "linker=clang",
+            "-C",
+            "link-args=-T link.x -nostartfiles",
+            "foo.rs",
+            "--out-dir",
+            "out"
+        ];
+        let h = match parse_arguments(&args, f.tempdir.path(), IncrementalPolicy::Uncached) {
+            CompilerArguments::Ok(h) => h,
+            o => panic!("Got unexpected parse result: {:?}", o),
+        };
+        assert_eq!(h.linker, Some(PathBuf::from("clang")));
+        assert_eq!(
+            h.link_inputs,
+            vec![
+                f.tempdir.path().join("native").join(lib),
+                f.tempdir.path().join("link.x")
+            ]
+        );
+    }
+
     #[test]
     fn test_parse_arguments_non_rlib_crate() {
         parses!(
@@ -2626,7 +3258,7 @@ mod test {
             "--crate-name",
             "foo"
         );
-        fails!(
+        let h = parses!(
             "--crate-type",
             "bin",
             "--emit",
@@ -2637,7 +3269,9 @@ mod test {
             "--crate-name",
             "foo"
         );
-        fails!(
+        assert!(h.crate_types.bin);
+        assert!(h.crate_types.needs_linker());
+        let h = parses!(
             "--crate-type",
             "rlib,dylib",
             "--emit",
@@ -2648,6 +3282,88 @@ mod test {
             "--crate-name",
             "foo"
         );
+        assert!(h.crate_types.rlib && h.crate_types.dylib);
+        let h = parses!(
+            "--crate-type",
+            "proc-macro",
+            "--emit",
+            "link",
+            "foo.rs",
+            "--out-dir",
+            "out",
+            "--crate-name",
+            "foo"
+        );
+        assert!(h.crate_types.proc_macro);
+        fails!(
+            "--crate-type",
+            "rlib,bogus",
+            "--emit",
+            "link",
+            "foo.rs",
+            "--out-dir",
+            "out",
+            "--crate-name",
+            "foo"
+        );
+    }
+
+    #[test]
+    fn test_debuginfo_outputs() {
+        let h = parses!(
+            "--crate-type",
+            "bin",
+            "--emit",
+            "link",
+            "foo.rs",
+            "--out-dir",
+            "out",
+            "--crate-name",
+            "foo",
+            "-C",
+            "debuginfo=2",
+            "-C",
+            "split-debuginfo=packed"
+        );
+        let linux = "x86_64-unknown-linux-gnu";
+        assert_eq!(
+            stringvec!["foo-1234.dwp"],
+            h.debuginfo.outputs("foo-1234", linux)
+        );
+        assert_eq!(
+            stringvec!["libfoo-1234.dwp"],
+            h.debuginfo.outputs("libfoo-1234.so", linux)
+        );
+        assert_eq!(
+            stringvec!["foo-1234.dSYM/"],
+            h.debuginfo.outputs("foo-1234", "aarch64-apple-darwin")
+        );
+        let msvc = "x86_64-pc-windows-msvc";
+        assert_eq!(
+            stringvec!["foo-1234.pdb"],
+            h.debuginfo.outputs("foo-1234.exe", msvc)
+        );
+        assert_eq!(stringvec!["foo.pdb"], h.debuginfo.outputs("foo.dll", msvc));
+
+        let h = parses!(
+            "--crate-type",
+            "bin",
+            "--emit",
+            "link",
+            "foo.rs",
+            "--out-dir",
+            "out",
+            "--crate-name",
+            "foo",
+            "-C",
+            "debuginfo=0",
+            "-C",
+            "split-debuginfo=packed",
+            "-C",
+            "strip=debuginfo"
+        );
+        assert!(h.debuginfo.outputs("foo-1234", linux).is_empty());
+        assert!(h.debuginfo.outputs("foo-1234.exe", msvc).is_empty());
     }
 
     #[test]
@@ -2755,6 +3471,27 @@ bar.rs:
         );
     }
 
+    #[test]
+    fn test_parse_env_dep_info() {
+        let deps = "foo: baz.rs
+
+baz.rs:
+
+# env-dep:GIT_SHA=abc123
+# env-dep:MY_FEATURE_FLAG
+# env-dep:CARGO_PKG_NAME=foo=bar
+";
+        assert_eq!(
+            vec![
+                ("CARGO_PKG_NAME".to_owned(), Some("foo=bar".to_owned())),
+                ("GIT_SHA".to_owned(), Some("abc123".to_owned())),
+                ("MY_FEATURE_FLAG".to_owned(), None),
+            ],
+            parse_env_dep_info(deps)
+        );
+        assert_eq!(pathvec!["baz.rs"], parse_dep_info(deps, ""));
+    }
+
     #[test]
     fn test_parse_dep_info_with_escaped_spaces() {
         let deps = r#"foo: baz.rs abc\ def.rs
@@ -2919,10 +3656,13 @@ c:/foo/bar.rs:
         let hasher = Box::new(RustHasher {
             executable: "rustc".into(),
             host: "x86-64-unknown-unknown-unknown".to_owned(),
+            version: None,
             sysroot: f.tempdir.path().join("sysroot"),
+            builtin_targets: vec![],
             compiler_shlibs_digests: vec![FAKE_DIGEST.to_owned()],
             #[cfg(feature = "dist-client")]
             rlib_dep_reader: None,
+            config: Default::default(),
             parsed_args: ParsedArguments {
                 arguments: vec![
                     Argument::Raw("a".into()),
@@ -2942,15 +3682,20 @@ c:/foo/bar.rs:
                 externs: vec!["bar.rlib".into()],
                 crate_link_paths: vec![],
                 staticlibs: vec![f.tempdir.path().join("libbaz.a")],
+                link_inputs: vec![],
+                linker: None,
+                target: None,
                 crate_name: "foo".into(),
                 crate_types: CrateTypes {
                     rlib: true,
-                    staticlib: false,
+                    ..Default::default()
                 },
                 dep_info: None,
                 emit,
                 color_mode: ColorMode::Auto,
                 has_json: false,
+                incremental: None,
+                debuginfo: Default::default(),
             },
         });
         let creator = new_creator();
@@ -3020,10 +3765,11 @@ c:/foo/bar.rs:
         F: Fn(&Path) -> Result<()>,
     {
         let oargs = args.iter().map(OsString::from).collect::<Vec<OsString>>();
-        let parsed_args = match parse_arguments(&oargs, &f.tempdir.path()) {
-            CompilerArguments::Ok(parsed_args) => parsed_args,
-            o => panic!("Got unexpected parse result: {:?}", o),
-        };
+        let parsed_args =
+            match parse_arguments(&oargs, f.tempdir.path(), IncrementalPolicy::Uncached) {
+                CompilerArguments::Ok(parsed_args) => parsed_args,
+                o => panic!("Got unexpected parse result: {:?}", o),
+            };
         // Just use empty files for sources.
         for src in ["foo.rs"].iter() {
             let s = format!("Failed to create {}", src);
@@ -3038,10 +3784,13 @@ c:/foo/bar.rs:
         let hasher = Box::new(RustHasher {
             executable: "rustc".into(),
             host: "x86-64-unknown-unknown-unknown".to_owned(),
+            version: None,
             sysroot: f.tempdir.path().join("sysroot"),
+            builtin_targets: vec![],
             compiler_shlibs_digests: vec![],
             #[cfg(feature = "dist-client")]
             rlib_dep_reader: None,
+            config: Default::default(),
             parsed_args,
         });
 
@@ -3068,6 +3817,135 @@ c:/foo/bar.rs:
         Ok(())
     }
 
+    #[test]
+    fn test_target_spec_affects_hash() {
+        let f = TestFixture::new();
+        let args = &[
+            "--emit",
+            "link",
+            "foo.rs",
+            "--out-dir",
+            "out",
+            "--crate-name",
+            "foo",
+            "--crate-type",
+            "lib",
+            "--target",
+            "specs/custom.json",
+        ];
+        create_file(f.tempdir.path(), "specs/custom.json", |mut f| {
+            f.write_all(b"{\"arch\": \"arm\"}")
+        })
+        .unwrap();
+        let h1 = hash_key(&f, args, &[], nothing);
+        create_file(f.tempdir.path(), "specs/custom.json", |mut f| {
+            f.write_all(b"{\"arch\": \"riscv32\"}")
+        })
+        .unwrap();
+        assert_neq!(h1, hash_key(&f, args, &[], nothing));
+    }
+
+    #[test]
+    fn test_target_find_spec() {
+        let f = TestFixture::new();
+        let cwd = f.tempdir.path();
+        let spec = f.touch("specs/custom.json").unwrap();
+        let local = f.touch("local.json").unwrap();
+        f.touch("specs/x86_64-unknown-linux-gnu.json").unwrap();
+        let builtin = stringvec!["x86_64-unknown-linux-gnu"];
+        let target_path = vec![(OsString::from("RUST_TARGET_PATH"), OsString::from("specs"))];
+        assert_eq!(
+            ArgTarget::Path("specs/custom.json".into()).find_spec(&builtin, cwd, &[]),
+            Some(cwd.join("specs/custom.json"))
+        );
+        assert_eq!(
+            ArgTarget::Name("custom".into()).find_spec(&builtin, cwd, &target_path),
+            Some(spec)
+        );
+        assert_eq!(
+            ArgTarget::Name("custom".into()).find_spec(&builtin, cwd, &[]),
+            None
+        );
+        assert_eq!(
+            ArgTarget::Unsure("local".into()).find_spec(&builtin, cwd, &[]),
+            Some(local)
+        );
+        // Built-in targets are never looked for, even if a spec exists.
+        assert_eq!(
+            ArgTarget::Name("x86_64-unknown-linux-gnu".into()).find_spec(
+                &builtin,
+                cwd,
+                &target_path
+            ),
+            None
+        );
+        assert_eq!(
+            ArgTarget::Path("specs/custom.json".into()).triple(),
+            "custom"
+        );
+    }
+
+    #[test]
+    fn test_linker_inputs_affect_hash() {
+        let f = TestFixture::new();
+        let args = &[
+            "--emit",
+            "link",
+            "foo.rs",
+            "--out-dir",
+            "out",
+            "--crate-name",
+            "foo",
+            "--crate-type",
+            "bin",
+            "-C",
+            "linker=./my-linker",
+            "-C",
+            "link-arg=-Wl,--version-script=foo.map",
+        ];
+        f.touch("foo.map").unwrap();
+        create_file(f.tempdir.path(), "my-linker", |mut f| f.write_all(b"v1")).unwrap();
+        let h1 = hash_key(&f, args, &[], nothing);
+        create_file(f.tempdir.path(), "my-linker", |mut f| f.write_all(b"v2")).unwrap();
+        let h2 = hash_key(&f, args, &[], nothing);
+        assert_neq!(h1, h2);
+        create_file(f.tempdir.path(), "foo.map", |mut f| f.write_all(b"{};")).unwrap();
+        assert_neq!(h2, hash_key(&f, args, &[], nothing));
+    }
+
+    #[test]
+    fn test_hash_metadata() {
+        fn mk_rlib(path: &Path, metadata: &[u8], code: &[u8]) {
+            let mut builder = ar::Builder::new(File::create(path).unwrap());
+            for (name, data) in &[("foo.o", code), ("lib.rmeta", metadata)] {
+                let header = ar::Header::new(name.as_bytes().to_vec(), data.len() as u64);
+                builder.append(&header, *data).unwrap();
+            }
+        }
+        let f = TestFixture::new();
+        let rlib = f.tempdir.path().join("libfoo.rlib");
+        mk_rlib(&rlib, b"interface", b"code");
+        let h1 = hash_metadata(&rlib).unwrap();
+        assert_eq!(h1, Digest::reader_sync(&b"interface"[..]).unwrap());
+        mk_rlib(&rlib, b"interface", b"faster code");
+        assert_eq!(h1, hash_metadata(&rlib).unwrap());
+        mk_rlib(&rlib, b"new interface", b"faster code");
+        assert_neq!(h1, hash_metadata(&rlib).unwrap());
+        // Anything else is hashed in full.
+        let rmeta = f.tempdir.path().join("libfoo.rmeta");
+        create_file(f.tempdir.path(), "libfoo.rmeta", |mut f| {
+            f.write_all(b"interface")
+        })
+        .unwrap();
+        assert_eq!(h1, hash_metadata(&rmeta).unwrap());
+        let not_an_rlib = f.tempdir.path().join("libbar.rlib");
+        create_file(f.tempdir.path(), "libbar.rlib", |mut f| f.write_all(b"bar")).unwrap();
+        assert_eq!(
+            Digest::reader_sync(&b"bar"[..]).unwrap(),
+            hash_metadata(&not_an_rlib).unwrap()
+        );
+    }
+
     #[test]
     fn test_equal_hashes_externs() {
         // Put some content in the extern rlibs so we can verify that the content hashes are
@@ -0,0 +1,752 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::compiler::args::*;
+use crate::compiler::rust::{
+    get_source_files, hash_all_metadata, hash_env, ArgExtern, ArgTarget, Rust, RustupProxy,
+};
+#[cfg(feature = "dist-client")]
+use crate::compiler::DistPackagers;
+use crate::compiler::{
+    compiler_check_digests, Cacheable, ColorMode, Compilation, CompileCommand, Compiler,
+    CompilerArguments, CompilerHasher, CompilerKind, CompilerProxy, DetectedCompiler,
+    DetectedProxy, HashResult,
+};
+use crate::dist;
+#[cfg(feature = "dist-client")]
+use crate::dist::pkg;
+use crate::mock_command::CommandCreatorSync;
+use crate::util::{hash_all, Digest, HashToDigest};
+use filetime::FileTime;
+use futures::Future;
+use futures_03::executor::ThreadPool;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::env::consts::EXE_SUFFIX;
+use std::ffi::OsString;
+use std::fs;
+use std::hash::Hash;
+use std::path::{Path, PathBuf};
+
+use crate::errors::*;
+
+/// Version number for cache key.
+const CACHE_VERSION: &[u8] = b"1";
+
+/// The directory rustdoc writes to when no `--out-dir` is passed.
+const DEFAULT_OUT_DIR: &str = "doc";
+
+/// The file rustdoc writes the crate's parts of the shared files to, in the
+/// directory passed to `--parts-out-dir`.
+const CRATE_INFO_FILE: &str = "crate-info";
+
+/// A struct on which to hang a `Compiler` impl.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rustdoc {
+    /// The path to the rustdoc executable.
+    pub(crate) executable: PathBuf,
+    /// The digest of the rustdoc executable.
+    executable_digest: String,
+    /// The rustc from the same toolchain, which finds the source files.
+    pub(crate) rustc: Rust,
+}
+
+/// A struct on which to hang a `CompilerHasher` impl.
+#[derive(Debug, Clone)]
+pub struct RustdocHasher {
+    /// The path to the rustdoc executable, not the rustup proxy.
+    executable: PathBuf,
+    /// The digest of the rustdoc executable.
+    executable_digest: String,
+    /// The rustc from the same toolchain, which finds the source files.
+    rustc: Rust,
+    /// Parsed arguments from the rustdoc invocation
+    parsed_args: ParsedArguments,
+}
+
+/// A lookup proxy resolving rustdoc through the rustup proxy for its rustc.
+#[derive(Debug, Clone)]
+pub struct RustdocProxy(RustupProxy);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedArguments {
+    /// The full commandline, with all parsed aguments
+    arguments: Vec<Argument<ArgData>>,
+    /// The location of the generated documentation.
+    output_dir: PathBuf,
+    /// The directory passed to `--parts-out-dir`, if any.
+    parts_out_dir: Option<PathBuf>,
+    /// Paths to extern crates used in the compile.
+    externs: Vec<PathBuf>,
+    /// The target passed with `--target`.
+    target: Option<ArgTarget>,
+    /// The crate name passed to --crate-name.
+    crate_name: String,
+    /// The value of any `--color` option passed on the commandline.
+    color_mode: ColorMode,
+}
+
+/// A struct on which to hang a `Compilation` impl.
+#[derive(Debug, Clone)]
+pub struct RustdocCompilation {
+    /// The path to the rustdoc executable, not the rustup proxy.
+    executable: PathBuf,
+    /// All arguments passed to rustdoc
+    arguments: Vec<Argument<ArgData>>,
+    /// The generated documentation directories and files.
+    outputs: HashMap<String, PathBuf>,
+    /// The crate name being documented.
+    crate_name: String,
+    /// The current working directory
+    cwd: PathBuf,
+    /// The environment variables
+    env_vars: Vec<(OsString, OsString)>,
+}
+
+impl Rustdoc {
+    /// Create a new rustdoc compiler instance for the rustdoc at `executable`,
+    /// which comes with `rustc`.
+    pub fn new<T>(
+        executable: PathBuf,
+        rustc: Rust,
+        creator: &T,
+        env_vars: &[(OsString, OsString)],
+        pool: &ThreadPool,
+    ) -> SFuture<Rustdoc>
+    where
+        T: CommandCreatorSync,
+    {
+        let digests = compiler_check_digests(
+            creator,
+            &executable,
+            vec![executable.clone()],
+            &rustc.config.compiler_check,
+            env_vars,
+            pool,
+        );
+        Box::new(digests.map(move |digests| Rustdoc {
+            executable,
+            executable_digest: digests.concat(),
+            rustc,
+        }))
+    }
+
+    /// The path of the rustdoc that comes with the rustc at `rustc`.
+    pub fn sibling_of(rustc: &Path) -> PathBuf {
+        rustc.with_file_name(format!("rustdoc{}", EXE_SUFFIX))
+    }
+}
+
+impl<T> Compiler<T> for Rustdoc
+where
+    T: CommandCreatorSync,
+{
+    fn kind(&self) -> CompilerKind {
+        CompilerKind::Rustdoc
+    }
+    fn executable(&self) -> &Path {
+        &self.executable
+    }
+    #[cfg(feature = "dist-client")]
+    fn get_toolchain_packager(&self) -> Box<dyn pkg::ToolchainPackager> {
+        Compiler::<T>::get_toolchain_packager(&self.rustc)
+    }
+    /// Parse `arguments` as rustdoc command-line arguments, determine if
+    /// we can cache the result. This is only intended to cover the
+    /// invocations made by `cargo doc -Zrustdoc-mergeable-info`, which
+    /// needs a nightly toolchain.
+    ///
+    /// Caveats:
+    /// * We don't support documenting from stdin, or Markdown files.
+    /// * We don't support doctests (`--test`), which rustdoc compiles and
+    ///   runs in one go, leaving nothing to cache.
+    /// * We only support HTML output.
+    /// * We only support `--merge=none`, where rustdoc leaves the files
+    ///   shared between crates alone. By default it merges the crate into
+    ///   them in place, which can't be replayed from the cache, so plain
+    ///   `cargo doc` isn't cached.
+    fn parse_arguments(
+        &self,
+        arguments: &[OsString],
+        cwd: &Path,
+    ) -> CompilerArguments<Box<dyn CompilerHasher<T> + 'static>> {
+        match parse_arguments(arguments, cwd) {
+            CompilerArguments::Ok(args) => CompilerArguments::Ok(Box::new(RustdocHasher {
+                executable: self.executable.clone(),
+                executable_digest: self.executable_digest.clone(),
+                rustc: self.rustc.clone(),
+                parsed_args: args,
+            })),
+            CompilerArguments::NotCompilation => CompilerArguments::NotCompilation,
+            CompilerArguments::CannotCache(why, extra_info) => {
+                CompilerArguments::CannotCache(why, extra_info)
+            }
+            CompilerArguments::Multiple(_) => unreachable!("rustdoc documents a single crate"),
+        }
+    }
+
+    fn detected(&self) -> Option<DetectedCompiler> {
+        Some(DetectedCompiler::Rustdoc(self.clone()))
+    }
+
+    fn box_clone(&self) -> Box<dyn Compiler<T>> {
+        Box::new((*self).clone())
+    }
+}
+
+impl<T> CompilerProxy<T> for RustdocProxy
+where
+    T: CommandCreatorSync,
+{
+    fn resolve_proxied_executable(
+        &self,
+        creator: T,
+        cwd: PathBuf,
+        env: &[(OsString, OsString)],
+    ) -> SFuture<(PathBuf, FileTime)> {
+        let rustc = self.0.resolve_proxied_executable(creator, cwd, env);
+        Box::new(rustc.and_then(|(rustc, _)| {
+            let rustdoc = Rustdoc::sibling_of(&rustc);
+            let attr = fs::metadata(&rustdoc).with_context(|| {
+                format!(
+                    "Failed to obtain metadata of the resolved, true rustdoc: {:?}",
+                    rustdoc
+                )
+            })?;
+            Ok((rustdoc, FileTime::from_last_modification_time(&attr)))
+        }))
+    }
+
+    fn detected(&self) -> Option<DetectedProxy> {
+        match CompilerProxy::<T>::detected(&self.0) {
+            Some(DetectedProxy::Rustup(path)) => Some(DetectedProxy::Rustdoc(path)),
+            _ => None,
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn CompilerProxy<T>> {
+        Box::new((*self).clone())
+    }
+}
+
+impl RustdocProxy {
+    pub fn new(rustc_proxy: RustupProxy) -> Self {
+        RustdocProxy(rustc_proxy)
+    }
+}
+
+ArgData! {
+    TooHardFlag,
+    TooHard(OsString),
+    TooHardPath(PathBuf),
+    NotCompilationFlag,
+    NotCompilation(OsString),
+    Color(String),
+    CrateName(String),
+    Extern(ArgExtern),
+    LinkPath(OsString),
+    Merge(OsString),
+    OutDir(PathBuf),
+    PartsOutDir(PathBuf),
+    PassThroughFlag,
+    PassThrough(OsString),
+    Rustc(OsString),
+    Target(ArgTarget),
+}
+
+use self::ArgData::*;
+
+// `Rustc` arguments are also passed to rustc when finding the source files.
+counted_array!(static ARGS: [ArgInfo<ArgData>; _] = [
+    flag!("-", TooHardFlag),
+    take_arg!("--allow", OsString, CanBeSeparated('='), PassThrough),
+    take_arg!("--cap-lints", OsString, CanBeSeparated('='), PassThrough),
+    take_arg!("--cfg", OsString, CanBeSeparated('='), Rustc),
+    take_arg!("--check-theme", OsString, CanBeSeparated('='), NotCompilation),
+    take_arg!("--codegen", OsString, CanBeSeparated('='), Rustc),
+    take_arg!("--color", String, CanBeSeparated('='), Color),
+    take_arg!("--crate-name", String, CanBeSeparated('='), CrateName),
+    take_arg!("--crate-type", OsString, CanBeSeparated('='), Rustc),
+    take_arg!("--crate-version", OsString, CanBeSeparated('='), PassThrough),
+    take_arg!("--default-theme", OsString, CanBeSeparated('='), PassThrough),
+    take_arg!("--deny", OsString, CanBeSeparated('='), PassThrough),
+    flag!("--document-private-items", PassThroughFlag),
+    take_arg!("--edition", OsString, CanBeSeparated('='), Rustc),
+    take_arg!("--emit", OsString, CanBeSeparated('='), TooHard),
+    take_arg!("--error-format", OsString, CanBeSeparated('='), PassThrough),
+    take_arg!("--extend-css", PathBuf, CanBeSeparated('='), TooHardPath),
+    take_arg!("--extern", ArgExtern, CanBeSeparated('='), Extern),
+    take_arg!("--extern-html-root-url", OsString, CanBeSeparated('='), PassThrough),
+    take_arg!("--forbid", OsString, CanBeSeparated('='), PassThrough),
+    flag!("--help", NotCompilationFlag),
+    take_arg!("--html-after-content", PathBuf, CanBeSeparated('='), TooHardPath),
+    take_arg!("--html-before-content", PathBuf, CanBeSeparated('='), TooHardPath),
+    take_arg!("--html-in-header", PathBuf, CanBeSeparated('='), TooHardPath),
+    take_arg!("--include-parts-dir", PathBuf, CanBeSeparated('='), TooHardPath),
+    take_arg!("--index-page", PathBuf, CanBeSeparated('='), TooHardPath),
+    take_arg!("--json", OsString, CanBeSeparated('='), PassThrough),
+    take_arg!("--markdown-css", PathBuf, CanBeSeparated('='), TooHardPath),
+    take_arg!("--merge", OsString, CanBeSeparated('='), Merge),
+    take_arg!("--out-dir", PathBuf, CanBeSeparated('='), OutDir),
+    take_arg!("--output", PathBuf, CanBeSeparated('='), OutDir),
+    take_arg!("--output-format", OsString, CanBeSeparated('='), TooHard),
+    take_arg!("--parts-out-dir", PathBuf, CanBeSeparated('='), PartsOutDir),
+    take_arg!("--resource-suffix", OsString, CanBeSeparated('='), PassThrough),
+    take_arg!("--sysroot", PathBuf, CanBeSeparated('='), TooHardPath),
+    take_arg!("--target", ArgTarget, CanBeSeparated('='), Target),
+    flag!("--test", TooHardFlag),
+    take_arg!("--theme", PathBuf, CanBeSeparated('='), TooHardPath),
+    flag!("--version", NotCompilationFlag),
+    take_arg!("--warn", OsString, CanBeSeparated('='), PassThrough),
+    take_arg!("-A", OsString, CanBeSeparated, PassThrough),
+    take_arg!("-C", OsString, CanBeSeparated, Rustc),
+    take_arg!("-D", OsString, CanBeSeparated, PassThrough),
+    take_arg!("-F", OsString, CanBeSeparated, PassThrough),
+    take_arg!("-L", OsString, CanBeSeparated, LinkPath),
+    flag!("-V", NotCompilationFlag),
+    take_arg!("-W", OsString, CanBeSeparated, PassThrough),
+    take_arg!("-Z", OsString, CanBeSeparated, PassThrough),
+    flag!("-h", NotCompilationFlag),
+    take_arg!("-o", PathBuf, CanBeSeparated, OutDir),
+    take_arg!("-w", OsString, CanBeSeparated, TooHard),
+]);
+
+fn parse_arguments(arguments: &[OsString], cwd: &Path) -> CompilerArguments<ParsedArguments> {
+    let mut args = vec![];
+
+    let mut input = None;
+    let mut output_dir = None;
+    let mut parts_out_dir = None;
+    let mut merge = None;
+    let mut crate_name = None;
+    let mut externs = vec![];
+    let mut target = None;
+    let mut color_mode = ColorMode::Auto;
+
+    for arg in ArgsIter::new(arguments.iter().cloned(), &ARGS[..]) {
+        let arg = try_or_cannot_cache!(arg, "argument parse");
+        match arg.get_data() {
+            Some(TooHardFlag) | Some(TooHard(_)) | Some(TooHardPath(_)) => {
+                cannot_cache!(arg.flag_str().expect("Can't be Argument::Raw/UnknownFlag",))
+            }
+            Some(NotCompilationFlag) | Some(NotCompilation(_)) => {
+                return CompilerArguments::NotCompilation
+            }
+            Some(Color(value)) => {
+                // We'll just assume the last specified value wins.
+                color_mode = match value.as_ref() {
+                    "always" => ColorMode::On,
+                    "never" => ColorMode::Off,
+                    _ => ColorMode::Auto,
+                };
+            }
+            Some(CrateName(value)) => crate_name = Some(value.clone()),
+            Some(Extern(ArgExtern { path, .. })) => externs.push(cwd.join(path)),
+            Some(Merge(value)) => merge = Some(value.clone()),
+            Some(OutDir(value)) => output_dir = Some(value.clone()),
+            Some(PartsOutDir(value)) => parts_out_dir = Some(value.clone()),
+            Some(Target(value)) => target = Some(value.clone()),
+            Some(LinkPath(_))
+            | Some(PartsOutDir(_))
+            | Some(PassThroughFlag)
+            | Some(PassThrough(_))
+            | Some(Rustc(_)) => {}
+            None => match arg {
+                Argument::Raw(ref val) => {
+                    if input.is_some() {
+                        // Can't cache compilations with multiple inputs.
+                        cannot_cache!("multiple input files");
+                    }
+                    input = Some(val.clone());
+                }
+                Argument::UnknownFlag(_) => {}
+                _ => unreachable!(),
+            },
+        }
+        // We'll drop --color arguments, we're going to pass --color=always and the client will
+        // strip colors if necessary.
+        match arg.get_data() {
+            Some(Color(_)) => {}
+            _ => args.push(arg.normalize(NormalizedDisposition::Separated)),
+        }
+    }
+
+    // Unwrap required values.
+    macro_rules! req {
+        ($x:ident) => {
+            let $x = if let Some($x) = $x {
+                $x
+            } else {
+                debug!("Can't cache compilation, missing `{}`", stringify!($x));
+                cannot_cache!(concat!("missing ", stringify!($x)));
+            };
+        };
+    }
+    req!(input);
+    req!(crate_name);
+    // Markdown files are rendered standalone, with no crate to find the
+    // sources of.
+    if Path::new(&input)
+        .extension()
+        .map(|ext| ext == "md")
+        .unwrap_or(false)
+    {
+        cannot_cache!("markdown input");
+    }
+    // Unless told otherwise, rustdoc adds the crate to the search index and
+    // the other files shared between crates in the output directory. Those
+    // are updated in place, so restoring them from the cache would lose the
+    // other crates' entries. With `--merge=none`, the crate's parts go to
+    // `--parts-out-dir` instead, for a later `--merge=finalize` run.
+    if merge.as_ref().map_or(true, |merge| merge != "none") {
+        cannot_cache!("rustdoc shared files");
+    }
+    // Cargo doesn't deterministically order --externs, and we need the hash inputs in a
+    // deterministic order.
+    externs.sort();
+    CompilerArguments::Ok(ParsedArguments {
+        arguments: args,
+        output_dir: output_dir.unwrap_or_else(|| PathBuf::from(DEFAULT_OUT_DIR)),
+        parts_out_dir,
+        externs,
+        target,
+        crate_name,
+        color_mode,
+    })
+}
+
+impl<T> CompilerHasher<T> for RustdocHasher
+where
+    T: CommandCreatorSync,
+{
+    fn generate_hash_key(
+        self: Box<Self>,
+        creator: &T,
+        cwd: PathBuf,
+        env_vars: Vec<(OsString, OsString)>,
+        _may_dist: bool,
+        pool: &ThreadPool,
+        _rewrite_includes_only: bool,
+    ) -> SFuture<HashResult> {
+        let RustdocHasher {
+            executable,
+            executable_digest,
+            rustc,
+            parsed_args:
+                ParsedArguments {
+                    arguments,
+                    output_dir,
+                    parts_out_dir,
+                    externs,
+                    target,
+                    crate_name,
+                    ..
+                },
+        } = *self;
+        trace!("[{}]: generate_hash_key", crate_name);
+        // rustdoc compiles the crate with `--cfg doc`, so ask rustc for the
+        // source files of that configuration.
+        let rustc_arguments: Vec<OsString> = arguments
+            .iter()
+            .filter(|arg| match arg.get_data() {
+                Some(CrateName(_)) | Some(Extern(_)) | Some(LinkPath(_)) | Some(Rustc(_))
+                | Some(Target(_)) => true,
+                Some(_) => false,
+                None => matches!(arg, Argument::Raw(_)),
+            })
+            .flat_map(|arg| arg.iter_os_strings())
+            .chain(vec!["--cfg".into(), "doc".into()])
+            .collect();
+        let source_hashes_pool = pool.clone();
+        let source_files = get_source_files(
+            creator,
+            &crate_name,
+            &rustc.executable,
+            &rustc_arguments,
+            &cwd,
+            &env_vars,
+            pool,
+        );
+        let source_hashes = source_files.and_then(move |(source_files, env_deps)| {
+            hash_all(&source_files, &source_hashes_pool).map(|hashes| (hashes, env_deps))
+        });
+        // Hash the contents of the externs listed on the commandline, and the
+        // spec of a custom target. Documentation only depends on the metadata
+        // of the externs, which is all that early cutoff hashes.
+        trace!("[{}]: hashing {} externs", crate_name, externs.len());
+        let extern_hashes = if rustc.config.early_cutoff {
+            hash_all_metadata(&externs, pool)
+        } else {
+            hash_all(&externs, pool)
+        };
+        let target_spec = target.and_then(|t| t.find_spec(&rustc.builtin_targets, &cwd, &env_vars));
+        let target_spec_hashes = hash_all(&target_spec.into_iter().collect::<Vec<_>>(), pool);
+        let hashes = source_hashes.join3(extern_hashes, target_spec_hashes);
+        Box::new(hashes.map(
+            move |((source_hashes, env_deps), extern_hashes, target_spec_hashes)| {
+                // If you change any of the inputs to the hash, you should change `CACHE_VERSION`.
+                let mut m = Digest::new();
+                // Hash inputs:
+                // 1. A version
+                m.update(CACHE_VERSION);
+                // 2. The rustdoc executable and rustc's compiler_shlibs_digests
+                m.update(executable_digest.as_bytes());
+                for d in &rustc.compiler_shlibs_digests {
+                    m.update(d.as_bytes());
+                }
+                let weak_toolchain_key = m.clone().finish();
+                // 3. The full commandline, leaving out the paths of externs,
+                // -L and the output directories, and sorting --cfg.
+                let (mut sortables, rest): (Vec<_>, Vec<_>) = arguments
+                    .iter()
+                    .filter(|arg| {
+                        !matches!(
+                            arg.get_data(),
+                            Some(Extern(_))
+                                | Some(LinkPath(_))
+                                | Some(OutDir(_))
+                                | Some(PartsOutDir(_))
+                        )
+                    })
+                    .map(|arg| arg.iter_os_strings().collect::<Vec<_>>())
+                    .partition(|arg| arg[0] == "--cfg");
+                sortables.sort();
+                for arg in rest.into_iter().chain(sortables) {
+                    arg.hash(&mut HashToDigest { digest: &mut m });
+                }
+                // 4. The digest of all source files, externs and the target spec.
+                for h in source_hashes
+                    .into_iter()
+                    .chain(extern_hashes)
+                    .chain(target_spec_hashes)
+                {
+                    m.update(h.as_bytes());
+                }
+                // 5. Environment variables (see `hash_env`).
+                let mut env_vars = env_vars;
+                env_vars.sort();
+                hash_env(&mut m, &rustc.config, &env_vars, env_deps);
+                // 6. The cwd of the compile, as source links are relative to it.
+                cwd.hash(&mut HashToDigest { digest: &mut m });
+
+                // The pages for the crate and its rendered sources, and the
+                // crate's parts of the shared files, if requested.
+                let outputs = [format!("{}/", crate_name), format!("src/{}/", crate_name)]
+                    .iter()
+                    .map(|key| (key.clone(), output_dir.join(key)))
+                    .chain(
+                        parts_out_dir
+                            .map(|dir| (CRATE_INFO_FILE.to_owned(), dir.join(CRATE_INFO_FILE))),
+                    )
+                    .collect();
+                let mut arguments = arguments;
+                // Request color output. The client will strip colors if needed.
+                arguments.push(Argument::WithValue(
+                    "--color",
+                    ArgData::Color("always".into()),
+                    ArgDisposition::Separated,
+                ));
+                HashResult {
+                    key: m.finish(),
+                    compilation: Box::new(RustdocCompilation {
+                        executable,
+                        arguments,
+                        outputs,
+                        crate_name,
+                        cwd,
+                        env_vars,
+                    }),
+                    weak_toolchain_key,
+                }
+            },
+        ))
+    }
+
+    fn color_mode(&self) -> ColorMode {
+        self.parsed_args.color_mode
+    }
+
+    fn kind(&self) -> CompilerKind {
+        CompilerKind::Rustdoc
+    }
+
+    fn version(&self) -> Option<String> {
+        self.rustc.version.clone()
+    }
+
+    fn output_pretty(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.parsed_args.crate_name)
+    }
+
+    fn box_clone(&self) -> Box<dyn CompilerHasher<T>> {
+        Box::new((*self).clone())
+    }
+}
+
+impl Compilation for RustdocCompilation {
+    fn generate_compile_commands(
+        &self,
+        _path_transformer: &mut dist::PathTransformer,
+        _rewrite_includes_only: bool,
+    ) -> Result<(CompileCommand, Option<dist::CompileCommand>, Cacheable)> {
+        trace!("[{}]: compile", self.crate_name);
+        let command = CompileCommand {
+            executable: self.executable.clone(),
+            arguments: self
+                .arguments
+                .iter()
+                .flat_map(|arg| arg.iter_os_strings())
+                .collect(),
+            env_vars: self.env_vars.clone(),
+            cwd: self.cwd.clone(),
+        };
+        // The documentation is cheap to generate locally compared to shipping
+        // the sources and extern metadata, so it's never distributed.
+        Ok((command, None, Cacheable::Yes))
+    }
+
+    #[cfg(feature = "dist-client")]
+    fn into_dist_packagers(
+        self: Box<Self>,
+        _path_transformer: dist::PathTransformer,
+    ) -> Result<DistPackagers> {
+        bail!("rustdoc invocations are never distributed")
+    }
+
+    fn outputs<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a str, &'a Path)> + 'a> {
+        Box::new(self.outputs.iter().map(|(k, v)| (k.as_str(), &**v)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::*;
+    use itertools::Itertools;
+
+    fn _parse_arguments(arguments: &[String]) -> CompilerArguments<ParsedArguments> {
+        let arguments = arguments.iter().map(OsString::from).collect::<Vec<_>>();
+        parse_arguments(&arguments, ".".as_ref())
+    }
+
+    macro_rules! parses {
+        ( $( $s:expr ),* ) => {
+            match _parse_arguments(&[ $( $s.to_string(), )* ]) {
+                CompilerArguments::Ok(a) => a,
+                o => panic!("Got unexpected parse result: {:?}", o),
+            }
+        }
+    }
+
+    macro_rules! fails {
+        ( $( $s:expr ),* ) => {
+            match _parse_arguments(&[ $( $s.to_string(), )* ]) {
+                CompilerArguments::Ok(_) => panic!("Should not have parsed ok: `{}`", stringify!($($s,)*)),
+
+                o => o,
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_arguments_simple() {
+        let h = parses!(
+            "--edition=2018",
+            "--crate-type",
+            "lib",
+            "--crate-name",
+            "foo",
+            "src/lib.rs",
+            "-o",
+            "/out/doc",
+            "--error-format=json",
+            "--json=diagnostic-rendered-ansi",
+            "--extern",
+            "b=/out/deps/libb.rmeta",
+            "--extern",
+            "a=/out/deps/liba.rmeta",
+            "-L",
+            "dependency=/out/deps",
+            "--color=never",
+            "--crate-version",
+            "0.1.0",
+            "-Zunstable-options",
+            "--merge=none",
+            "--parts-out-dir=/out/doc.parts/foo"
+        );
+        assert_eq!(h.crate_name, "foo");
+        assert_eq!(h.output_dir, Path::new("/out/doc"));
+        assert_eq!(h.parts_out_dir, Some(PathBuf::from("/out/doc.parts/foo")));
+        assert_eq!(
+            h.externs,
+            vec![
+                PathBuf::from("/out/deps/liba.rmeta"),
+                PathBuf::from("/out/deps/libb.rmeta")
+            ]
+        );
+        assert_eq!(h.color_mode, ColorMode::Off);
+        assert!(!h
+            .arguments
+            .iter()
+            .any(|arg| matches!(arg.get_data(), Some(Color(_)))));
+
+        let h = parses!("--crate-name", "foo", "src/lib.rs", "--merge", "none");
+        assert_eq!(h.output_dir, Path::new("doc"));
+        assert_eq!(h.parts_out_dir, None);
+        assert_eq!(
+            h.arguments
+                .iter()
+                .flat_map(|arg| arg.iter_os_strings())
+                .map(|s| s.into_string().unwrap())
+                .join(" "),
+            "--crate-name foo src/lib.rs --merge none"
+        );
+    }
+
+    #[test]
+    fn test_parse_arguments_not_cacheable() {
+        assert_eq!(
+            CompilerArguments::CannotCache("--test", None),
+            fails!("--test", "--crate-name", "foo", "src/lib.rs")
+        );
+        assert_eq!(CompilerArguments::NotCompilation, fails!("-V"));
+        fails!("--crate-name", "foo", "README.md", "--merge=none");
+        fails!("src/lib.rs", "--merge=none");
+        // Without `--merge=none`, rustdoc updates the shared files in place.
+        fails!("--crate-name", "foo", "src/lib.rs");
+        fails!("--crate-name", "foo", "src/lib.rs", "--merge=shared");
+        fails!(
+            "--crate-name",
+            "foo",
+            "src/lib.rs",
+            "--merge=finalize",
+            "--include-parts-dir=doc.parts/bar"
+        );
+        fails!(
+            "--crate-name",
+            "foo",
+            "src/lib.rs",
+            "--output-format",
+            "json"
+        );
+        fails!(
+            "--crate-name",
+            "foo",
+            "src/lib.rs",
+            "--html-in-header",
+            "header.html"
+        );
+        fails!("--crate-name", "foo", "-");
+    }
+}
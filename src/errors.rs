@@ -37,6 +37,10 @@ pub struct HttpClientError(pub String);
 #[derive(Debug)]
 pub struct ProcessError(pub process::Output);
 
+/// A server older than the client, which can be replaced by a new one.
+#[derive(Debug)]
+pub struct StaleServerError(pub String);
+
 #[cfg(feature = "hyper")]
 impl std::error::Error for BadHttpStatusError {}
 
@@ -44,6 +48,8 @@ impl std::error::Error for HttpClientError {}
 
 impl std::error::Error for ProcessError {}
 
+impl std::error::Error for StaleServerError {}
+
 #[cfg(feature = "hyper")]
 impl std::fmt::Display for BadHttpStatusError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -63,6 +69,12 @@ impl std::fmt::Display for ProcessError {
     }
 }
 
+impl std::fmt::Display for StaleServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 pub type Result<T> = anyhow::Result<T>;
 
 pub type SFuture<T> = Box<dyn Future<Item = T, Error = Error>>;
@@ -57,6 +57,7 @@ mod protocol;
 pub mod server;
 #[cfg(feature = "simple-s3")]
 mod simples3;
+mod stats_history;
 #[doc(hidden)]
 pub mod util;
 
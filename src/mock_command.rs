@@ -344,7 +344,7 @@ pub struct MockChild {
     pub stdout: Option<io::Cursor<Vec<u8>>>,
     /// A `Cursor` to hand out as stderr.
     pub stderr: Option<io::Cursor<Vec<u8>>>,
-    /// The `Result` to be handed out when `wait` is called.
+    /// The `Result` to be handed out when `wait` is called, or `None` to never exit.
     pub wait_result: Option<io::Result<ExitStatus>>,
 }
 
@@ -365,6 +365,17 @@ impl MockChild {
         }
     }
 
+    /// Create a `MockChild` that never exits, like a long-running compile.
+    #[allow(dead_code)]
+    pub fn never_exits() -> MockChild {
+        MockChild {
+            stdin: Some(io::Cursor::new(vec![])),
+            stdout: Some(io::Cursor::new(vec![])),
+            stderr: Some(io::Cursor::new(vec![])),
+            wait_result: None,
+        }
+    }
+
     /// Create a `MockChild` that will return the specified `err` when waited upon.
     #[allow(dead_code)]
     pub fn with_error(err: io::Error) -> MockChild {
@@ -393,7 +404,10 @@ impl CommandChild for MockChild {
     }
 
     fn wait(mut self) -> Box<dyn Future<Item = ExitStatus, Error = io::Error>> {
-        Box::new(future::result(self.wait_result.take().unwrap()))
+        match self.wait_result.take() {
+            Some(result) => Box::new(future::result(result)),
+            None => Box::new(future::empty()),
+        }
     }
 
     fn wait_with_output(self) -> Box<dyn Future<Item = Output, Error = io::Error>> {
@@ -403,7 +417,11 @@ impl CommandChild for MockChild {
             wait_result,
             ..
         } = self;
-        let result = wait_result.unwrap().map(|status| Output {
+        let wait_result = match wait_result {
+            Some(wait_result) => wait_result,
+            None => return Box::new(future::empty()),
+        };
+        let result = wait_result.map(|status| Output {
             status,
             stdout: stdout.map(|c| c.into_inner()).unwrap_or_else(Vec::new),
             stderr: stderr.map(|c| c.into_inner()).unwrap_or_else(Vec::new),
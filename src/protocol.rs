@@ -1,7 +1,14 @@
 use crate::compiler::ColorMode;
 use crate::server::{DistInfo, ServerInfo};
+use crate::stats_history::StatsHistory;
 use std::ffi::OsString;
 
+/// The version of the client/server protocol.
+///
+/// This must be bumped whenever `Request`, `Response` or any type they contain
+/// changes in a way that alters its bincode encoding.
+pub const PROTOCOL_VERSION: u32 = 6;
+
 /// A client request.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Request {
@@ -15,6 +22,13 @@ pub enum Request {
     Shutdown,
     /// Execute a compile or fetch a cached compilation result.
     Compile(Compile),
+    /// Exchange protocol and binary versions with the server.
+    Handshake(Handshake),
+    /// Get server statistics recorded across server runs.
+    GetStatsHistory,
+    /// Load the configuration again and switch to the storage and
+    /// distributed compilation settings it describes.
+    ReloadConfig,
 }
 
 /// A server response.
@@ -30,6 +44,46 @@ pub enum Response {
     ShuttingDown(Box<ServerInfo>),
     /// Second response for `Request::Compile`, containing the results of the compilation.
     CompileFinished(CompileFinished),
+    /// Response for `Request::Handshake`, containing the server's versions.
+    Handshake(Handshake),
+    /// Response for `Request::GetStatsHistory`.
+    StatsHistory(Box<StatsHistory>),
+    /// Response for `Request::ReloadConfig`, containing server statistics
+    /// and the new cache location.
+    ConfigReloaded(Box<ServerInfo>),
+    /// Response for `Request::ReloadConfig` when the configuration couldn't
+    /// be loaded, and the server kept the old one.
+    ConfigReloadFailed(String),
+}
+
+/// The versions a client or server speaks, exchanged when a client connects.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    /// The client/server protocol version, see `PROTOCOL_VERSION`.
+    pub protocol_version: u32,
+    /// The sccache version of the binary.
+    pub version: String,
+}
+
+impl Handshake {
+    /// The versions spoken by this binary.
+    pub fn current() -> Handshake {
+        Handshake {
+            protocol_version: PROTOCOL_VERSION,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    /// Whether these versions are older than `other`, comparing the protocol
+    /// version first and then the numeric parts of the sccache version.
+    pub fn is_older_than(&self, other: &Handshake) -> bool {
+        fn numbers(version: &str) -> Vec<u64> {
+            let release = version.split(|c| c == '-' || c == '+').next().unwrap_or("");
+            release.split('.').map(|n| n.parse().unwrap_or(0)).collect()
+        }
+        (self.protocol_version, numbers(&self.version))
+            < (other.protocol_version, numbers(&other.version))
+    }
 }
 
 /// Possible responses from the server for a `Compile` request.
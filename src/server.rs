@@ -17,21 +17,24 @@
 
 use crate::cache::{storage_from_config, Storage};
 use crate::compiler::{
-    get_compiler_info, CacheControl, CompileResult, Compiler, CompilerArguments, CompilerHasher,
-    CompilerKind, CompilerProxy, DistType, MissType,
+    compiler_check_digests, get_compiler_info, CacheControl, CacheWriteInfo, CompileResult,
+    Compiler, CompilerArguments, CompilerHasher, CompilerInfoCache, CompilerKind, CompilerProxy,
+    DistType, InFlight, MissType,
 };
 #[cfg(feature = "dist-client")]
 use crate::config;
-use crate::config::Config;
+use crate::config::{CompilerCheck, CompilerConfigs, Config, StatsConfig};
 use crate::dist;
 use crate::jobserver::Client;
 use crate::mock_command::{CommandCreatorSync, ProcessCommandCreator};
-use crate::protocol::{Compile, CompileFinished, CompileResponse, Request, Response};
+use crate::protocol::{Compile, CompileFinished, CompileResponse, Handshake, Request, Response};
+use crate::stats_history::{StatsHistory, StatsRecorder};
 use crate::util;
-#[cfg(feature = "dist-client")]
 use anyhow::Context as _;
 use filetime::FileTime;
+use futures::future::Either;
 use futures::sync::mpsc;
+use futures::unsync::oneshot;
 use futures::{future, stream, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
 use futures_03::compat::Compat;
 use futures_03::executor::ThreadPool;
@@ -45,6 +48,7 @@ use std::io::{self, Write};
 #[cfg(feature = "dist-client")]
 use std::mem;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::ops;
 use std::path::PathBuf;
 use std::pin::Pin;
 use std::process::{ExitStatus, Output};
@@ -70,6 +74,9 @@ use crate::errors::*;
 /// If the server is idle for this many seconds, shut down.
 const DEFAULT_IDLE_TIMEOUT: u64 = 600;
 
+/// The `compiler_check` of generic compilers missing from the configuration.
+static DEFAULT_COMPILER_CHECK: CompilerCheck = CompilerCheck::Content;
+
 /// If the dist client couldn't be created, retry creation at this number
 /// of seconds from now (or later)
 #[cfg(feature = "dist-client")]
@@ -182,6 +189,12 @@ impl DistClientContainer {
 
     pub fn reset_state(&self) {}
 
+    pub fn reconfigure(&self, config: &Config, _: &ThreadPool) {
+        if config.dist.scheduler_url.is_some() {
+            warn!("Scheduler address configured but dist feature disabled, disabling distributed sccache")
+        }
+    }
+
     pub fn get_status(&self) -> DistInfo {
         DistInfo::Disabled("dist-client feature not selected".to_string())
     }
@@ -194,7 +207,14 @@ impl DistClientContainer {
 #[cfg(feature = "dist-client")]
 impl DistClientContainer {
     fn new(config: &Config, pool: &ThreadPool) -> Self {
-        let config = DistClientConfig {
+        let state = Self::create_state(Self::client_config(config, pool));
+        Self {
+            state: Mutex::new(state),
+        }
+    }
+
+    fn client_config(config: &Config, pool: &ThreadPool) -> DistClientConfig {
+        DistClientConfig {
             pool: pool.clone(),
             scheduler_url: config.dist.scheduler_url.clone(),
             auth: config.dist.auth.clone(),
@@ -202,10 +222,6 @@ impl DistClientContainer {
             toolchain_cache_size: config.dist.toolchain_cache_size,
             toolchains: config.dist.toolchains.clone(),
             rewrite_includes_only: config.dist.rewrite_includes_only,
-        };
-        let state = Self::create_state(config);
-        Self {
-            state: Mutex::new(state),
         }
     }
 
@@ -231,6 +247,23 @@ impl DistClientContainer {
         }
     }
 
+    /// Switch to the distributed compilation settings in `config`. The
+    /// client is created again when it is next needed.
+    pub fn reconfigure(&self, config: &Config, pool: &ThreadPool) {
+        let config = Self::client_config(config, pool);
+        let state = if config.scheduler_url.is_some() {
+            info!("Config reloaded. Will recreate the dist client");
+            DistClientState::RetryCreateAt(
+                Box::new(config),
+                Instant::now() - Duration::from_secs(1),
+            )
+        } else {
+            info!("No scheduler address configured, disabling distributed sccache");
+            DistClientState::Disabled
+        };
+        *self.state.lock().unwrap() = state;
+    }
+
     pub fn get_status(&self) -> DistInfo {
         let mut guard = self.state.lock();
         let state = guard.as_mut().unwrap();
@@ -403,10 +436,14 @@ pub fn start_server(config: &Config, port: u16) -> Result<()> {
         client,
         dist_client,
         storage,
+        config.compiler.clone(),
     );
     let notify = env::var_os("SCCACHE_STARTUP_NOTIFY");
     match res {
-        Ok(srv) => {
+        Ok(mut srv) => {
+            srv.set_compiler_info_cache_dir(config.fallback_cache.dir.join("compiler-info"));
+            srv.set_stats_history_file(config.fallback_cache.dir.join("stats.json"));
+            srv.set_stats_config(config.stats.clone());
             let port = srv.port();
             info!("server started, listening on port {}", port);
             notify_server_startup(&notify, ServerStartup::Ok { port })?;
@@ -446,6 +483,7 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
         client: Client,
         dist_client: DistClientContainer,
         storage: Arc<dyn Storage>,
+        compiler_config: CompilerConfigs,
     ) -> Result<SccacheServer<C>> {
         let addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port);
         let listener = TcpListener::bind(&SocketAddr::V4(addr))?;
@@ -454,7 +492,15 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
         // connections.
         let (tx, rx) = mpsc::channel(1);
         let (wait, info) = WaitUntilZero::new();
-        let service = SccacheService::new(dist_client, storage, &client, pool, tx, info);
+        let service = SccacheService::new(
+            dist_client,
+            storage,
+            compiler_config,
+            &client,
+            pool,
+            tx,
+            info,
+        );
 
         Ok(SccacheServer {
             runtime,
@@ -475,7 +521,32 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
     /// Set the storage this server will use.
     #[allow(dead_code)]
     pub fn set_storage(&mut self, storage: Arc<dyn Storage>) {
-        self.service.storage = storage;
+        *self.service.storage.borrow_mut() = storage;
+    }
+
+    /// Set how this server loads its configuration when asked to reload it.
+    #[allow(dead_code)]
+    pub fn set_config_loader<F>(&mut self, load_config: F)
+    where
+        F: Fn() -> Result<Config> + 'static,
+    {
+        self.service.load_config = Rc::new(load_config);
+    }
+
+    /// Set how to break down the server's stats.
+    pub fn set_stats_config(&mut self, config: StatsConfig) {
+        self.service.stats_config = Rc::new(config);
+    }
+
+    /// Set the file to keep the history of the server's stats in.
+    pub fn set_stats_history_file(&mut self, path: PathBuf) {
+        *self.service.stats_history.borrow_mut() = StatsRecorder::with_file(path);
+    }
+
+    /// Set the directory to keep compiler info in across restarts.
+    pub fn set_compiler_info_cache_dir(&mut self, dir: PathBuf) {
+        let cache = CompilerInfoCache::new(dir, &self.service.compiler_config);
+        self.service.compiler_info_cache = Some(Rc::new(cache));
     }
 
     /// Returns a reference to a thread pool to run work on
@@ -518,6 +589,12 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
             wait,
         } = self;
 
+        let stats = service.stats.clone();
+        let stats_history = service.stats_history.clone();
+        let pool = service.pool.clone();
+        #[cfg(unix)]
+        let reloader = service.clone();
+
         // Create our "server future" which will simply handle all incoming
         // connections in separate tasks.
         let server = listener.incoming().for_each(move |socket| {
@@ -569,6 +646,29 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
             a
         })));
 
+        // Reload the configuration on SIGHUP. This never resolves, so that it
+        // doesn't end the server.
+        #[cfg(unix)]
+        {
+            use tokio_signal::unix::{Signal, SIGHUP};
+            let reload = Signal::new(SIGHUP)
+                .flatten_stream()
+                .for_each(move |_| {
+                    info!("reloading config due to SIGHUP");
+                    if let Err(e) = reloader.reload_config() {
+                        error!("{:?}", e);
+                    }
+                    Ok(())
+                })
+                .then(|res| {
+                    if let Err(e) = res {
+                        warn!("Failed to listen for SIGHUP: {}", e);
+                    }
+                    future::empty()
+                });
+            futures.push(Box::new(reload));
+        }
+
         let server = future::select_all(futures);
         runtime.block_on(server).map_err(|p| p.0)?;
 
@@ -594,6 +694,11 @@ impl<C: CommandCreatorSync> SccacheServer<C> {
                 }
             })?;
 
+        let flush = stats_history.borrow_mut().flush(&stats.borrow(), &pool);
+        if let Err(e) = flush.wait() {
+            warn!("Failed to flush the stats history: {:?}", e);
+        }
+
         info!("ok, fully shutting down now");
 
         Ok(())
@@ -610,6 +715,9 @@ struct CompilerCacheEntry<C: CommandCreatorSync> {
     pub mtime: FileTime,
     /// distributed compilation extra info
     pub dist_info: Option<(PathBuf, FileTime)>,
+    /// the output digest of a `compiler_check` command, which is run again
+    /// to tell whether the entry is still valid
+    pub check_digests: Option<Vec<String>>,
 }
 
 impl<C> CompilerCacheEntry<C>
@@ -620,11 +728,13 @@ where
         compiler: Box<dyn Compiler<C>>,
         mtime: FileTime,
         dist_info: Option<(PathBuf, FileTime)>,
+        check_digests: Option<Vec<String>>,
     ) -> Self {
         Self {
             compiler,
             mtime,
             dist_info,
+            check_digests,
         }
     }
 }
@@ -634,15 +744,33 @@ struct SccacheService<C: CommandCreatorSync> {
     /// Server statistics.
     stats: Rc<RefCell<ServerStats>>,
 
+    /// Server statistics kept across restarts.
+    stats_history: Rc<RefCell<StatsRecorder>>,
+
     /// Distributed sccache client
     dist_client: Rc<DistClientContainer>,
 
-    /// Cache storage.
-    storage: Arc<dyn Storage>,
+    /// Cache storage, replaced when the configuration is reloaded.
+    storage: Rc<RefCell<Arc<dyn Storage>>>,
+
+    /// Loads the configuration when it is reloaded.
+    load_config: Rc<dyn Fn() -> Result<Config>>,
+
+    /// Compilations in progress, which identical requests wait for.
+    in_flight: InFlight,
+
+    /// Compiler settings from the configuration.
+    compiler_config: Rc<CompilerConfigs>,
+
+    /// Stats settings from the configuration.
+    stats_config: Rc<StatsConfig>,
 
     /// A cache of known compiler info.
     compilers: Rc<RefCell<CompilerMap<C>>>,
 
+    /// Compiler info kept on disk across server restarts.
+    compiler_info_cache: Option<Rc<CompilerInfoCache>>,
+
     /// map the cwd with compiler proxy path to a proxy resolver, which
     /// will dynamically resolve the input compiler for the current context
     /// (usually file or current working directory)
@@ -701,6 +829,15 @@ where
         // that every message is received.
         drop(self.tx.clone().start_send(ServerMessage::Request));
 
+        if self.stats_history.borrow().needs_flush() {
+            // The flush carries on in the background.
+            drop(
+                self.stats_history
+                    .borrow_mut()
+                    .flush(&self.stats.borrow(), &self.pool),
+            );
+        }
+
         let res: SFuture<Response> = match req.into_inner() {
             Request::Compile(compile) => {
                 debug!("handle_client: compile");
@@ -720,6 +857,30 @@ where
                 self.zero_stats();
                 Box::new(self.get_info().map(|i| Response::Stats(Box::new(i))))
             }
+            Request::GetStatsHistory => {
+                debug!("handle_client: get_stats_history");
+                Box::new(
+                    self.get_stats_history()
+                        .map(|h| Response::StatsHistory(Box::new(h))),
+                )
+            }
+            Request::ReloadConfig => {
+                debug!("handle_client: reload_config");
+                match self.reload_config() {
+                    Ok(()) => Box::new(
+                        self.get_info()
+                            .map(|i| Response::ConfigReloaded(Box::new(i))),
+                    ),
+                    Err(e) => {
+                        error!("{:?}", e);
+                        Box::new(f_ok(Response::ConfigReloadFailed(format!("{:#}", e))))
+                    }
+                }
+            }
+            Request::Handshake(handshake) => {
+                debug!("handle_client: handshake from {:?}", handshake);
+                Box::new(f_ok(Response::Handshake(Handshake::current())))
+            }
             Request::Shutdown => {
                 debug!("handle_client: shutdown");
                 let future = self
@@ -749,6 +910,7 @@ where
     pub fn new(
         dist_client: DistClientContainer,
         storage: Arc<dyn Storage>,
+        compiler_config: CompilerConfigs,
         client: &Client,
         pool: ThreadPool,
         tx: mpsc::Sender<ServerMessage>,
@@ -756,9 +918,15 @@ where
     ) -> SccacheService<C> {
         SccacheService {
             stats: Rc::new(RefCell::new(ServerStats::default())),
+            stats_history: Rc::new(RefCell::new(StatsRecorder::new())),
             dist_client: Rc::new(dist_client),
-            storage,
+            storage: Rc::new(RefCell::new(storage)),
+            load_config: Rc::new(Config::load),
+            in_flight: InFlight::new(),
+            compiler_config: Rc::new(compiler_config),
+            stats_config: Rc::new(StatsConfig::default()),
             compilers: Rc::new(RefCell::new(HashMap::new())),
+            compiler_info_cache: None,
             compiler_proxies: Rc::new(RefCell::new(HashMap::new())),
             pool,
             creator: C::new(client),
@@ -786,24 +954,39 @@ where
         }
         .split();
         let sink = sink.sink_from_err::<Error>();
+        let requests = ClientRequests::new(stream);
+        let watcher = requests.clone();
 
-        stream
+        requests
             .from_err::<Error>()
             .and_then(move |input| self.call(input))
-            .and_then(|message| {
+            .and_then(move |message| {
                 let f: Box<dyn Stream<Item = _, Error = _>> = match message {
                     Message::WithoutBody(message) => Box::new(stream::once(Ok(Frame::Message {
                         message,
                         body: false,
                     }))),
-                    Message::WithBody(message, body) => Box::new(
-                        stream::once(Ok(Frame::Message {
-                            message,
-                            body: true,
-                        }))
-                        .chain(Compat::new(body).map(|chunk| Frame::Body { chunk: Some(chunk) }))
-                        .chain(stream::once(Ok(Frame::Body { chunk: None }))),
-                    ),
+                    Message::WithBody(message, body) => {
+                        // Stop waiting for the body once the client is gone,
+                        // dropping it cancels whatever is producing it.
+                        let watcher = watcher.clone();
+                        let mut body = Compat::new(body);
+                        let body = stream::poll_fn(move || {
+                            if watcher.poll_disconnected().is_ready() {
+                                debug!("Client disconnected before the response was complete");
+                                return Ok(Async::Ready(None));
+                            }
+                            body.poll()
+                        });
+                        Box::new(
+                            stream::once(Ok(Frame::Message {
+                                message,
+                                body: true,
+                            }))
+                            .chain(body.map(|chunk| Frame::Body { chunk: Some(chunk) }))
+                            .chain(stream::once(Ok(Frame::Body { chunk: None }))),
+                        )
+                    }
                 };
                 Ok(f.from_err::<Error>())
             })
@@ -820,23 +1003,45 @@ where
     /// Get info and stats about the cache.
     fn get_info(&self) -> SFuture<ServerInfo> {
         let stats = self.stats.borrow().clone();
-        let cache_location = self.storage.location();
-        Box::new(
-            self.storage
-                .current_size()
-                .join(self.storage.max_size())
-                .map(move |(cache_size, max_cache_size)| ServerInfo {
-                    stats,
-                    cache_location,
-                    cache_size,
-                    max_cache_size,
-                }),
-        )
+        let storage = self.storage.borrow().clone();
+        let cache_location = storage.location();
+        Box::new(storage.current_size().join(storage.max_size()).map(
+            move |(cache_size, max_cache_size)| ServerInfo {
+                stats,
+                cache_location,
+                cache_size,
+                max_cache_size,
+            },
+        ))
     }
 
     /// Zero stats about the cache.
     fn zero_stats(&self) {
-        *self.stats.borrow_mut() = ServerStats::default();
+        let mut stats = self.stats.borrow_mut();
+        drop(
+            self.stats_history
+                .borrow_mut()
+                .flush_and_reset(&stats, &self.pool),
+        );
+        *stats = ServerStats::default();
+    }
+
+    /// Load the configuration again and switch to the cache storage and
+    /// distributed compilation settings it describes. Compilations already
+    /// in progress finish with the old storage.
+    fn reload_config(&self) -> Result<()> {
+        let config = (self.load_config)().context("Failed to reload config")?;
+        info!("Reloading config");
+        *self.storage.borrow_mut() = storage_from_config(&config, &self.pool);
+        self.dist_client.reconfigure(&config, &self.pool);
+        Ok(())
+    }
+
+    /// Get the stats recorded across server runs, including this one.
+    fn get_stats_history(&self) -> SFuture<StatsHistory> {
+        self.stats_history
+            .borrow_mut()
+            .flush(&self.stats.borrow(), &self.pool)
     }
 
     /// Handle a compile request from a client.
@@ -876,6 +1081,22 @@ where
         let path1 = path.clone();
         let env = env.to_vec();
 
+        // A proxy found by an earlier server is used like one found by this one.
+        if !self.compiler_proxies.borrow().contains_key(&path) {
+            let proxy = self
+                .compiler_info_cache
+                .as_ref()
+                .and_then(|cache| cache.get_proxy(&path))
+                .and_then(|proxy| proxy.into_proxy::<C>().ok());
+            let mtime = metadata(&path).map(|attr| FileTime::from_last_modification_time(&attr));
+            if let (Some(proxy), Ok(mtime)) = (proxy, mtime) {
+                trace!("Restored compiler proxy for {:?}", path);
+                self.compiler_proxies
+                    .borrow_mut()
+                    .insert(path.clone(), (proxy, mtime));
+            }
+        }
+
         let resolve_w_proxy = {
             let compiler_proxies_borrow = self.compiler_proxies.borrow();
 
@@ -930,7 +1151,7 @@ where
                 // It's a hit only if the mtime and dist archive data matches.
                 Some(&Some(ref entry)) => {
                     if entry.mtime == mtime && entry.dist_info == dist_info {
-                        Some(entry.compiler.clone())
+                        Some((entry.compiler.clone(), entry.check_digests.clone()))
                     } else {
                         None
                     }
@@ -940,6 +1161,79 @@ where
             f_ok((resolved_compiler_path, mtime, opt, dist_info))
         });
 
+        // Fall back to compiler info stored by an earlier server.
+        let me4 = self.clone();
+        let env4 = env.clone();
+        let lookup_compiler = lookup_compiler.and_then(
+            move |(resolved_compiler_path, mtime, opt, dist_info)| -> SFuture<_> {
+                let stored = match (&opt, &dist_info, &me4.compiler_info_cache) {
+                    (None, None, Some(cache)) => cache.get_compiler(&resolved_compiler_path),
+                    _ => None,
+                };
+                let (detected, check_digests) = match stored {
+                    Some(stored) => stored,
+                    None => return f_ok((resolved_compiler_path, mtime, opt, dist_info)),
+                };
+                let compiler = detected.into_compiler::<C>(&me4.compiler_config, &env4, &me4.pool);
+                Box::new(compiler.then(move |res| {
+                    let opt = match res {
+                        Ok(compiler) => {
+                            trace!("Restored compiler info for {:?}", resolved_compiler_path);
+                            let entry = CompilerCacheEntry::new(
+                                compiler.clone(),
+                                mtime,
+                                None,
+                                check_digests.clone(),
+                            );
+                            me4.compilers
+                                .borrow_mut()
+                                .insert(resolved_compiler_path.clone(), Some(entry));
+                            Some((compiler, check_digests))
+                        }
+                        Err(e) => {
+                            debug!("Failed to restore compiler info: {}", e);
+                            None
+                        }
+                    };
+                    f_ok((resolved_compiler_path, mtime, opt, dist_info))
+                }))
+            },
+        );
+
+        // A `compiler_check` command can tell about changes the mtime doesn't
+        // show, like those behind a wrapper script, so run it again.
+        let me2 = self.clone();
+        let env2 = env.clone();
+        let lookup_compiler = lookup_compiler.and_then(
+            move |(resolved_compiler_path, mtime, opt, dist_info)| -> SFuture<_> {
+                let (compiler, expected) = match opt {
+                    Some((compiler, Some(expected))) => (compiler, expected),
+                    opt => {
+                        let opt = opt.map(|(compiler, _)| compiler);
+                        return f_ok((resolved_compiler_path, mtime, opt, dist_info));
+                    }
+                };
+                let digests = compiler_check_digests(
+                    &me2.creator,
+                    &resolved_compiler_path,
+                    vec![],
+                    me2.compiler_check(compiler.kind()),
+                    &env2,
+                    &me2.pool,
+                );
+                Box::new(digests.then(move |digests| {
+                    let opt = match digests {
+                        Ok(ref digests) if *digests == expected => Some(compiler),
+                        _ => {
+                            trace!("compiler_check output changed");
+                            None
+                        }
+                    };
+                    f_ok((resolved_compiler_path, mtime, opt, dist_info))
+                }))
+            },
+        );
+
         let obtain = lookup_compiler.and_then(
             move |(resolved_compiler_path, mtime, opt, dist_info): (
                 PathBuf,
@@ -967,15 +1261,37 @@ where
                             env.as_slice(),
                             &me.pool,
                             dist_info.clone().map(|(p, _)| p),
+                            &me.compiler_config,
                         );
+                        // Remember the output of a `compiler_check` command to
+                        // check it again later.
+                        let me3 = me.clone();
+                        let check_path = resolved_compiler_path.clone();
+                        let x = x.and_then(move |(c, proxy)| -> SFuture<_> {
+                            let check = me3.compiler_check(c.kind());
+                            if let CompilerCheck::Command(_) = check {
+                                let digests = compiler_check_digests(
+                                    &me3.creator,
+                                    &check_path,
+                                    vec![],
+                                    check,
+                                    &env,
+                                    &me3.pool,
+                                );
+                                Box::new(digests.map(move |d| (c, proxy, Some(d))))
+                            } else {
+                                f_ok((c, proxy, None))
+                            }
+                        });
 
                         Box::new(x.then(
                             move |info: Result<(
                                 Box<dyn Compiler<C>>,
                                 Option<Box<dyn CompilerProxy<C>>>,
+                                Option<Vec<String>>,
                             )>| {
                                 match info {
-                                    Ok((ref c, ref proxy)) => {
+                                    Ok((ref c, ref proxy, ref check_digests)) => {
                                         // register the proxy for this compiler, so it will be used directly from now on
                                         // and the true/resolved compiler will create table hits in the hash map
                                         // based on the resolved path
@@ -990,14 +1306,32 @@ where
                                                 proxy.box_clone();
                                             me.compiler_proxies
                                                 .borrow_mut()
-                                                .insert(path, (proxy, mtime));
+                                                .insert(path.clone(), (proxy, mtime));
                                         }
                                         // TODO add some safety checks in case a proxy exists, that the initial `path` is not
                                         // TODO the same as the resolved compiler binary
 
+                                        // Keep what was found for later servers too.
+                                        if let (Some(cache), None) =
+                                            (&me.compiler_info_cache, &dist_info)
+                                        {
+                                            if let Some(proxy) =
+                                                proxy.as_ref().and_then(|p| p.detected())
+                                            {
+                                                cache.put_proxy(&path, proxy);
+                                            }
+                                            if let Some(detected) = c.detected() {
+                                                cache.put_compiler(detected, check_digests.clone());
+                                            }
+                                        }
+
                                         // cache
-                                        let map_info =
-                                            CompilerCacheEntry::new(c.clone(), mtime, dist_info);
+                                        let map_info = CompilerCacheEntry::new(
+                                            c.clone(),
+                                            mtime,
+                                            dist_info,
+                                            check_digests.clone(),
+                                        );
                                         trace!(
                                             "Inserting POSSIBLY PROXIED cache map info for {:?}",
                                             &resolved_compiler_path
@@ -1024,6 +1358,21 @@ where
         Box::new(obtain)
     }
 
+    /// The `compiler_check` setting for compilers of `kind`.
+    fn compiler_check(&self, kind: CompilerKind) -> &CompilerCheck {
+        match kind {
+            CompilerKind::C(_) => &self.compiler_config.c.compiler_check,
+            CompilerKind::Rust | CompilerKind::Rustdoc => &self.compiler_config.rust.compiler_check,
+            CompilerKind::Generic(name) => self
+                .compiler_config
+                .generic
+                .iter()
+                .find(|generic| generic.name == name)
+                .map(|generic| &generic.compiler_check)
+                .unwrap_or(&DEFAULT_COMPILER_CHECK),
+        }
+    }
+
     /// Check that we can handle and cache `cmd` when run with `compiler`.
     /// If so, run `start_compile_task` to execute it.
     fn check_compiler(
@@ -1050,8 +1399,24 @@ where
                     CompilerArguments::Ok(hasher) => {
                         debug!("parse_arguments: Ok: {:?}", cmd);
                         stats.requests_executed += 1;
-                        let (tx, rx) = Body::pair();
-                        self.start_compile_task(c, hasher, cmd, cwd, env_vars, tx);
+                        let (tx, client_gone, rx) = Body::pair();
+                        self.start_compile_task(
+                            c,
+                            vec![hasher],
+                            cmd,
+                            cwd,
+                            env_vars,
+                            tx,
+                            client_gone,
+                        );
+                        let res = CompileResponse::CompileStarted;
+                        return Message::WithBody(Response::Compile(res), rx);
+                    }
+                    CompilerArguments::Multiple(hashers) => {
+                        debug!("parse_arguments: Multiple({}): {:?}", hashers.len(), cmd);
+                        stats.requests_executed += 1;
+                        let (tx, client_gone, rx) = Body::pair();
+                        self.start_compile_task(c, hashers, cmd, cwd, env_vars, tx, client_gone);
                         let res = CompileResponse::CompileStarted;
                         return Message::WithBody(Response::Compile(res), rx);
                     }
@@ -1082,14 +1447,25 @@ where
     /// Given compiler arguments `arguments`, look up
     /// a compile result in the cache or execute the compilation and store
     /// the result in the cache.
+    ///
+    /// Commandlines compiling several inputs come with one hasher per input,
+    /// each of which is looked up and compiled independently. Their output is
+    /// merged back in order into a single response.
+    ///
+    /// If the client goes away before the compilation finishes, signalled by
+    /// `client_gone` being canceled, the compilation is dropped, which kills
+    /// any processes it spawned. Cache writes of finished compilations are
+    /// always completed.
+    #[allow(clippy::too_many_arguments)]
     fn start_compile_task(
         &self,
         compiler: Box<dyn Compiler<C>>,
-        hasher: Box<dyn CompilerHasher<C>>,
+        hashers: Vec<Box<dyn CompilerHasher<C>>>,
         arguments: Vec<OsString>,
         cwd: PathBuf,
         env_vars: Vec<(OsString, OsString)>,
         tx: mpsc::Sender<Result<Response>>,
+        mut client_gone: oneshot::Sender<()>,
     ) {
         let force_recache = env_vars
             .iter()
@@ -1099,157 +1475,232 @@ where
         } else {
             CacheControl::Default
         };
-        let out_pretty = hasher.output_pretty().into_owned();
-        let color_mode = hasher.color_mode();
-        let result = hasher.get_cached_or_compile(
-            self.dist_client.get_client(),
-            self.creator.clone(),
-            self.storage.clone(),
-            arguments,
-            cwd,
-            env_vars,
-            cache_control,
-            self.pool.clone(),
-        );
+        let color_mode = hashers
+            .first()
+            .map(|hasher| hasher.color_mode())
+            .unwrap_or_default();
+        let results: Vec<_> = hashers
+            .into_iter()
+            .map(|hasher| {
+                let out_pretty = hasher.output_pretty().into_owned();
+                hasher
+                    .get_cached_or_compile(
+                        self.dist_client.get_client(),
+                        self.creator.clone(),
+                        self.storage.borrow().clone(),
+                        self.in_flight.clone(),
+                        arguments.clone(),
+                        cwd.clone(),
+                        env_vars.clone(),
+                        cache_control,
+                        self.pool.clone(),
+                    )
+                    .then(move |result| Ok::<_, Error>((out_pretty, result)))
+            })
+            .collect();
         let me = self.clone();
         let kind = compiler.kind();
-        let task = result.then(move |result| {
-            let mut cache_write = None;
-            let mut stats = me.stats.borrow_mut();
-            let mut res = CompileFinished {
-                color_mode,
-                ..Default::default()
-            };
-            match result {
-                Ok((compiled, out)) => {
-                    match compiled {
-                        CompileResult::Error => {
-                            stats.cache_errors.increment(&kind);
-                        }
-                        CompileResult::CacheHit(duration) => {
-                            stats.cache_hits.increment(&kind);
-                            stats.cache_read_hit_duration += duration;
-                        }
-                        CompileResult::CacheMiss(miss_type, dist_type, duration, future) => {
-                            match dist_type {
-                                DistType::NoDist => {}
-                                DistType::Ok(id) => {
-                                    let server = id.addr().to_string();
-                                    let server_count =
-                                        stats.dist_compiles.entry(server).or_insert(0);
-                                    *server_count += 1;
-                                }
-                                DistType::Error => stats.dist_errors += 1,
+        let keys = StatsKeys {
+            compiler: compiler.executable().display().to_string(),
+            project: self
+                .stats_config
+                .project_of(&cwd)
+                .map(|project| project.display().to_string()),
+        };
+        let client_gone = future::poll_fn(move || client_gone.poll_cancel());
+        let task = future::join_all(results).select2(client_gone).then(
+            move |results| -> Box<dyn Future<Item = (), Error = ()>> {
+                let results = match results {
+                    Ok(Either::A((results, _))) => results,
+                    Ok(Either::B(_)) => {
+                        debug!(
+                            "Client disconnected, abandoning compilation of {:?}",
+                            arguments
+                        );
+                        me.stats.borrow_mut().compiles_abandoned += 1;
+                        return Box::new(future::ok(()));
+                    }
+                    Err(_) => unreachable!("compile results are never errors"),
+                };
+                let mut cache_writes = vec![];
+                let mut res = CompileFinished {
+                    color_mode,
+                    ..Default::default()
+                };
+                for (out_pretty, result) in results {
+                    let (finished, cache_write) =
+                        me.finish_compile(&kind, &keys, &out_pretty, result);
+                    cache_writes.extend(cache_write);
+                    res.stdout.extend(finished.stdout);
+                    res.stderr.extend(finished.stderr);
+                    // Report the first failure, like the compiler itself would.
+                    if res.retcode.unwrap_or(0) == 0 && res.signal.is_none() {
+                        res.retcode = finished.retcode;
+                        res.signal = finished.signal;
+                    }
+                }
+                let send = tx.send(Ok(Response::CompileFinished(res)));
+
+                let cache_writes = cache_writes.into_iter().map(move |cache_write| {
+                    let me = me.clone();
+                    cache_write.then(move |result| {
+                        match result {
+                            Err(e) => {
+                                debug!("Error executing cache write: {}", e);
+                                me.stats.borrow_mut().cache_write_errors += 1;
                             }
-                            match miss_type {
-                                MissType::Normal => {}
-                                MissType::ForcedRecache => {
-                                    stats.forced_recaches += 1;
-                                }
-                                MissType::TimedOut => {
-                                    stats.cache_timeouts += 1;
-                                }
-                                MissType::CacheReadError => {
-                                    stats.cache_errors.increment(&kind);
-                                }
+                            //TODO: save cache stats!
+                            Ok(info) => {
+                                debug!(
+                                    "[{}]: Cache write finished in {}",
+                                    info.object_file_pretty,
+                                    util::fmt_duration_as_secs(&info.duration)
+                                );
+                                me.stats.borrow_mut().cache_writes += 1;
+                                me.stats.borrow_mut().cache_write_duration += info.duration;
                             }
-                            stats.cache_misses.increment(&kind);
-                            stats.cache_read_miss_duration += duration;
-                            cache_write = Some(future);
                         }
-                        CompileResult::NotCacheable => {
-                            stats.cache_misses.increment(&kind);
-                            stats.non_cacheable_compilations += 1;
-                        }
-                        CompileResult::CompileFailed => {
-                            stats.compile_fails += 1;
+                        Ok(())
+                    })
+                });
+
+                Box::new(send.join(future::join_all(cache_writes)).then(|_| Ok(())))
+            },
+        );
+
+        tokio_compat::runtime::current_thread::TaskExecutor::current()
+            .spawn_local(Box::new(task))
+            .unwrap();
+    }
+
+    /// Record the result of compiling a single input in the stats, and turn
+    /// it into the response for the client along with the pending cache write,
+    /// if any.
+    fn finish_compile(
+        &self,
+        kind: &CompilerKind,
+        keys: &StatsKeys,
+        out_pretty: &str,
+        result: Result<(CompileResult, Output)>,
+    ) -> (CompileFinished, Option<SFuture<CacheWriteInfo>>) {
+        let mut cache_write = None;
+        let mut stats = self.stats.borrow_mut();
+        let mut res = CompileFinished::default();
+        match result {
+            Ok((compiled, out)) => {
+                match compiled {
+                    CompileResult::Error => {
+                        stats.cache_errors.increment(kind);
+                    }
+                    CompileResult::CacheHit(duration, saved) => {
+                        stats.cache_hits.increment(kind);
+                        stats.cache_read_hit_duration += duration;
+                        if let Some(saved) = saved {
+                            stats.time_saved.add(kind, saved);
                         }
-                    };
-                    let Output {
-                        status,
-                        stdout,
-                        stderr,
-                    } = out;
-                    trace!("CompileFinished retcode: {}", status);
-                    match status.code() {
-                        Some(code) => res.retcode = Some(code),
-                        None => res.signal = Some(get_signal(status)),
-                    };
-                    res.stdout = stdout;
-                    res.stderr = stderr;
-                }
-                Err(err) => {
-                    match err.downcast::<ProcessError>() {
-                        Ok(ProcessError(output)) => {
-                            debug!("Compilation failed: {:?}", output);
-                            stats.compile_fails += 1;
-                            match output.status.code() {
-                                Some(code) => res.retcode = Some(code),
-                                None => res.signal = Some(get_signal(output.status)),
-                            };
-                            res.stdout = output.stdout;
-                            res.stderr = output.stderr;
+                        stats.increment_keyed(keys, |count| count.hits += 1);
+                    }
+                    CompileResult::CacheMiss(miss_type, dist_type, duration, future) => {
+                        match dist_type {
+                            DistType::NoDist => {}
+                            DistType::Ok(id) => {
+                                let server = id.addr().to_string();
+                                let server_count = stats.dist_compiles.entry(server).or_insert(0);
+                                *server_count += 1;
+                            }
+                            DistType::Error => stats.dist_errors += 1,
                         }
-                        Err(err) => match err.downcast::<HttpClientError>() {
-                            Ok(HttpClientError(msg)) => {
-                                me.dist_client.reset_state();
-                                let errmsg =
-                                    format!("[{:?}] http error status: {}", out_pretty, msg);
-                                error!("{}", errmsg);
-                                res.retcode = Some(1);
-                                res.stderr = errmsg.as_bytes().to_vec();
+                        match miss_type {
+                            MissType::Normal => {}
+                            MissType::ForcedRecache => {
+                                stats.forced_recaches += 1;
                             }
-                            Err(err) => {
-                                use std::fmt::Write;
-
-                                error!("[{:?}] fatal error: {}", out_pretty, err);
-
-                                let mut error = "sccache: encountered fatal error\n".to_string();
-                                let _ = writeln!(error, "sccache: error: {}", err);
-                                for e in err.chain() {
-                                    error!("[{:?}] \t{}", out_pretty, e);
-                                    let _ = writeln!(error, "sccache: caused by: {}", e);
-                                }
-                                stats.cache_errors.increment(&kind);
-                                //TODO: figure out a better way to communicate this?
-                                res.retcode = Some(-2);
-                                res.stderr = error.into_bytes();
+                            MissType::TimedOut => {
+                                stats.cache_timeouts += 1;
                             }
-                        },
+                            MissType::CacheReadError => {
+                                stats.cache_errors.increment(kind);
+                            }
+                        }
+                        stats.cache_misses.increment(kind);
+                        stats.cache_misses_by_type.increment(&miss_type);
+                        stats.increment_keyed(keys, |count| count.misses += 1);
+                        stats.cache_read_miss_duration += duration;
+                        cache_write = Some(future);
                     }
-                }
-            };
-            let send = tx.send(Ok(Response::CompileFinished(res)));
-
-            let me = me.clone();
-            let cache_write = cache_write.then(move |result| {
-                match result {
-                    Err(e) => {
-                        debug!("Error executing cache write: {}", e);
-                        me.stats.borrow_mut().cache_write_errors += 1;
+                    CompileResult::NotCacheable => {
+                        stats.cache_misses.increment(kind);
+                        stats.increment_keyed(keys, |count| count.misses += 1);
+                        stats.non_cacheable_compilations += 1;
                     }
-                    //TODO: save cache stats!
-                    Ok(Some(info)) => {
-                        debug!(
-                            "[{}]: Cache write finished in {}",
-                            info.object_file_pretty,
-                            util::fmt_duration_as_secs(&info.duration)
-                        );
-                        me.stats.borrow_mut().cache_writes += 1;
-                        me.stats.borrow_mut().cache_write_duration += info.duration;
+                    CompileResult::CompileFailed => {
+                        stats.compile_fails += 1;
                     }
+                    CompileResult::Coalesced(_, saved) => {
+                        // No compiler ran for this request, so it's counted
+                        // as a hit saving what the identical compilation took.
+                        stats.cache_hits.increment(kind);
+                        stats.coalesced_compilations += 1;
+                        if let Some(saved) = saved {
+                            stats.time_saved.add(kind, saved);
+                        }
+                        stats.increment_keyed(keys, |count| count.hits += 1);
+                    }
+                };
+                let Output {
+                    status,
+                    stdout,
+                    stderr,
+                } = out;
+                trace!("CompileFinished retcode: {}", status);
+                match status.code() {
+                    Some(code) => res.retcode = Some(code),
+                    None => res.signal = Some(get_signal(status)),
+                };
+                res.stdout = stdout;
+                res.stderr = stderr;
+            }
+            Err(err) => {
+                match err.downcast::<ProcessError>() {
+                    Ok(ProcessError(output)) => {
+                        debug!("Compilation failed: {:?}", output);
+                        stats.compile_fails += 1;
+                        match output.status.code() {
+                            Some(code) => res.retcode = Some(code),
+                            None => res.signal = Some(get_signal(output.status)),
+                        };
+                        res.stdout = output.stdout;
+                        res.stderr = output.stderr;
+                    }
+                    Err(err) => match err.downcast::<HttpClientError>() {
+                        Ok(HttpClientError(msg)) => {
+                            self.dist_client.reset_state();
+                            let errmsg = format!("[{:?}] http error status: {}", out_pretty, msg);
+                            error!("{}", errmsg);
+                            res.retcode = Some(1);
+                            res.stderr = errmsg.as_bytes().to_vec();
+                        }
+                        Err(err) => {
+                            use std::fmt::Write;
 
-                    Ok(None) => {}
-                }
-                Ok(())
-            });
-
-            send.join(cache_write).then(|_| Ok(()))
-        });
+                            error!("[{:?}] fatal error: {}", out_pretty, err);
 
-        tokio_compat::runtime::current_thread::TaskExecutor::current()
-            .spawn_local(Box::new(task))
-            .unwrap();
+                            let mut error = "sccache: encountered fatal error\n".to_string();
+                            let _ = writeln!(error, "sccache: error: {}", err);
+                            for e in err.chain() {
+                                error!("[{:?}] \t{}", out_pretty, e);
+                                let _ = writeln!(error, "sccache: caused by: {}", e);
+                            }
+                            stats.cache_errors.increment(kind);
+                            //TODO: figure out a better way to communicate this?
+                            res.retcode = Some(-2);
+                            res.stderr = error.into_bytes();
+                        }
+                    },
+                }
+            }
+        };
+        (res, cache_write)
     }
 }
 
@@ -1278,8 +1729,62 @@ impl PerLanguageCount {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PerLanguageDuration {
+    durations: HashMap<String, Duration>,
+}
+
+impl PerLanguageDuration {
+    fn add(&mut self, kind: &CompilerKind, duration: Duration) {
+        let key = kind.lang_kind();
+        *self.durations.entry(key).or_default() += duration;
+    }
+
+    pub fn all(&self) -> Duration {
+        self.durations.values().sum()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Duration> {
+        self.durations.get(key)
+    }
+}
+
+/// Cache hits and misses of the compilations sharing a compiler or project.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct HitMissCount {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Cache misses, by why the cache was missed.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct MissTypeCount {
+    /// The compilation was not found in the cache.
+    pub normal: u64,
+    /// The cache lookup was skipped because of `SCCACHE_RECACHE`.
+    pub forced_recache: u64,
+    /// The cache took too long to respond.
+    pub timed_out: u64,
+    /// The cache entry couldn't be read.
+    pub read_error: u64,
+}
+
+impl MissTypeCount {
+    fn increment(&mut self, miss_type: &MissType) {
+        match miss_type {
+            MissType::Normal => self.normal += 1,
+            MissType::ForcedRecache => self.forced_recache += 1,
+            MissType::TimedOut => self.timed_out += 1,
+            MissType::CacheReadError => self.read_error += 1,
+        }
+    }
+}
+
 /// Statistics about the server.
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
 pub struct ServerStats {
     /// The count of client compile requests.
     pub compile_requests: u64,
@@ -1305,6 +1810,11 @@ pub struct ServerStats {
     pub non_cacheable_compilations: u64,
     /// The count of compilations which forcibly ignored the cache.
     pub forced_recaches: u64,
+    /// The count of requests that took the result of an identical
+    /// compilation running at the same time. They're also counted as hits.
+    pub coalesced_compilations: u64,
+    /// The count of compilations abandoned because the client disconnected.
+    pub compiles_abandoned: u64,
     /// The count of errors writing to cache.
     pub cache_write_errors: u64,
     /// The number of successful cache writes.
@@ -1315,6 +1825,9 @@ pub struct ServerStats {
     pub cache_read_hit_duration: Duration,
     /// The total time spent reading cache misses.
     pub cache_read_miss_duration: Duration,
+    /// The total time the compilations of cache hits originally took
+    /// (per language).
+    pub time_saved: PerLanguageDuration,
     /// The count of compilation failures.
     pub compile_fails: u64,
     /// Counts of reasons why compiles were not cached.
@@ -1324,6 +1837,12 @@ pub struct ServerStats {
     pub dist_compiles: HashMap<String, usize>,
     /// The count of compilations that were distributed but failed and had to be re-run locally
     pub dist_errors: u64,
+    /// The count of cache misses by why the cache was missed.
+    pub cache_misses_by_type: MissTypeCount,
+    /// Cache hits and misses by compiler executable.
+    pub compilers: HashMap<String, HitMissCount>,
+    /// Cache hits and misses by project, see `StatsConfig::project_roots`.
+    pub projects: HashMap<String, HitMissCount>,
 }
 
 /// Info and stats about the server.
@@ -1360,24 +1879,204 @@ impl Default for ServerStats {
             cache_read_errors: u64::default(),
             non_cacheable_compilations: u64::default(),
             forced_recaches: u64::default(),
+            coalesced_compilations: u64::default(),
+            compiles_abandoned: u64::default(),
             cache_write_errors: u64::default(),
             cache_writes: u64::default(),
             cache_write_duration: Duration::new(0, 0),
             cache_read_hit_duration: Duration::new(0, 0),
             cache_read_miss_duration: Duration::new(0, 0),
+            time_saved: PerLanguageDuration::default(),
             compile_fails: u64::default(),
             not_cached: HashMap::new(),
             dist_compiles: HashMap::new(),
             dist_errors: u64::default(),
+            cache_misses_by_type: MissTypeCount::default(),
+            compilers: HashMap::new(),
+            projects: HashMap::new(),
         }
     }
 }
 
+/// What a compilation is counted under in the stats besides its language.
+struct StatsKeys {
+    /// The compiler's executable.
+    compiler: String,
+    /// The project the compilation belongs to, if any.
+    project: Option<String>,
+}
+
 impl ServerStats {
+    /// Update the counts of the compiler and project in `keys` with `f`.
+    fn increment_keyed<F: Fn(&mut HitMissCount)>(&mut self, keys: &StatsKeys, f: F) {
+        f(self.compilers.entry(keys.compiler.clone()).or_default());
+        if let Some(ref project) = keys.project {
+            f(self.projects.entry(project.clone()).or_default());
+        }
+    }
+
+    /// Add the counts in `other` to these.
+    pub fn merge(&mut self, other: &ServerStats) {
+        self.combine(other, true)
+    }
+
+    /// The counts accumulated since `earlier`, an older copy of these stats.
+    pub fn since(&self, earlier: &ServerStats) -> ServerStats {
+        let mut stats = self.clone();
+        stats.combine(earlier, false);
+        stats
+    }
+
+    /// Add the counts in `other` to these, or subtract them if `add` is false.
+    fn combine(&mut self, other: &ServerStats, add: bool) {
+        fn count(a: &mut u64, b: u64, add: bool) {
+            *a = if add { *a + b } else { a.saturating_sub(b) };
+        }
+        fn duration(a: &mut Duration, b: Duration, add: bool) {
+            *a = if add {
+                *a + b
+            } else {
+                a.checked_sub(b).unwrap_or_default()
+            };
+        }
+        fn counts<V>(a: &mut HashMap<String, V>, b: &HashMap<String, V>, add: bool)
+        where
+            V: Copy + Default + PartialOrd + ops::Add<Output = V> + ops::Sub<Output = V>,
+        {
+            for (key, &value) in b {
+                let entry = a.entry(key.clone()).or_default();
+                *entry = if add {
+                    *entry + value
+                } else if *entry > value {
+                    *entry - value
+                } else {
+                    V::default()
+                };
+            }
+            if !add {
+                a.retain(|_, value| *value > V::default());
+            }
+        }
+        fn durations(a: &mut HashMap<String, Duration>, b: &HashMap<String, Duration>, add: bool) {
+            for (key, &value) in b {
+                duration(a.entry(key.clone()).or_default(), value, add);
+            }
+            if !add {
+                a.retain(|_, value| *value > Duration::default());
+            }
+        }
+        fn hit_miss_counts(
+            a: &mut HashMap<String, HitMissCount>,
+            b: &HashMap<String, HitMissCount>,
+            add: bool,
+        ) {
+            for (key, HitMissCount { hits, misses }) in b {
+                let entry = a.entry(key.clone()).or_default();
+                count(&mut entry.hits, *hits, add);
+                count(&mut entry.misses, *misses, add);
+            }
+            if !add {
+                a.retain(|_, value| *value != HitMissCount::default());
+            }
+        }
+
+        // Destructure `other` so that a new field can't be left out here.
+        let ServerStats {
+            compile_requests,
+            requests_unsupported_compiler,
+            requests_not_compile,
+            requests_not_cacheable,
+            requests_executed,
+            cache_errors,
+            cache_hits,
+            cache_misses,
+            cache_timeouts,
+            cache_read_errors,
+            non_cacheable_compilations,
+            forced_recaches,
+            coalesced_compilations,
+            compiles_abandoned,
+            cache_write_errors,
+            cache_writes,
+            cache_write_duration,
+            cache_read_hit_duration,
+            cache_read_miss_duration,
+            time_saved,
+            compile_fails,
+            not_cached,
+            dist_compiles,
+            dist_errors,
+            cache_misses_by_type:
+                MissTypeCount {
+                    normal,
+                    forced_recache,
+                    timed_out,
+                    read_error,
+                },
+            compilers,
+            projects,
+        } = other;
+        count(&mut self.compile_requests, *compile_requests, add);
+        count(
+            &mut self.requests_unsupported_compiler,
+            *requests_unsupported_compiler,
+            add,
+        );
+        count(&mut self.requests_not_compile, *requests_not_compile, add);
+        count(
+            &mut self.requests_not_cacheable,
+            *requests_not_cacheable,
+            add,
+        );
+        count(&mut self.requests_executed, *requests_executed, add);
+        counts(&mut self.cache_errors.counts, &cache_errors.counts, add);
+        counts(&mut self.cache_hits.counts, &cache_hits.counts, add);
+        counts(&mut self.cache_misses.counts, &cache_misses.counts, add);
+        count(&mut self.cache_timeouts, *cache_timeouts, add);
+        count(&mut self.cache_read_errors, *cache_read_errors, add);
+        count(
+            &mut self.non_cacheable_compilations,
+            *non_cacheable_compilations,
+            add,
+        );
+        count(&mut self.forced_recaches, *forced_recaches, add);
+        count(
+            &mut self.coalesced_compilations,
+            *coalesced_compilations,
+            add,
+        );
+        count(&mut self.compiles_abandoned, *compiles_abandoned, add);
+        count(&mut self.cache_write_errors, *cache_write_errors, add);
+        count(&mut self.cache_writes, *cache_writes, add);
+        duration(&mut self.cache_write_duration, *cache_write_duration, add);
+        duration(
+            &mut self.cache_read_hit_duration,
+            *cache_read_hit_duration,
+            add,
+        );
+        duration(
+            &mut self.cache_read_miss_duration,
+            *cache_read_miss_duration,
+            add,
+        );
+        durations(&mut self.time_saved.durations, &time_saved.durations, add);
+        count(&mut self.compile_fails, *compile_fails, add);
+        counts(&mut self.not_cached, not_cached, add);
+        counts(&mut self.dist_compiles, dist_compiles, add);
+        count(&mut self.dist_errors, *dist_errors, add);
+        let misses = &mut self.cache_misses_by_type;
+        count(&mut misses.normal, *normal, add);
+        count(&mut misses.forced_recache, *forced_recache, add);
+        count(&mut misses.timed_out, *timed_out, add);
+        count(&mut misses.read_error, *read_error, add);
+        hit_miss_counts(&mut self.compilers, compilers, add);
+        hit_miss_counts(&mut self.projects, projects, add);
+    }
+
     /// Print stats to stdout in a human-readable format.
     ///
     /// Return the formatted width of each of the (name, value) columns.
-    fn print(&self) -> (usize, usize) {
+    pub(crate) fn print(&self) -> (usize, usize) {
         macro_rules! set_stat {
             ($vec:ident, $var:expr, $name:expr) => {{
                 // name, value, suffix length
@@ -1421,8 +2120,14 @@ impl ServerStats {
         set_stat!(stats_vec, self.cache_timeouts, "Cache timeouts");
         set_stat!(stats_vec, self.cache_read_errors, "Cache read errors");
         set_stat!(stats_vec, self.forced_recaches, "Forced recaches");
+        set_stat!(
+            stats_vec,
+            self.coalesced_compilations,
+            "Coalesced compilations"
+        );
         set_stat!(stats_vec, self.cache_write_errors, "Cache write errors");
         set_stat!(stats_vec, self.compile_fails, "Compilation failures");
+        set_stat!(stats_vec, self.compiles_abandoned, "Compilations abandoned");
         set_lang_stat!(stats_vec, self.cache_errors, "Cache errors");
         set_stat!(
             stats_vec,
@@ -1462,6 +2167,25 @@ impl ServerStats {
             self.cache_hits.all(),
             "Average cache read hit"
         );
+        macro_rules! set_lang_duration_stat {
+            ($vec:ident, $var:expr, $name:expr) => {{
+                $vec.push((
+                    $name.to_string(),
+                    util::fmt_duration_as_secs(&$var.all()),
+                    2,
+                ));
+                let mut sorted_stats: Vec<_> = $var.durations.iter().collect();
+                sorted_stats.sort_by_key(|v| v.0);
+                for (lang, duration) in sorted_stats.iter() {
+                    $vec.push((
+                        format!("{} ({})", $name, lang),
+                        util::fmt_duration_as_secs(duration),
+                        2,
+                    ));
+                }
+            }};
+        }
+        set_lang_duration_stat!(stats_vec, self.time_saved, "Estimated time saved");
         set_stat!(
             stats_vec,
             self.dist_errors,
@@ -1486,7 +2210,11 @@ impl ServerStats {
                 stat_width = stat_width + suffix_len
             );
         }
+        // Sections below the table each start with an empty line, and are
+        // followed by one.
+        let mut sections = false;
         if !self.dist_compiles.is_empty() {
+            sections = true;
             println!("\nSuccessful distributed compiles");
             let mut counts: Vec<_> = self.dist_compiles.iter().collect();
             counts.sort_by(|(_, c1), (_, c2)| c1.cmp(c2).reverse());
@@ -1500,7 +2228,54 @@ impl ServerStats {
                 );
             }
         }
+        if self.cache_misses_by_type != MissTypeCount::default() {
+            sections = true;
+            println!("\nCache misses by reason");
+            let misses = &self.cache_misses_by_type;
+            for &(reason, count) in &[
+                ("Not in cache", misses.normal),
+                ("Forced recache", misses.forced_recache),
+                ("Cache timeout", misses.timed_out),
+                ("Cache read error", misses.read_error),
+            ] {
+                println!(
+                    "  {:<name_width$} {:>stat_width$}",
+                    reason,
+                    count,
+                    name_width = name_width - 2,
+                    stat_width = stat_width
+                );
+            }
+        }
+        for &(title, counts) in &[
+            ("Cache hits and misses by compiler", &self.compilers),
+            ("Cache hits and misses by project", &self.projects),
+        ] {
+            if counts.is_empty() {
+                continue;
+            }
+            sections = true;
+            println!("\n{}", title);
+            let mut counts: Vec<_> = counts.iter().collect();
+            counts.sort_by(|(k1, c1), (k2, c2)| {
+                (c2.hits + c2.misses)
+                    .cmp(&(c1.hits + c1.misses))
+                    .then(k1.cmp(k2))
+            });
+            let key_width = counts.iter().map(|(k, _)| k.len()).max().unwrap_or(0);
+            for (key, count) in counts {
+                println!(
+                    "  {:<key_width$} {:>stat_width$} hits {:>stat_width$} misses",
+                    key,
+                    count.hits,
+                    count.misses,
+                    key_width = key_width,
+                    stat_width = stat_width
+                );
+            }
+        }
         if !self.not_cached.is_empty() {
+            sections = true;
             println!("\nNon-cacheable reasons:");
             let mut counts: Vec<_> = self.not_cached.iter().collect();
             counts.sort_by(|(_, c1), (_, c2)| c1.cmp(c2).reverse());
@@ -1513,6 +2288,8 @@ impl ServerStats {
                     stat_width = stat_width
                 );
             }
+        }
+        if sections {
             println!();
         }
         (name_width, stat_width)
@@ -1560,12 +2337,25 @@ enum Frame<R, R1> {
 
 struct Body<R> {
     receiver: mpsc::Receiver<Result<R>>,
+    /// Dropped along with the body, which tells the producer that nobody is
+    /// listening anymore.
+    _client_gone: oneshot::Receiver<()>,
 }
 
 impl<R> Body<R> {
-    fn pair() -> (mpsc::Sender<Result<R>>, Self) {
+    /// Create a body, along with the sender for its contents and a sender
+    /// that is canceled once the body is dropped.
+    fn pair() -> (mpsc::Sender<Result<R>>, oneshot::Sender<()>, Self) {
         let (tx, rx) = mpsc::channel(0);
-        (tx, Body { receiver: rx })
+        let (gone_tx, gone_rx) = oneshot::channel();
+        (
+            tx,
+            gone_tx,
+            Body {
+                receiver: rx,
+                _client_gone: gone_rx,
+            },
+        )
     }
 }
 
@@ -1596,6 +2386,70 @@ impl<R, B> Message<R, B> {
     }
 }
 
+/// The stream of requests read from a client connection, which can also be
+/// watched for the client going away while a response is still being
+/// produced.
+///
+/// Clients don't send anything while they wait for a response, so whatever is
+/// read while watching is handed out by the next poll of the stream.
+struct ClientRequests<S: Stream> {
+    inner: Rc<RefCell<ClientRequestsInner<S>>>,
+}
+
+struct ClientRequestsInner<S: Stream> {
+    stream: S,
+    /// The result of a poll made while watching for a disconnect.
+    peeked: Option<std::result::Result<Option<S::Item>, S::Error>>,
+}
+
+impl<S: Stream> ClientRequests<S> {
+    fn new(stream: S) -> Self {
+        ClientRequests {
+            inner: Rc::new(RefCell::new(ClientRequestsInner {
+                stream,
+                peeked: None,
+            })),
+        }
+    }
+
+    /// Check whether the client has closed its connection, or it failed.
+    fn poll_disconnected(&self) -> Async<()> {
+        let mut inner = self.inner.borrow_mut();
+        if inner.peeked.is_none() {
+            inner.peeked = match inner.stream.poll() {
+                Ok(Async::NotReady) => return Async::NotReady,
+                Ok(Async::Ready(item)) => Some(Ok(item)),
+                Err(e) => Some(Err(e)),
+            };
+        }
+        match inner.peeked {
+            Some(Ok(Some(_))) => Async::NotReady,
+            _ => Async::Ready(()),
+        }
+    }
+}
+
+impl<S: Stream> Clone for ClientRequests<S> {
+    fn clone(&self) -> Self {
+        ClientRequests {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S: Stream> Stream for ClientRequests<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        let mut inner = self.inner.borrow_mut();
+        match inner.peeked.take() {
+            Some(peeked) => peeked.map(Async::Ready),
+            None => inner.stream.poll(),
+        }
+    }
+}
+
 /// Implementation of `Stream + Sink` that tokio-proto is expecting
 ///
 /// This type is composed of a few layers:
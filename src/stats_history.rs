@@ -0,0 +1,360 @@
+// Copyright 2016 Mozilla Foundation
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Server statistics kept across restarts and `--zero-stats`.
+//!
+//! The server periodically folds what it counted since the last flush into a
+//! `StatsHistory` stored in the cache directory, both into running totals and
+//! into a bucket for the current day.
+
+use crate::server::ServerStats;
+use crate::util;
+use chrono::{Local, NaiveDate};
+use futures::sync::oneshot;
+use futures::Future;
+use futures_03::executor::ThreadPool;
+use futures_03::task::SpawnExt;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tempfile::NamedTempFile;
+
+use crate::errors::*;
+
+/// How often the server writes its stats to the history file.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many days of per-day statistics are kept.
+const MAX_DAYS: usize = 366;
+
+/// The format of the days in a `StatsHistory`.
+pub const DAY_FORMAT: &str = "%Y-%m-%d";
+
+/// Statistics accumulated across server runs.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct StatsHistory {
+    /// Stats accumulated since the history was started.
+    pub total: ServerStats,
+    /// Stats per local day, keyed by `DAY_FORMAT` dates.
+    pub days: BTreeMap<String, ServerStats>,
+}
+
+impl StatsHistory {
+    /// Read the history from `path`, or start a new one if there is none.
+    pub fn load(path: &Path) -> Result<StatsHistory> {
+        match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .with_context(|| format!("Failed to parse stats history {}", path.display())),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(StatsHistory::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Write the history to `path`.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let dir = path.parent().expect("stats history path has a parent");
+        fs::create_dir_all(dir)?;
+        // Write to a temporary file first, so that readers never see a
+        // partial history.
+        let mut tmp = NamedTempFile::new_in(dir)?;
+        tmp.write_all(&serde_json::to_vec(self)?)?;
+        tmp.persist(path)?;
+        Ok(())
+    }
+
+    /// Add `stats` to the totals and to the bucket for `day`.
+    pub fn record(&mut self, day: NaiveDate, stats: &ServerStats) {
+        self.total.merge(stats);
+        self.days
+            .entry(day.format(DAY_FORMAT).to_string())
+            .or_default()
+            .merge(stats);
+        while self.days.len() > MAX_DAYS {
+            let oldest = self
+                .days
+                .keys()
+                .next()
+                .cloned()
+                .expect("days are not empty");
+            self.days.remove(&oldest);
+        }
+    }
+
+    /// The part of the history starting with `day`, with totals for only
+    /// those days.
+    pub fn since(&self, day: NaiveDate) -> StatsHistory {
+        let first = day.format(DAY_FORMAT).to_string();
+        let mut history = StatsHistory::default();
+        for (day, stats) in self.days.range(first..) {
+            history.total.merge(stats);
+            history.days.insert(day.clone(), stats.clone());
+        }
+        history
+    }
+}
+
+impl StatsHistory {
+    /// Print the history to stdout in a human-readable format, either as
+    /// totals or as a summary of each day.
+    pub fn print(&self, per_day: bool) {
+        if !per_day {
+            self.total.print();
+            return;
+        }
+        let rows: Vec<_> = self
+            .days
+            .iter()
+            .map(|(day, stats)| (day.as_str(), stats))
+            .chain(std::iter::once(("Total", &self.total)))
+            .map(|(day, stats)| {
+                let hits = stats.cache_hits.all();
+                let misses = stats.cache_misses.all();
+                let hit_rate = if hits + misses > 0 {
+                    format!("{:.2} %", hits as f64 * 100.0 / (hits + misses) as f64)
+                } else {
+                    "-".to_string()
+                };
+                [
+                    day.to_string(),
+                    stats.compile_requests.to_string(),
+                    hits.to_string(),
+                    misses.to_string(),
+                    hit_rate,
+                    util::fmt_duration_as_secs(&stats.time_saved.all()),
+                ]
+            })
+            .collect();
+        let header = [
+            "Day",
+            "Requests",
+            "Hits",
+            "Misses",
+            "Hit rate",
+            "Time saved",
+        ];
+        let mut widths: Vec<_> = header.iter().map(|h| h.len()).collect();
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row.iter()) {
+                *width = (*width).max(cell.len());
+            }
+        }
+        println!(
+            "{:<w0$}  {:>w1$}  {:>w2$}  {:>w3$}  {:>w4$}  {:>w5$}",
+            header[0],
+            header[1],
+            header[2],
+            header[3],
+            header[4],
+            header[5],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+            w3 = widths[3],
+            w4 = widths[4],
+            w5 = widths[5]
+        );
+        for row in rows {
+            println!(
+                "{:<w0$}  {:>w1$}  {:>w2$}  {:>w3$}  {:>w4$}  {:>w5$}",
+                row[0],
+                row[1],
+                row[2],
+                row[3],
+                row[4],
+                row[5],
+                w0 = widths[0],
+                w1 = widths[1],
+                w2 = widths[2],
+                w3 = widths[3],
+                w4 = widths[4],
+                w5 = widths[5]
+            );
+        }
+    }
+}
+
+/// Folds the stats of a running server into a `StatsHistory`, which is kept
+/// in the file at `path`, if any.
+pub struct StatsRecorder {
+    path: Option<PathBuf>,
+    /// The history as of the last flush, shared with flushes running on the
+    /// thread pool.
+    history: Arc<Mutex<StatsHistory>>,
+    /// The server stats as of the last flush.
+    flushed: ServerStats,
+    last_flush: Instant,
+}
+
+impl StatsRecorder {
+    /// Create a recorder that keeps its history in memory only.
+    pub fn new() -> StatsRecorder {
+        StatsRecorder {
+            path: None,
+            history: Arc::new(Mutex::new(StatsHistory::default())),
+            flushed: ServerStats::default(),
+            last_flush: Instant::now(),
+        }
+    }
+
+    /// Create a recorder that keeps its history in `path`, starting from
+    /// what is already there.
+    pub fn with_file(path: PathBuf) -> StatsRecorder {
+        let history = StatsHistory::load(&path).unwrap_or_else(|e| {
+            warn!("Starting a new stats history: {:?}", e);
+            StatsHistory::default()
+        });
+        StatsRecorder {
+            path: Some(path),
+            history: Arc::new(Mutex::new(history)),
+            ..StatsRecorder::new()
+        }
+    }
+
+    /// Whether it has been a while since the last flush.
+    pub fn needs_flush(&self) -> bool {
+        self.last_flush.elapsed() >= FLUSH_INTERVAL
+    }
+
+    /// Record what was counted in `stats` since the last flush, reading and
+    /// writing the history file on `pool`. The returned future resolves to
+    /// the history including `stats`, and the flush happens even if it is
+    /// dropped.
+    pub fn flush(&mut self, stats: &ServerStats, pool: &ThreadPool) -> SFuture<StatsHistory> {
+        let delta = stats.since(&self.flushed);
+        self.flushed = stats.clone();
+        self.last_flush = Instant::now();
+        let day = Local::today().naive_local();
+        let path = self.path.clone();
+        let history = self.history.clone();
+        let (tx, rx) = oneshot::channel();
+        let flush = async move {
+            let mut history = history.lock().unwrap();
+            if let Some(ref path) = path {
+                record_in_file(&mut history, path, day, &delta);
+            } else {
+                history.record(day, &delta);
+            }
+            drop(tx.send(history.clone()));
+        };
+        if let Err(e) = pool.spawn(flush) {
+            return f_err(e);
+        }
+        Box::new(rx.map_err(|_| anyhow!("stats history flush was cancelled")))
+    }
+
+    /// Flush `stats` before they're zeroed.
+    pub fn flush_and_reset(
+        &mut self,
+        stats: &ServerStats,
+        pool: &ThreadPool,
+    ) -> SFuture<StatsHistory> {
+        let flush = self.flush(stats, pool);
+        self.flushed = ServerStats::default();
+        flush
+    }
+}
+
+/// Add `delta` to the history in the file at `path`, and to `history`.
+///
+/// Other servers sharing the cache directory may have written since we last
+/// looked, so this adds to what's on disk while holding a lock on it.
+fn record_in_file(history: &mut StatsHistory, path: &Path, day: NaiveDate, delta: &ServerStats) {
+    let _lock = util::lock_file(&path.with_extension("lock"))
+        .map_err(|e| warn!("Failed to lock stats history: {:?}", e))
+        .ok();
+    match StatsHistory::load(path) {
+        Ok(on_disk) => *history = on_disk,
+        Err(e) => warn!("Overwriting stats history: {:?}", e),
+    }
+    history.record(day, delta);
+    if let Err(e) = history.save(path) {
+        warn!("Failed to write stats history: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::utils::*;
+
+    #[test]
+    fn test_stats_recorder() {
+        let f = TestFixture::new();
+        let pool = ThreadPool::sized(1);
+        let path = f.tempdir.path().join("stats.json");
+        let today = Local::today().naive_local();
+
+        let mut stats = ServerStats {
+            compile_requests: 3,
+            ..Default::default()
+        };
+        let mut recorder = StatsRecorder::with_file(path.clone());
+        recorder.flush(&stats, &pool).wait().unwrap();
+        // Only what was counted since the last flush is added.
+        stats.compile_requests = 5;
+        let history = recorder.flush(&stats, &pool).wait().unwrap();
+        assert_eq!(5, history.total.compile_requests);
+        // Zeroing the server's stats keeps the history.
+        recorder.flush_and_reset(&stats, &pool).wait().unwrap();
+        let history = recorder
+            .flush(&ServerStats::default(), &pool)
+            .wait()
+            .unwrap();
+        assert_eq!(5, history.total.compile_requests);
+
+        // A new server picks up where the last one left off, and a flush
+        // that nobody waits for still happens.
+        let mut recorder = StatsRecorder::with_file(path.clone());
+        stats.compile_requests = 2;
+        drop(recorder.flush(&stats, &pool));
+        let history = recorder
+            .flush(&ServerStats::default(), &pool)
+            .wait()
+            .unwrap();
+        assert_eq!(7, history.total.compile_requests);
+        let history = StatsHistory::load(&path).unwrap();
+        assert_eq!(7, history.total.compile_requests);
+        assert_eq!(1, history.days.len());
+        assert_eq!(7, history.since(today).total.compile_requests);
+        assert!(history.since(today.succ()).days.is_empty());
+    }
+
+    #[test]
+    fn test_stats_history_max_days() {
+        let mut history = StatsHistory::default();
+        let stats = ServerStats {
+            compile_requests: 1,
+            ..Default::default()
+        };
+        let first = NaiveDate::from_ymd(2020, 1, 1);
+        let mut day = first;
+        for _ in 0..MAX_DAYS + 10 {
+            history.record(day, &stats);
+            day = day.succ();
+        }
+        assert_eq!(MAX_DAYS, history.days.len());
+        assert!(!history.days.contains_key("2020-01-01"));
+        assert_eq!(MAX_DAYS as u64 + 10, history.total.compile_requests);
+        assert_eq!(
+            3,
+            history
+                .since(day.pred().pred().pred())
+                .total
+                .compile_requests
+        );
+    }
+}
@@ -14,10 +14,16 @@
 
 use crate::cache::disk::DiskCache;
 use crate::client::connect_to_server;
-use crate::commands::{do_compile, request_shutdown, request_stats};
+use crate::commands::{
+    do_compile, request_reload_config, request_shutdown, request_stats, request_stats_history,
+    request_zero_stats,
+};
+use crate::config::{Config, DiskCacheConfig, StatsConfig};
+use crate::errors::*;
 use crate::jobserver::Client;
 use crate::mock_command::*;
-use crate::server::{DistClientContainer, SccacheServer, ServerMessage};
+use crate::protocol::{Compile, CompileResponse, Handshake, Request, Response};
+use crate::server::{DistClientContainer, HitMissCount, SccacheServer, ServerMessage};
 use crate::test::utils::*;
 use futures::sync::oneshot::{self, Sender};
 use futures_03::executor::ThreadPool;
@@ -41,6 +47,10 @@ struct ServerOptions {
     idle_timeout: Option<u64>,
     /// The maximum size of the disk cache.
     cache_size: Option<u64>,
+    /// How to break down the server's stats.
+    stats_config: Option<StatsConfig>,
+    /// How the server loads its configuration when asked to reload it.
+    config_loader: Option<Box<dyn Fn() -> Result<Config> + Send>>,
 }
 
 /// Run a server on a background thread, and return a tuple of useful things.
@@ -81,13 +91,28 @@ where
 
         let runtime = Runtime::new().unwrap();
         let client = unsafe { Client::new() };
-        let srv = SccacheServer::new(0, pool, runtime, client, dist_client, storage).unwrap();
+        let srv = SccacheServer::new(
+            0,
+            pool,
+            runtime,
+            client,
+            dist_client,
+            storage,
+            Default::default(),
+        )
+        .unwrap();
         let mut srv: SccacheServer<Arc<Mutex<MockCommandCreator>>> = srv;
         assert!(srv.port() > 0);
         if let Some(options) = options {
             if let Some(timeout) = options.idle_timeout {
                 srv.set_idle_timeout(Duration::from_millis(timeout));
             }
+            if let Some(stats_config) = options.stats_config {
+                srv.set_stats_config(stats_config);
+            }
+            if let Some(config_loader) = options.config_loader {
+                srv.set_config_loader(config_loader);
+            }
         }
         let port = srv.port();
         let creator = srv.command_creator().clone();
@@ -161,6 +186,117 @@ fn test_server_stats() {
     child.join().unwrap();
 }
 
+#[test]
+fn test_server_handshake() {
+    let f = TestFixture::new();
+    let (port, sender, _storage, child) = run_server_thread(f.tempdir.path(), None);
+    let mut conn = connect_to_server(port).unwrap();
+    // The server should reply with the same versions we're speaking.
+    let stale = Handshake {
+        protocol_version: 0,
+        version: "0.0.0".to_string(),
+    };
+    match conn.request(Request::Handshake(stale)).unwrap() {
+        Response::Handshake(theirs) => assert_eq!(Handshake::current(), theirs),
+        r => panic!("Unexpected response: {:?}", r),
+    }
+    conn.handshake().unwrap();
+    // The connection is still usable after the handshake.
+    let info = request_stats(conn).unwrap();
+    assert_eq!(0, info.stats.compile_requests);
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_handshake_is_older_than() {
+    let handshake = |protocol_version, version: &str| Handshake {
+        protocol_version,
+        version: version.to_string(),
+    };
+    assert!(handshake(5, "0.2.16").is_older_than(&handshake(6, "0.2.16")));
+    assert!(handshake(6, "0.2.9").is_older_than(&handshake(6, "0.2.16-alpha.0")));
+    assert!(handshake(5, "0.3.0").is_older_than(&handshake(6, "0.2.16")));
+    assert!(!handshake(6, "0.2.16").is_older_than(&handshake(6, "0.2.16")));
+    assert!(!handshake(6, "0.2.17").is_older_than(&handshake(6, "0.2.16")));
+    assert!(!handshake(7, "0.2.15").is_older_than(&handshake(6, "0.2.16")));
+    // Builds of the same release can't be ordered.
+    assert!(!handshake(6, "0.2.16").is_older_than(&handshake(6, "0.2.16-alpha.0")));
+}
+
+#[test]
+fn test_server_reload_config() {
+    let f = TestFixture::new();
+    let new_cache = f.tempdir.path().join("new-cache");
+    let loaded = new_cache.clone();
+    let loads = Arc::new(Mutex::new(0));
+    let config_loader = move || -> Result<Config> {
+        let mut loads = loads.lock().unwrap();
+        *loads += 1;
+        if *loads > 1 {
+            bail!("broken config");
+        }
+        Ok(Config {
+            fallback_cache: DiskCacheConfig {
+                dir: loaded.clone(),
+                size: 1234,
+            },
+            ..Default::default()
+        })
+    };
+    let (port, sender, _storage, child) = run_server_thread(
+        &f.tempdir.path().join("cache"),
+        ServerOptions {
+            config_loader: Some(Box::new(config_loader)),
+            ..Default::default()
+        },
+    );
+    // The server switches to the storage in the new configuration.
+    let info = request_reload_config(connect_to_server(port).unwrap()).unwrap();
+    assert!(info.cache_location.contains("new-cache"));
+    assert_eq!(Some(1234), info.max_cache_size);
+    // A configuration that fails to load leaves the server as it was.
+    let err = request_reload_config(connect_to_server(port).unwrap()).unwrap_err();
+    assert!(err.to_string().contains("broken config"));
+    let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+    assert!(info.cache_location.contains("new-cache"));
+    assert_eq!(Some(1234), info.max_cache_size);
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
+#[test]
+fn test_server_stats_history() {
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(f.tempdir.path(), None);
+    // Pretend to be an unsupported compiler.
+    next_command(
+        &server_creator,
+        Ok(MockChild::new(exit_status(0), "hello", "error")),
+    );
+    let compile = Compile {
+        exe: f.bins[0].clone().into(),
+        cwd: f.tempdir.path().into(),
+        args: vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()],
+        env_vars: vec![],
+    };
+    let mut conn = connect_to_server(port).unwrap();
+    match conn.request(Request::Compile(compile)).unwrap() {
+        Response::Compile(CompileResponse::UnsupportedCompiler(_)) => {}
+        r => panic!("Unexpected response: {:?}", r),
+    }
+    drop(conn);
+    // Zeroing the stats doesn't lose their history.
+    let info = request_zero_stats(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(0, info.stats.compile_requests);
+    let history = request_stats_history(connect_to_server(port).unwrap()).unwrap();
+    assert_eq!(1, history.total.compile_requests);
+    assert_eq!(1, history.total.requests_unsupported_compiler);
+    assert_eq!(1, history.days.len());
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
 #[test]
 fn test_server_unsupported_compiler() {
     let f = TestFixture::new();
@@ -213,7 +349,16 @@ fn test_server_unsupported_compiler() {
 fn test_server_compile() {
     let _ = env_logger::try_init();
     let f = TestFixture::new();
-    let (port, sender, server_creator, child) = run_server_thread(&f.tempdir.path(), None);
+    let stats_config = StatsConfig {
+        project_roots: vec![f.tempdir.path().to_owned()],
+    };
+    let (port, sender, server_creator, child) = run_server_thread(
+        f.tempdir.path(),
+        ServerOptions {
+            stats_config: Some(stats_config),
+            ..Default::default()
+        },
+    );
     // Connect to the server.
     const PREPROCESSOR_STDOUT: &[u8] = b"preprocessor stdout";
     const PREPROCESSOR_STDERR: &[u8] = b"preprocessor stderr";
@@ -272,12 +417,60 @@ fn test_server_compile() {
     assert_eq!(0, server_creator.lock().unwrap().children.len());
     assert_eq!(STDOUT, stdout.into_inner().as_slice());
     assert_eq!(STDERR, stderr.into_inner().as_slice());
+    // The miss is broken down by compiler and project.
+    let stats = request_stats(connect_to_server(port).unwrap())
+        .unwrap()
+        .stats;
+    let miss = HitMissCount { hits: 0, misses: 1 };
+    assert_eq!(1, stats.cache_misses_by_type.normal);
+    assert_eq!(Some(&miss), stats.compilers.get(&exe.display().to_string()));
+    assert_eq!(Some(&miss), stats.projects.get(&cwd.display().to_string()));
     // Shut down the server.
     sender.send(ServerMessage::Shutdown).ok().unwrap();
     // Ensure that it shuts down.
     child.join().unwrap();
 }
 
+#[test]
+fn test_server_compile_client_disconnect() {
+    let _ = env_logger::try_init();
+    let f = TestFixture::new();
+    let (port, sender, server_creator, child) = run_server_thread(f.tempdir.path(), None);
+    let mut conn = connect_to_server(port).unwrap();
+    {
+        let mut c = server_creator.lock().unwrap();
+        // The server will check the compiler. Pretend it's GCC.
+        c.next_command_spawns(Ok(MockChild::new(exit_status(0), "gcc", "")));
+        // The preprocessor never finishes.
+        c.next_command_spawns(Ok(MockChild::never_exits()));
+    }
+    let compile = Compile {
+        exe: f.bins[0].clone().into(),
+        cwd: f.tempdir.path().into(),
+        args: vec!["-c".into(), "file.c".into(), "-o".into(), "file.o".into()],
+        env_vars: vec![],
+    };
+    match conn.request(Request::Compile(compile)).unwrap() {
+        Response::Compile(CompileResponse::CompileStarted) => {}
+        r => panic!("Unexpected response: {:?}", r),
+    }
+    // Go away without waiting for the result, the server should give up on
+    // the compilation.
+    drop(conn);
+    let mut abandoned = 0;
+    for _ in 0..50 {
+        let info = request_stats(connect_to_server(port).unwrap()).unwrap();
+        abandoned = info.stats.compiles_abandoned;
+        if abandoned > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(1, abandoned);
+    sender.send(ServerMessage::Shutdown).ok().unwrap();
+    child.join().unwrap();
+}
+
 #[test]
 // test fails intermittently on macos:
 // https://github.com/mozilla/sccache/issues/234
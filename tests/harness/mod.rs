@@ -149,6 +149,8 @@ pub fn sccache_client_cfg(tmpdir: &Path) -> sccache::config::FileConfig {
             toolchain_cache_size: TC_CACHE_SIZE,
             rewrite_includes_only: false, // TODO
         },
+        compiler: Default::default(),
+        stats: Default::default(),
     }
 }
 #[cfg(feature = "dist-server")]
@@ -59,6 +59,8 @@ fn config_with_dist_auth(
             toolchain_cache_size: 0,
             rewrite_includes_only: true,
         },
+        compiler: Default::default(),
+        stats: Default::default(),
     }
 }
 